@@ -34,7 +34,7 @@ impl StopwatchUS {
 impl telemetry::Flatten for StopwatchUS {
     fn as_u32(&self) -> u32 {
         match self.value.as_micros() {
-            x if x >= std::u32::MAX as u128 => std::u32::MAX,
+            x if x >= u32::MAX as u128 => u32::MAX,
             x => x as u32,
         }
     }
@@ -0,0 +1,324 @@
+//!
+//! Helpers for aggregating payloads.
+//!
+//! Some histograms are serialized in a form that is meant to be
+//! combined with the same histogram from other payloads (e.g. other
+//! sessions, other clients) before being interpreted. This module
+//! contains the operations needed by a server, or by any tool
+//! processing the payloads, to do so.
+//!
+
+use rustc_serialize::json::Json;
+
+use std::collections::BTreeMap;
+
+use misc::{
+    bloom_bits, hyperloglog_estimate, randomized_response_keep, RunningStats,
+    HYPERLOGLOG_MAX_PRECISION, HYPERLOGLOG_MIN_PRECISION,
+};
+
+// Extract `(precision, registers)` from a serialized `DistinctCount`.
+fn read_distinct_count(json: &Json) -> Option<(u8, Vec<u8>)> {
+    let precision = json.find("precision").and_then(Json::as_u64)?;
+    if precision < HYPERLOGLOG_MIN_PRECISION as u64 || precision > HYPERLOGLOG_MAX_PRECISION as u64
+    {
+        return None;
+    }
+    let array = json.find("registers").and_then(Json::as_array)?;
+    if array.len() != 1 << precision {
+        return None;
+    }
+    let mut registers = Vec::with_capacity(array.len());
+    for register in array {
+        let value = register.as_u64()?;
+        if value > 32 {
+            return None;
+        }
+        registers.push(value as u8);
+    }
+    Some((precision as u8, registers))
+}
+
+///
+/// Merge the serialized content of several `plain::DistinctCount`
+/// histograms, as produced by `SerializationFormat::SimpleJson`.
+///
+/// The result has the same format and estimates the number of
+/// distinct values recorded in any of the payloads.
+///
+/// Returns `None` if there is no payload, if a payload is malformed
+/// or if payloads were recorded with distinct precisions.
+///
+pub fn merge_distinct_counts<'a, I>(payloads: I) -> Option<Json>
+where
+    I: IntoIterator<Item = &'a Json>,
+{
+    let mut merged: Option<(u8, Vec<u8>)> = None;
+    for payload in payloads {
+        let (precision, registers) = read_distinct_count(payload)?;
+        match merged {
+            None => merged = Some((precision, registers)),
+            Some((ref expected, ref mut accumulator)) => {
+                if *expected != precision {
+                    return None;
+                }
+                for (acc, register) in accumulator.iter_mut().zip(registers) {
+                    if *acc < register {
+                        *acc = register;
+                    }
+                }
+            }
+        }
+    }
+    let (precision, registers) = merged?;
+    let mut tree = BTreeMap::new();
+    tree.insert("precision".to_string(), Json::I64(precision as i64));
+    tree.insert(
+        "estimate".to_string(),
+        Json::I64(hyperloglog_estimate(&registers).round() as i64),
+    );
+    tree.insert(
+        "registers".to_string(),
+        Json::Array(registers.iter().map(|&x| Json::I64(x as i64)).collect()),
+    );
+    Some(Json::Object(tree))
+}
+
+///
+/// Estimate the number of distinct values recorded in a serialized
+/// `plain::DistinctCount`, as produced by `SerializationFormat::SimpleJson`
+/// or by `merge_distinct_counts`.
+///
+/// Returns `None` if the payload is malformed.
+///
+pub fn estimate_distinct_count(payload: &Json) -> Option<f64> {
+    let (_, registers) = read_distinct_count(payload)?;
+    Some(hyperloglog_estimate(&registers))
+}
+
+// Extract `epsilon` from a serialized privacy-preserving histogram.
+fn read_epsilon(json: &Json) -> Option<f64> {
+    let epsilon = json.find("epsilon").and_then(Json::as_f64)?;
+    if epsilon > 0. && epsilon.is_finite() {
+        Some(epsilon)
+    } else {
+        None
+    }
+}
+
+// Extract an array of numbers, each at most `max`.
+fn read_numbers(json: &Json, field: &str, max: u64) -> Option<Vec<u64>> {
+    let array = json.find(field).and_then(Json::as_array)?;
+    let mut numbers = Vec::with_capacity(array.len());
+    for number in array {
+        let number = number.as_u64()?;
+        if number > max {
+            return None;
+        }
+        numbers.push(number);
+    }
+    Some(numbers)
+}
+
+// Estimate how many of `total` reports are truly `value`, given that
+// `observed` reports are encoded as `value` by randomized response over
+// `k` values.
+fn unbias(observed: f64, total: f64, k: u32, epsilon: f64) -> f64 {
+    let keep = randomized_response_keep(k, epsilon);
+    (observed - total * (1. - keep) / k as f64) / keep
+}
+
+///
+/// Estimate how many of the payloads of a `plain::PrivateFlag`
+/// histogram, as produced by `SerializationFormat::SimpleJson`, have
+/// the flag truly set.
+///
+/// The estimate is unbiased, so it may be slightly negative or greater
+/// than the number of payloads. Its standard error grows with the square
+/// root of the number of payloads.
+///
+/// Returns `None` if there is no payload, if a payload is malformed
+/// or if payloads were recorded with distinct values of `epsilon`.
+///
+pub fn estimate_private_flags<'a, I>(payloads: I) -> Option<f64>
+where
+    I: IntoIterator<Item = &'a Json>,
+{
+    let mut epsilon = None;
+    let mut total = 0;
+    let mut set = 0;
+    for payload in payloads {
+        let current = read_epsilon(payload)?;
+        if *epsilon.get_or_insert(current) != current {
+            return None;
+        }
+        let value = payload.find("value").and_then(Json::as_u64)?;
+        if value > 1 {
+            return None;
+        }
+        total += 1;
+        set += value;
+    }
+    Some(unbias(set as f64, total as f64, 2, epsilon?))
+}
+
+///
+/// Estimate the total number of values truly recorded for each variant
+/// in the payloads of a `plain::PrivateEnum` histogram, as produced by
+/// `SerializationFormat::SimpleJson`.
+///
+/// The estimates are unbiased, so they may be slightly negative.
+///
+/// Returns `None` if there is no payload, if a payload is malformed
+/// or if payloads were recorded with distinct values of `epsilon` or
+/// distinct numbers of variants.
+///
+pub fn estimate_private_enums<'a, I>(payloads: I) -> Option<Vec<f64>>
+where
+    I: IntoIterator<Item = &'a Json>,
+{
+    let mut merged: Option<(f64, Vec<u64>)> = None;
+    for payload in payloads {
+        let epsilon = read_epsilon(payload)?;
+        let values = read_numbers(payload, "values", u64::from(u32::MAX))?;
+        if values.len() < 2 {
+            return None;
+        }
+        match merged {
+            None => merged = Some((epsilon, values)),
+            Some((expected, ref mut accumulator)) => {
+                if expected != epsilon || accumulator.len() != values.len() {
+                    return None;
+                }
+                for (acc, value) in accumulator.iter_mut().zip(values) {
+                    *acc += value;
+                }
+            }
+        }
+    }
+    let (epsilon, values) = merged?;
+    let total: u64 = values.iter().sum();
+    let k = values.len() as u32;
+    Some(
+        values
+            .iter()
+            .map(|&observed| unbias(observed as f64, total as f64, k, epsilon))
+            .collect(),
+    )
+}
+
+///
+/// Estimate, for each of a list of candidate keys, how many of the
+/// payloads of a `keyed::KeyedPrivateFlag` histogram, as produced by
+/// `SerializationFormat::SimpleJson`, have truly recorded the key.
+///
+/// Keys that were recorded but are not among the candidates cannot be
+/// recovered. As the estimate for a key is the lowest estimate for
+/// the bits of the Bloom filter that it sets, collisions with other
+/// keys may cause overestimates.
+///
+/// Returns `None` if there is no payload, if a payload is malformed
+/// or if payloads were recorded with distinct parameters.
+///
+pub fn estimate_private_keyed_flags<'a, I>(
+    payloads: I,
+    candidates: &[&str],
+) -> Option<BTreeMap<String, f64>>
+where
+    I: IntoIterator<Item = &'a Json>,
+{
+    let mut merged: Option<(f64, u64, Vec<u64>)> = None;
+    let mut total = 0;
+    for payload in payloads {
+        let epsilon = read_epsilon(payload)?;
+        let hashes = payload.find("hashes").and_then(Json::as_u64)?;
+        let bits = read_numbers(payload, "bits", 1)?;
+        if hashes == 0 || hashes > bits.len() as u64 {
+            return None;
+        }
+        match merged {
+            None => merged = Some((epsilon, hashes, bits)),
+            Some((expected_epsilon, expected_hashes, ref mut accumulator)) => {
+                if expected_epsilon != epsilon
+                    || expected_hashes != hashes
+                    || accumulator.len() != bits.len()
+                {
+                    return None;
+                }
+                for (acc, bit) in accumulator.iter_mut().zip(bits) {
+                    *acc += bit;
+                }
+            }
+        }
+        total += 1;
+    }
+    let (epsilon, hashes, bits) = merged?;
+    // Each bit is encoded with `epsilon / hashes`.
+    let bit_epsilon = epsilon / hashes as f64;
+    let estimates = candidates
+        .iter()
+        .map(|candidate| {
+            let estimate = bloom_bits(candidate, hashes as u32, bits.len() as u32)
+                .into_iter()
+                .map(|bit| unbias(bits[bit as usize] as f64, total as f64, 2, bit_epsilon))
+                .fold(f64::INFINITY, f64::min);
+            (candidate.to_string(), estimate)
+        })
+        .collect();
+    Some(estimates)
+}
+
+///
+/// Merge the serialized content of several `plain::Stats` histograms,
+/// as produced by `SerializationFormat::SimpleJson`.
+///
+/// The result has the same format and holds the statistics of all the
+/// values recorded in any of the payloads.
+///
+/// Returns `None` if there is no payload, if a payload is malformed or
+/// if the merged count or sum would overflow `u64`.
+///
+pub fn merge_stats<'a, I>(payloads: I) -> Option<Json>
+where
+    I: IntoIterator<Item = &'a Json>,
+{
+    let mut payloads = payloads.into_iter().peekable();
+    payloads.peek()?;
+    let mut merged = RunningStats::default();
+    for payload in payloads {
+        merged.merge(&RunningStats::from_json(payload)?)?;
+    }
+    Some(merged.to_json())
+}
+
+///
+/// Merge the serialized content of several `keyed::KeyedStats`
+/// histograms, as produced by `SerializationFormat::SimpleJson`.
+///
+/// The result has the same format and holds, for each key that appears
+/// in any of the payloads, the statistics of all the values recorded
+/// with this key.
+///
+/// Returns `None` if there is no payload, if a payload is malformed or
+/// if the merged count or sum of a key would overflow `u64`.
+///
+pub fn merge_keyed_stats<'a, I>(payloads: I) -> Option<Json>
+where
+    I: IntoIterator<Item = &'a Json>,
+{
+    let mut merged: Option<BTreeMap<String, RunningStats>> = None;
+    for payload in payloads {
+        let merged = merged.get_or_insert_with(BTreeMap::new);
+        for (key, stats) in payload.as_object()? {
+            merged
+                .entry(key.clone())
+                .or_default()
+                .merge(&RunningStats::from_json(stats)?)?;
+        }
+    }
+    let tree = merged?
+        .into_iter()
+        .map(|(key, stats)| (key, stats.to_json()))
+        .collect();
+    Some(Json::Object(tree))
+}
@@ -0,0 +1,299 @@
+//!
+//! Thread-local batching of records.
+//!
+//! By default, each record is sent to the telemetry thread as its own
+//! `Op`. For histograms recorded very often, this may be costly. With
+//! batching (see `ServiceBuilder::batching`), each thread merges its
+//! records in a local accumulator and sends them as a single
+//! `Op::RecordBatch` once enough samples have been accumulated, upon
+//! `Service::flush` or once the thread exits. The age of a batch is
+//! only checked when the thread records, so a batch that is old
+//! enough is sent with the next record of its thread, not by a timer.
+//!
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use clock::Clock;
+use task::{Op, OpSender};
+
+/// A source of unique identifiers for `Batching`, so that a thread
+/// may record to several services.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The accumulators of the current thread, indexed by `Batching::id`.
+    static BATCHES: RefCell<HashMap<usize, Arc<Mutex<Batch>>>> = RefCell::new(HashMap::new());
+}
+
+///
+/// The batching configuration of a service, shared by all its histograms.
+///
+impl Batching {
+    pub fn new(
+        sender: OpSender,
+        clock: Arc<dyn Clock>,
+        max_samples: usize,
+        period: Duration,
+    ) -> Batching {
+        Batching {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            sender,
+            clock,
+            max_samples,
+            period,
+            batches: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Record a value in a plain histogram.
+    pub fn record_plain(&self, index: usize, value: u32) {
+        self.record(Record::Plain(index, value))
+    }
+
+    /// Record a value in a slot of a plain histogram, see
+    /// `PlainRawStorage::store_at`. Values recorded in the same slot
+    /// are summed.
+    pub fn record_plain_at(&self, index: usize, slot: u32, value: u32) {
+        self.record(Record::PlainAt(index, slot, value))
+    }
+
+    /// Record a value in a keyed histogram, with an interned key.
+    pub fn record_keyed(&self, index: usize, id: u32, value: u32) {
+        self.record(Record::Keyed(index, id, value))
+    }
+
+    /// Record a value in a keyed histogram with two dimensions, with
+    /// interned keys.
+    pub fn record_keyed2(&self, index: usize, id1: u32, id2: u32, value: u32) {
+        self.record(Record::Keyed2(index, id1, id2, value))
+    }
+
+    /// Send the records accumulated by the current thread.
+    pub fn flush_current(&self) {
+        let _ = BATCHES.try_with(|batches| {
+            if let Some(batch) = batches.borrow().get(&self.id) {
+                batch.lock().unwrap().flush();
+            }
+        });
+    }
+
+    /// Send the records accumulated by all threads.
+    pub fn flush_all(&self) {
+        let batches: Vec<_> = self
+            .batches
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for batch in batches {
+            batch.lock().unwrap().flush();
+        }
+    }
+
+    /// Add a record to the accumulator of the current thread, creating
+    /// it if necessary, and flush it if it is full or old enough.
+    ///
+    /// If the thread is exiting, send the record directly instead.
+    fn record(&self, record: Record) {
+        let mut record = Some(record);
+        let _ = BATCHES.try_with(|batches| {
+            let batch = {
+                let mut batches = batches.borrow_mut();
+                if !batches.contains_key(&self.id) {
+                    // Forget about the accumulators of services that are gone.
+                    batches.retain(|_, batch| !batch.lock().unwrap().is_closed());
+                    batches.insert(self.id, self.new_batch());
+                }
+                batches[&self.id].clone()
+            };
+            let mut batch = batch.lock().unwrap();
+            match record.take().unwrap() {
+                Record::Plain(index, value) => {
+                    let times = batch.plain.entry((index, value)).or_insert(0);
+                    *times = times.saturating_add(1);
+                }
+                Record::PlainAt(index, slot, value) => {
+                    let sum = batch.plain_at.entry((index, slot)).or_insert(0);
+                    *sum = sum.saturating_add(value);
+                }
+                Record::Keyed(index, id, value) => {
+                    let times = batch.keyed.entry((index, id, value)).or_insert(0);
+                    *times = times.saturating_add(1);
+                }
+                Record::Keyed2(index, id1, id2, value) => {
+                    let times = batch.keyed2.entry((index, id1, id2, value)).or_insert(0);
+                    *times = times.saturating_add(1);
+                }
+            }
+            batch.pending += 1;
+            if batch.pending >= self.max_samples
+                || self.clock.monotonic() >= batch.started + self.period
+            {
+                batch.flush();
+            }
+        });
+        let op = match record {
+            None => return,
+            Some(Record::Plain(index, value)) => Op::RecordPlain(index, value),
+            Some(Record::PlainAt(index, slot, value)) => Op::RecordPlainAt(index, slot, value),
+            Some(Record::Keyed(index, id, value)) => Op::RecordKeyed(index, id, value),
+            Some(Record::Keyed2(index, id1, id2, value)) => {
+                Op::RecordKeyed2(index, id1, id2, value)
+            }
+        };
+        let _ = self.sender.send_record(op);
+    }
+
+    /// Create the accumulator of the current thread.
+    fn new_batch(&self) -> Arc<Mutex<Batch>> {
+        let batch = Arc::new(Mutex::new(Batch {
+            sender: Some(self.sender.clone()),
+            clock: self.clock.clone(),
+            plain: HashMap::new(),
+            plain_at: HashMap::new(),
+            keyed: HashMap::new(),
+            keyed2: HashMap::new(),
+            pending: 0,
+            started: self.clock.monotonic(),
+        }));
+        let mut batches = self.batches.lock().unwrap();
+        // Forget about threads that have exited.
+        batches.retain(|weak| weak.upgrade().is_some());
+        batches.push(Arc::downgrade(&batch));
+        batch
+    }
+}
+
+/// Once the service and all its histograms are gone, the accumulators
+/// of all threads are closed, as the entries of the threads in
+/// `BATCHES` are only removed once the threads record again or exit.
+impl Drop for Batching {
+    fn drop(&mut self) {
+        for batch in self.batches.lock().unwrap().drain(..) {
+            if let Some(batch) = batch.upgrade() {
+                batch.lock().unwrap().close();
+            }
+        }
+    }
+}
+
+pub struct Batching {
+    /// A unique identifier for this configuration.
+    id: usize,
+
+    /// The channel used to communicate with the `TelemetryTask`.
+    sender: OpSender,
+
+    /// The clock of the service, used to determine the age of batches.
+    clock: Arc<dyn Clock>,
+
+    /// Flush once a thread has accumulated this many samples.
+    max_samples: usize,
+
+    /// Flush once the samples accumulated by a thread are this old.
+    period: Duration,
+
+    /// The accumulators of all threads, so that `Service::flush` may
+    /// flush them. Each accumulator is owned by its thread.
+    batches: Mutex<Vec<Weak<Mutex<Batch>>>>,
+}
+
+/// A single record, as received by `Batching`.
+enum Record {
+    Plain(usize, u32),
+    PlainAt(usize, u32, u32),
+    Keyed(usize, u32, u32),
+    Keyed2(usize, u32, u32, u32),
+}
+
+/// The records accumulated by one thread for one service.
+impl Batch {
+    /// Send all accumulated records to the `TelemetryTask`.
+    fn flush(&mut self) {
+        self.started = self.clock.monotonic();
+        if self.pending == 0 {
+            return;
+        }
+        self.pending = 0;
+        let plain = self
+            .plain
+            .drain()
+            .map(|((index, value), times)| (index, value, times))
+            .collect();
+        let plain_at = self
+            .plain_at
+            .drain()
+            .map(|((index, slot), value)| (index, slot, value))
+            .collect();
+        let keyed = self
+            .keyed
+            .drain()
+            .map(|((index, id, value), times)| (index, id, value, times))
+            .collect();
+        let keyed2 = self
+            .keyed2
+            .drain()
+            .map(|((index, id1, id2, value), times)| (index, id1, id2, value, times))
+            .collect();
+        // If the service is gone, so is the data.
+        if let Some(ref sender) = self.sender {
+            let _ = sender.send_record(Op::RecordBatch(plain, plain_at, keyed, keyed2));
+        }
+    }
+
+    /// Send all accumulated records, then release the resources of the
+    /// accumulator, which may not be used anymore.
+    fn close(&mut self) {
+        self.flush();
+        self.sender = None;
+        self.plain = HashMap::new();
+        self.plain_at = HashMap::new();
+        self.keyed = HashMap::new();
+        self.keyed2 = HashMap::new();
+    }
+
+    /// `true` once the accumulator has been closed.
+    fn is_closed(&self) -> bool {
+        self.sender.is_none()
+    }
+}
+
+/// Upon exit of the thread, send whatever is left.
+impl Drop for Batch {
+    fn drop(&mut self) {
+        self.flush()
+    }
+}
+
+struct Batch {
+    /// The channel used to communicate with the `TelemetryTask`, or
+    /// `None` once the accumulator has been closed.
+    sender: Option<OpSender>,
+
+    /// The clock of the service.
+    clock: Arc<dyn Clock>,
+
+    /// For each `(histogram, value)`, the number of records.
+    plain: HashMap<(usize, u32), u32>,
+
+    /// For each `(histogram, slot)`, the sum of the values recorded.
+    plain_at: HashMap<(usize, u32), u32>,
+
+    /// For each `(histogram, key id, value)`, the number of records.
+    keyed: HashMap<(usize, u32, u32), u32>,
+
+    /// For each `(histogram, first key id, second key id, value)`, the
+    /// number of records.
+    keyed2: HashMap<(usize, u32, u32, u32), u32>,
+
+    /// The number of records since the latest flush.
+    pending: usize,
+
+    /// The time of the latest flush, or of the creation of the batch.
+    started: Duration,
+}
@@ -0,0 +1,99 @@
+//!
+//! Sources of time.
+//!
+//! Histograms that depend on time (e.g. timestamps, rolling windows)
+//! read it from the `Clock` of their `Service`, which makes it
+//! possible to test them without sleeping.
+//!
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+///
+/// A source of time.
+///
+pub trait Clock: Send + Sync {
+    ///
+    /// A monotonic time, measured from an arbitrary origin that
+    /// remains fixed for the lifetime of the clock. Used to measure
+    /// durations, e.g. for rolling windows.
+    ///
+    fn monotonic(&self) -> Duration;
+
+    ///
+    /// The wall-clock time. Used to timestamp data.
+    ///
+    fn system(&self) -> SystemTime;
+}
+
+///
+/// The clock of the operating system.
+///
+pub struct SystemClock {
+    origin: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            origin: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn monotonic(&self) -> Duration {
+        self.origin.elapsed()
+    }
+    fn system(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+///
+/// A clock that only moves when told to, for testing purposes.
+///
+/// The monotonic time starts at 0 and the wall-clock time at the Unix
+/// epoch.
+///
+pub struct MockClock {
+    /// The time elapsed since the creation of the clock.
+    elapsed: Mutex<Duration>,
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock {
+            elapsed: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+
+    ///
+    /// Move both the monotonic and the wall-clock time forward.
+    ///
+    pub fn advance(&self, duration: Duration) {
+        let mut elapsed = self.elapsed.lock().unwrap();
+        *elapsed += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn monotonic(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+    fn system(&self) -> SystemTime {
+        UNIX_EPOCH + self.monotonic()
+    }
+}
@@ -1,5 +1,15 @@
+use std::collections::HashMap;
 use std::marker::PhantomData;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use keyed::TelemetryKey;
+
+/// A kind of histograms, along with the data attached to the back-end
+/// of each histogram of this kind.
+pub trait Witness: Clone {
+    type Data: Clone + Default;
+}
 
 /// Witness type, used to specify that the data is specific to a plain histogram.
 #[derive(Clone)]
@@ -22,6 +32,95 @@ impl<T> Clone for Keyed<T> {
     }
 }
 
+impl Witness for Plain {
+    type Data = ();
+}
+
+/// Keyed histograms intern their keys.
+impl<T> Witness for Keyed<T> {
+    type Data = Arc<Interner<T>>;
+}
+
+///
+/// The keys of a keyed histogram, each with a compact identifier.
+///
+/// Shared by all clones of a back-end, so that each distinct key is
+/// converted to a string only once. At most `capacity` keys are
+/// interned, so that the memory used by a histogram with a bounded
+/// number of keys remains bounded.
+///
+pub struct Interner<T> {
+    ids: RwLock<HashMap<T, u32>>,
+
+    /// The number of identifiers assigned so far.
+    assigned: AtomicUsize,
+
+    /// The maximal number of identifiers, if any.
+    capacity: Option<usize>,
+}
+
+impl<T> Interner<T> {
+    /// Create an interner for at most `capacity` keys.
+    pub fn new(capacity: Option<usize>) -> Interner<T> {
+        Interner {
+            ids: RwLock::new(HashMap::new()),
+            assigned: AtomicUsize::new(0),
+            capacity,
+        }
+    }
+}
+
+impl<T> Default for Interner<T> {
+    fn default() -> Self {
+        Interner::new(None)
+    }
+}
+
+impl<T: TelemetryKey> Interner<T> {
+    ///
+    /// Get the identifier of a key, or `None` if the key has never
+    /// been seen and the interner is full.
+    ///
+    /// If the key has never been seen, assign it the next identifier
+    /// and call `on_new` with the identifier and the string of the
+    /// key. No lock is held while calling `on_new`, but no other
+    /// thread may use the identifier until `on_new` has returned.
+    ///
+    pub fn intern<F>(&self, key: &T, on_new: F) -> Option<u32>
+    where
+        F: FnOnce(u32, String),
+    {
+        if let Some(&id) = self.ids.read().unwrap().get(key) {
+            return Some(id);
+        }
+        let capacity = self.capacity;
+        let id = self
+            .assigned
+            .fetch_update(
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+                |assigned| match capacity {
+                    Some(capacity) if assigned >= capacity => None,
+                    _ => Some(assigned + 1),
+                },
+            )
+            .ok()? as u32;
+        on_new(id, key.to_key_string());
+        // The identifier is only published once `on_new` has returned.
+        // If another thread has interned the same key in the meantime,
+        // both identifiers designate the same key, and the first one
+        // published is kept.
+        let mut ids = self.ids.write().unwrap();
+        Some(*ids.entry(key.clone()).or_insert(id))
+    }
+}
+
+/// The maximal number of keys interned by a keyed histogram with key
+/// policies. Such a histogram may reject or rewrite any number of
+/// distinct keys, so its number of interned keys is not bounded by its
+/// storage.
+pub const MAX_INTERNED_KEYS_WITH_POLICIES: usize = 256;
+
 /// A key used to communicate with the back-end for a given kind of histograms.
 pub struct Key<T> {
     pub witness: PhantomData<T>,
@@ -9,13 +9,17 @@
 
 use rustc_serialize::json::Json;
 
-use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hash;
 use std::marker::PhantomData;
 use std::mem::size_of;
+use std::sync::mpsc::channel;
 
 use indexing::*;
-use misc::{vec_with_size, Flatten, LinearBuckets, SerializationFormat};
+use misc::{
+    bloom_bits, check_epsilon, randomized_response, vec_with_size, Definition, Flatten, Kind,
+    LinearBuckets, Rng, RunningStats, SerializationFormat, Summary, UnknownKeys, OTHER_KEY,
+};
 use service::{PrivateAccess, Service};
 use task::{BackEnd, KeyedRawStorage, Op};
 
@@ -29,7 +33,7 @@ use task::{BackEnd, KeyedRawStorage, Op};
 /// histogram can be cloned as needed for concurrent use.
 ///
 /// # Performance
-////
+///
 /// Cloning a histogram is relatively cheap, both in terms of memory
 /// and in terms of speed (most histograms weigh ~40bytes on a x86-64
 /// architecture).
@@ -62,17 +66,87 @@ pub trait KeyedHistogram<K, T>: Clone {
         F: FnOnce() -> Option<(K, T)>;
 }
 
+///
+/// A key of a keyed histogram.
+///
+/// Each histogram interns its keys: the string under which values are
+/// serialized is computed once per distinct key, and each record only
+/// carries a compact identifier, without allocating. Histograms that
+/// store a bounded number of keys (see `ServiceBuilder::max_keys`)
+/// intern a bounded number of keys, and send other keys as strings.
+/// Histograms with key policies (see `Definition::key_policy`) intern
+/// at most 256 keys, as their keys may be rejected or rewritten.
+/// `KeyedPrivateFlag` never interns its keys.
+///
+/// This is implemented for strings, integers, `bool` and `char`. For
+/// other types, e.g. an enum of plug-ins, implement `to_key_string`.
+///
+pub trait TelemetryKey: Hash + Eq + Clone + Send + Sync + 'static {
+    /// The string under which values recorded with this key are serialized.
+    fn to_key_string(&self) -> String;
+}
+
+macro_rules! telemetry_key_to_string {
+    ($($ty:ty),*) => {
+        $(
+            impl TelemetryKey for $ty {
+                fn to_key_string(&self) -> String {
+                    self.to_string()
+                }
+            }
+        )*
+    };
+}
+
+telemetry_key_to_string!(
+    String,
+    &'static str,
+    u8,
+    u16,
+    u32,
+    u64,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    isize,
+    bool,
+    char
+);
+
 /// Back-end features specific to keyed histograms.
 impl<K> BackEnd<Keyed<K>>
 where
-    K: ToString,
+    K: TelemetryKey,
 {
+    /// Get the identifier of a user key, declaring it to the Telemetry
+    /// Task if it is new, or `None` if the histogram interns no more keys.
+    fn intern(&self, k: &Key<Keyed<K>>, key: &K) -> Option<u32> {
+        self.data.intern(key, |id, key| {
+            // Not a record, this may not be dropped. If the telemetry
+            // thread is gone, there is nothing to record anyway.
+            let _ = self.sender.send(Op::InternKey(k.index, id, key));
+        })
+    }
+
     /// Instruct the Telemetry Task to record a value in an
     /// already registered histogram.
-    fn raw_record(&self, k: &Key<Keyed<K>>, key: String, value: u32) {
-        self.sender
-            .send(Op::RecordKeyed(k.index, key, value))
-            .unwrap();
+    fn raw_record(&self, k: &Key<Keyed<K>>, key: K, value: u32) {
+        // If the telemetry thread is gone, there is nothing to record.
+        match (self.intern(k, &key), &self.batching) {
+            (Some(id), Some(batching)) => batching.record_keyed(k.index, id, value),
+            (Some(id), None) => {
+                let _ = self.sender.send_record(Op::RecordKeyed(k.index, id, value));
+            }
+            (None, _) => {
+                let _ = self.sender.send_record(Op::RecordKeyedRaw(
+                    k.index,
+                    key.to_key_string(),
+                    value,
+                ));
+            }
+        }
     }
 
     /// Instruct the Telemetry Task to record the result of a callback
@@ -82,9 +156,9 @@ where
         F: FnOnce() -> Option<(K, T)>,
         T: Flatten,
     {
-        if let Some(k) = self.get_key() {
+        if let Some(k) = self.get_sampled_key() {
             if let Some((key, v)) = cb() {
-                self.raw_record(&k, key.to_string(), v.as_u32());
+                self.raw_record(k, key, v.as_u32());
                 true
             } else {
                 false
@@ -95,6 +169,176 @@ where
     }
 }
 
+/// Back-end features that do not depend on the type of user keys.
+impl<K> BackEnd<Keyed<K>> {
+    /// Run a callback on the storage of this histogram, on the
+    /// Telemetry Task, and wait for its result.
+    ///
+    /// Returns `None` if the Telemetry Task is not running anymore.
+    fn query<S, F, R>(&self, cb: F) -> Option<R>
+    where
+        S: KeyedRawStorage + 'static,
+        F: FnOnce(&S) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        // Make sure that the records of this thread are visible.
+        if let Some(ref batching) = self.batching {
+            batching.flush_current();
+        }
+        let (sender, receiver) = channel();
+        let callback = Box::new(move |storage: &dyn KeyedRawStorage| {
+            // The storage is created alongside the histogram, so its
+            // type cannot mismatch.
+            let storage = storage.as_any().downcast_ref::<S>().unwrap();
+            let _ = sender.send(cb(storage));
+        });
+        if self
+            .sender
+            .send(Op::QueryKeyed(self.raw_key().index, callback))
+            .is_err()
+        {
+            return None;
+        }
+        receiver.recv().ok()
+    }
+}
+
+///
+/// The values of a keyed storage, by key.
+///
+/// By default, values are stored in a `HashMap` and keys only appear
+/// once a value has been recorded. With declared keys (see
+/// `Definition::with_keys`), values are preallocated in a dense array, and
+/// all declared keys appear, even if nothing has been recorded.
+///
+struct Slots<V> {
+    /// The value of a key for which nothing has been recorded.
+    zero: V,
+    values: SlotValues<V>,
+}
+
+enum SlotValues<V> {
+    Sparse(HashMap<String, V>),
+    Dense {
+        /// The declared keys, followed by `OTHER_KEY` if unknown keys
+        /// are recorded under it.
+        keys: Vec<String>,
+
+        /// The index of each key in `keys` and `values`.
+        indices: HashMap<String, usize>,
+
+        values: Vec<V>,
+    },
+}
+
+impl<V: Clone> Slots<V> {
+    fn new(definition: &Definition, zero: V) -> Slots<V> {
+        let values = match definition.keys() {
+            None => SlotValues::Sparse(HashMap::new()),
+            Some((declared, unknown)) => {
+                let mut keys: Vec<String> = Vec::with_capacity(declared.len() + 1);
+                for key in declared {
+                    if !keys.contains(key) {
+                        keys.push(key.clone());
+                    }
+                }
+                if unknown == UnknownKeys::Other {
+                    keys.push(OTHER_KEY.to_string());
+                }
+                let indices = keys
+                    .iter()
+                    .enumerate()
+                    .map(|(index, key)| (key.clone(), index))
+                    .collect();
+                SlotValues::Dense {
+                    values: vec_with_size(keys.len(), zero.clone()),
+                    keys,
+                    indices,
+                }
+            }
+        };
+        Slots { zero, values }
+    }
+
+    /// The value of a key, created if necessary, or `None` if the key
+    /// is not declared and unknown keys are rejected.
+    fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        match self.values {
+            SlotValues::Sparse(ref mut values) => {
+                // Only allocate for new keys.
+                if !values.contains_key(key) {
+                    values.insert(key.to_string(), self.zero.clone());
+                }
+                values.get_mut(key)
+            }
+            SlotValues::Dense {
+                ref indices,
+                ref mut values,
+                ..
+            } => {
+                let index = indices.get(key).or_else(|| indices.get(OTHER_KEY))?;
+                Some(&mut values[*index])
+            }
+        }
+    }
+
+    /// All keys and values, sorted by key.
+    fn sorted(&self) -> Vec<(&String, &V)> {
+        let mut sorted: Vec<_> = match self.values {
+            SlotValues::Sparse(ref values) => values.iter().collect(),
+            SlotValues::Dense {
+                ref keys,
+                ref values,
+                ..
+            } => keys.iter().zip(values).collect(),
+        };
+        sorted.sort_by(|a, b| a.0.cmp(b.0));
+        sorted
+    }
+
+    fn to_map(&self) -> HashMap<String, V> {
+        self.sorted()
+            .into_iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    fn reset(&mut self) {
+        match self.values {
+            SlotValues::Sparse(ref mut values) => values.clear(),
+            SlotValues::Dense { ref mut values, .. } => {
+                for value in values {
+                    *value = self.zero.clone();
+                }
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self.values {
+            SlotValues::Sparse(ref values) => values.len(),
+            SlotValues::Dense { ref keys, .. } => keys.len(),
+        }
+    }
+
+    fn is_new_key(&self, key: &str) -> bool {
+        match self.values {
+            SlotValues::Sparse(ref values) => !values.contains_key(key),
+            // No key is ever added.
+            SlotValues::Dense { .. } => false,
+        }
+    }
+
+    /// The number of user keys worth interning, see
+    /// `KeyedRawStorage::interned_keys`.
+    fn interned_keys(&self) -> Option<usize> {
+        match self.values {
+            SlotValues::Sparse(_) => None,
+            SlotValues::Dense { ref keys, .. } => Some(keys.len()),
+        }
+    }
+}
+
 ///
 /// A histogram that ignores any input.
 ///
@@ -153,49 +397,99 @@ impl<T, U> Clone for KeyedIgnoring<T, U> {
 /// serialized as an array of the keys with which it was called.
 /// Keys are sorted by alphabetical order, and appear only once.
 ///
+/// With declared keys (see `Definition::with_keys`), they are serialized
+/// as an object, with one field per declared key (sorted), each 0
+/// (unset) or 1 (set).
+///
 pub struct KeyedFlag<T> {
     back_end: BackEnd<Keyed<T>>,
 }
 
 impl<K> KeyedFlag<K>
 where
-    K: ToString,
+    K: TelemetryKey,
 {
-    pub fn new(service: &Service, name: String) -> KeyedFlag<K> {
+    pub fn new(service: &Service, name: impl Into<Definition>) -> KeyedFlag<K> {
+        let definition = name.into();
         let storage = Box::new(KeyedFlagStorage {
-            encountered: HashSet::new(),
+            encountered: Slots::new(&definition, false),
         });
-        let key = PrivateAccess::register_keyed(service, name, storage);
-        KeyedFlag {
-            back_end: BackEnd::new(service, key),
-        }
+        let back_end = PrivateAccess::register_keyed(service, definition, storage);
+        KeyedFlag { back_end }
+    }
+
+    ///
+    /// Get the set of keys for which the flag has been set.
+    ///
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
+    ///
+    pub fn snapshot(&self) -> Option<HashSet<String>> {
+        self.back_end.query(|storage: &KeyedFlagStorage| {
+            storage
+                .encountered
+                .sorted()
+                .into_iter()
+                .filter(|&(_, &set)| set)
+                .map(|(key, _)| key.clone())
+                .collect()
+        })
     }
 }
 
 struct KeyedFlagStorage {
-    encountered: HashSet<String>,
+    encountered: Slots<bool>,
 }
 
 impl KeyedRawStorage for KeyedFlagStorage {
-    fn store(&mut self, k: String, _: u32) {
-        self.encountered.insert(k);
+    fn store(&mut self, k: &str, _: u32) {
+        if let Some(set) = self.encountered.get_mut(k) {
+            *set = true;
+        }
+    }
+    fn store_n(&mut self, k: &str, value: u32, _: u32) {
+        self.store(k, value)
+    }
+    fn reset(&mut self) {
+        self.encountered.reset();
+    }
+    fn kind(&self) -> Kind {
+        Kind::KeyedFlag
     }
     fn to_json(&self, format: &SerializationFormat) -> Json {
         match format {
             SerializationFormat::SimpleJson => {
-                // Collect and sort the keys.
-                let mut keys: Vec<&String> = self.encountered.iter().collect();
-                keys.sort();
-                let array = keys.iter().map(|&x| Json::String(x.clone())).collect();
+                let keys = self.encountered.sorted();
+                if let SlotValues::Dense { .. } = self.encountered.values {
+                    let tree = keys
+                        .into_iter()
+                        .map(|(key, &set)| (key.clone(), Json::I64(set as i64)))
+                        .collect();
+                    return Json::Object(tree);
+                }
+                let array = keys
+                    .into_iter()
+                    .map(|(key, _)| Json::String(key.clone()))
+                    .collect();
                 Json::Array(array)
             }
         }
     }
+    fn key_count(&self) -> usize {
+        self.encountered.len()
+    }
+    fn is_new_key(&self, key: &str) -> bool {
+        self.encountered.is_new_key(key)
+    }
+    fn interned_keys(&self) -> Option<usize> {
+        self.encountered.interned_keys()
+    }
 }
 
 impl<K> KeyedHistogram<K, ()> for KeyedFlag<K>
 where
-    K: ToString,
+    K: TelemetryKey,
 {
     fn record_cb<F>(&self, cb: F)
     where
@@ -214,255 +508,251 @@ impl<T> Clone for KeyedFlag<T> {
 }
 
 ///
-/// Linear histograms.
 ///
+/// Boolean histograms.
 ///
-/// Linear histograms classify numeric integer values into same-sized
-/// buckets. This type is typically used for percentages, or to store a
-/// relatively precise of the amount of resources (time, memory) used
-/// by a section.
+/// For each key, count how many times `false` and `true` have been
+/// recorded, e.g. how often a check has failed and succeeded for each
+/// server during a session.
 ///
 ///
 /// With `SerializationFormat::SimpleJson`, these histograms are
-/// serialized as an object
-/// ````js
-/// {
-///   key_1: array_1,
-///   key_2: array_2,
-///   ...
-/// }
-/// ````
-///
-/// where each `array_i` is an array of numbers, one per bucket, in
-/// the numeric order of buckets
+/// serialized as an object, with keys sorted, in which each field is
+/// an array `[false_count, true_count]`.
 ///
-pub struct KeyedLinear<K, T>
-where
-    T: Flatten,
-{
-    witness: PhantomData<T>,
+pub struct KeyedBoolean<K> {
     back_end: BackEnd<Keyed<K>>,
 }
 
-type KeyedLinearBuckets = LinearBuckets;
-
-struct KeyedLinearStorage {
-    values: HashMap<String, Vec<u32>>,
-    shape: KeyedLinearBuckets,
+// The storage, owned by the Telemetry Task.
+struct KeyedBooleanStorage {
+    /// The number of `false` and `true` records, in this order.
+    values: Slots<[u32; 2]>,
 }
 
-impl KeyedLinearStorage {
-    fn new(shape: KeyedLinearBuckets) -> KeyedLinearStorage {
-        KeyedLinearStorage {
-            values: HashMap::new(),
-            shape,
+impl KeyedRawStorage for KeyedBooleanStorage {
+    fn store(&mut self, key: &str, value: u32) {
+        self.store_n(key, value, 1)
+    }
+    fn store_n(&mut self, key: &str, value: u32, times: u32) {
+        if let Some(counts) = self.values.get_mut(key) {
+            let count = &mut counts[(value != 0) as usize];
+            *count = count.saturating_add(times);
         }
     }
-}
-
-impl KeyedRawStorage for KeyedLinearStorage {
-    fn store(&mut self, key: String, value: u32) {
-        let index = self.shape.get_bucket(value);
-        match self.values.entry(key) {
-            Occupied(mut e) => {
-                e.get_mut()[index] += 1;
-            }
-            Vacant(e) => {
-                let mut vec = vec_with_size(self.shape.buckets, 0);
-                vec[index] += 1;
-                e.insert(vec);
+    fn reset(&mut self) {
+        self.values.reset();
+    }
+    fn kind(&self) -> Kind {
+        Kind::KeyedBoolean
+    }
+    fn to_json(&self, format: &SerializationFormat) -> Json {
+        match format {
+            SerializationFormat::SimpleJson => {
+                let tree = self
+                    .values
+                    .sorted()
+                    .into_iter()
+                    .map(|(key, counts)| {
+                        let array = counts.iter().map(|&x| Json::I64(x as i64)).collect();
+                        (key.clone(), Json::Array(array))
+                    })
+                    .collect();
+                Json::Object(tree)
             }
         }
     }
-    fn to_json(&self, _: &SerializationFormat) -> Json {
-        // Sort keys, for easier testing/comparison.
-        let mut values: Vec<_> = self.values.iter().collect();
-        values.sort();
-        // Turn everything into an object.
-        let mut tree = BTreeMap::new();
-        for value in values {
-            let (name, vec) = value;
-            let array = Json::Array(vec.iter().map(|&x| Json::I64(x as i64)).collect());
-            tree.insert(name.clone(), array);
-        }
-        Json::Object(tree)
+    fn key_count(&self) -> usize {
+        self.values.len()
+    }
+    fn is_new_key(&self, key: &str) -> bool {
+        self.values.is_new_key(key)
+    }
+    fn interned_keys(&self) -> Option<usize> {
+        self.values.interned_keys()
     }
 }
 
-impl<K, T> KeyedLinear<K, T>
+impl<K> KeyedHistogram<K, bool> for KeyedBoolean<K>
 where
-    K: ToString,
-    T: Flatten,
+    K: TelemetryKey,
 {
+    fn record_cb<F>(&self, cb: F)
+    where
+        F: FnOnce() -> Option<(K, bool)>,
+    {
+        self.back_end.raw_record_cb(cb);
+    }
+}
+
+impl<K> KeyedBoolean<K> {
     ///
-    /// Create a new Linear histogram with a given name.
+    /// Create a new KeyedBoolean histogram with a given name.
     ///
     /// Argument `name` is used as key when processing and exporting
-    /// the data. Each `name` must be unique to the `Service`.
-    ///
-    /// `min` is the minimal value expected to be entered in this
-    /// histogram. Any value lower than `min` is rounded up to `min`.
-    ///
-    /// `max` is the maximal value expected to be entered in this
-    /// histogram. Any value higher than `max` is rounded up to `max`.
-    ///
-    /// `buckets` is the number of buckets in this histogram. For
-    /// highest possible precision, use `buckets = max - min + 1`.
-    /// In most cases, however, such precision is not needed, so you
-    /// should use a lower number of buckets.
-    ///
-    ///
-    /// # Performance
-    ///
-    /// Increasing the number of buckets increases the memory usage on
-    /// the client by a few bytes per bucket per key. More
-    /// importantly, it also increases the size of the payload, hence
-    /// the total amount of data that the application will eventually
-    /// upload to a central server. If your application has many
-    /// clients and you wish to keep your server happy and your
-    /// bandwidth costs manageable, don't use too many buckets.
-    ///
+    /// the data. Each `name` must be unique to the `Service`: a
+    /// histogram registered with a name that is already used is
+    /// rejected and records nothing, see `Service`.
     ///
-    /// # Panics
-    ///
-    /// If `name` is already used by another histogram in `service`.
+    pub fn new(service: &Service, name: impl Into<Definition>) -> KeyedBoolean<K> {
+        let definition = name.into();
+        let storage = Box::new(KeyedBooleanStorage {
+            values: Slots::new(&definition, [0, 0]),
+        });
+        let back_end = PrivateAccess::register_keyed(service, definition, storage);
+        KeyedBoolean { back_end }
+    }
+
     ///
-    /// If `min >= max`.
+    /// Get the number of `false` and `true` values recorded so far, in
+    /// this order, for each key.
     ///
-    /// If `buckets < max - min + 1`.
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
     ///
-    pub fn new(
-        service: &Service,
-        name: String,
-        min: u32,
-        max: u32,
-        buckets: usize,
-    ) -> KeyedLinear<K, T> {
-        assert!(size_of::<u32>() <= size_of::<usize>());
-        assert!(min < max);
-        assert!(max - min >= buckets as u32);
-        let shape = KeyedLinearBuckets::new(min, max, buckets);
-        let storage = Box::new(KeyedLinearStorage::new(shape));
-        let key = PrivateAccess::register_keyed(service, name, storage);
-        KeyedLinear {
-            witness: PhantomData,
-            back_end: BackEnd::new(service, key),
-        }
-    }
-}
-
-impl<K, T> KeyedHistogram<K, T> for KeyedLinear<K, T>
-where
-    K: ToString,
-    T: Flatten,
-{
-    fn record_cb<F>(&self, cb: F)
-    where
-        F: FnOnce() -> Option<(K, T)>,
-    {
-        self.back_end.raw_record_cb(cb);
+    pub fn snapshot(&self) -> Option<HashMap<String, [u32; 2]>> {
+        self.back_end
+            .query(|storage: &KeyedBooleanStorage| storage.values.to_map())
     }
 }
 
-impl<K, T> Clone for KeyedLinear<K, T>
-where
-    T: Flatten,
-{
+impl<K> Clone for KeyedBoolean<K> {
     fn clone(&self) -> Self {
-        KeyedLinear {
+        KeyedBoolean {
             back_end: self.back_end.clone(),
-            witness: PhantomData,
         }
     }
 }
 
 ///
 ///
-/// Count histograms.
+/// Summary statistics.
 ///
-/// A Count histogram simply accumulates the numbers passed with `record()`.
+/// For each key, keep the number, sum, minimum, maximum, mean and
+/// variance of the values recorded, as `plain::Stats`. Payloads of
+/// several clients may be combined with `aggregate::merge_keyed_stats`.
 ///
 ///
 /// With `SerializationFormat::SimpleJson`, these histograms are
 /// serialized as an object, with keys sorted, in which each field is
-/// a number.
+/// an object as for `plain::Stats`.
 ///
-pub struct KeyedCount<K> {
+pub struct KeyedStats<K, T>
+where
+    T: Flatten,
+{
+    witness: PhantomData<T>,
     back_end: BackEnd<Keyed<K>>,
 }
 
 // The storage, owned by the Telemetry Task.
-struct KeyedCountStorage {
-    values: HashMap<String, u32>,
+struct KeyedStatsStorage {
+    values: Slots<RunningStats>,
 }
 
-impl KeyedRawStorage for KeyedCountStorage {
-    fn store(&mut self, key: String, value: u32) {
-        match self.values.entry(key) {
-            Occupied(mut e) => {
-                let v = *e.get();
-                e.insert(v + value);
-            }
-            Vacant(e) => {
-                e.insert(value);
-            }
+impl KeyedRawStorage for KeyedStatsStorage {
+    fn store(&mut self, key: &str, value: u32) {
+        self.store_n(key, value, 1)
+    }
+    fn store_n(&mut self, key: &str, value: u32, times: u32) {
+        if let Some(stats) = self.values.get_mut(key) {
+            stats.record_n(value, times);
         }
     }
+    fn reset(&mut self) {
+        self.values.reset();
+    }
+    fn kind(&self) -> Kind {
+        Kind::KeyedStats
+    }
     fn to_json(&self, format: &SerializationFormat) -> Json {
         match format {
             SerializationFormat::SimpleJson => {
-                // Sort keys, for easier testing/comparison.
-                let mut values: Vec<_> = self.values.iter().collect();
-                values.sort();
-                // Turn everything into an object.
-                let mut tree = BTreeMap::new();
-                for value in values {
-                    let (name, val) = value;
-                    tree.insert(name.clone(), Json::I64(*val as i64));
-                }
+                let tree = self
+                    .values
+                    .sorted()
+                    .into_iter()
+                    .map(|(key, stats)| (key.clone(), stats.to_json()))
+                    .collect();
                 Json::Object(tree)
             }
         }
     }
+    fn key_count(&self) -> usize {
+        self.values.len()
+    }
+    fn is_new_key(&self, key: &str) -> bool {
+        self.values.is_new_key(key)
+    }
+    fn interned_keys(&self) -> Option<usize> {
+        self.values.interned_keys()
+    }
 }
 
-impl<K> KeyedHistogram<K, u32> for KeyedCount<K>
+impl<K, T> KeyedHistogram<K, T> for KeyedStats<K, T>
 where
-    K: ToString,
+    K: TelemetryKey,
+    T: Flatten,
 {
     fn record_cb<F>(&self, cb: F)
     where
-        F: FnOnce() -> Option<(K, u32)>,
+        F: FnOnce() -> Option<(K, T)>,
     {
         self.back_end.raw_record_cb(cb);
     }
 }
 
-impl<K> KeyedCount<K> {
+impl<K, T> KeyedStats<K, T>
+where
+    T: Flatten,
+{
     ///
-    /// Create a new KeyedCount histogram with a given name.
+    /// Create a new KeyedStats histogram with a given name.
     ///
     /// Argument `name` is used as key when processing and exporting
-    /// the data. Each `name` must be unique to the `Service`.
+    /// the data. Each `name` must be unique to the `Service`: a
+    /// histogram registered with a name that is already used is
+    /// rejected and records nothing, see `Service`.
     ///
-    /// # Panics
-    ///
-    /// If `name` is already used by another histogram in `service`.
-    ///
-    pub fn new(service: &Service, name: String) -> KeyedCount<K> {
-        let storage = Box::new(KeyedCountStorage {
-            values: HashMap::new(),
+    pub fn new(service: &Service, name: impl Into<Definition>) -> KeyedStats<K, T> {
+        let definition = name.into();
+        let storage = Box::new(KeyedStatsStorage {
+            values: Slots::new(&definition, RunningStats::default()),
         });
-        let key = PrivateAccess::register_keyed(service, name, storage);
-        KeyedCount {
-            back_end: BackEnd::new(service, key),
+        let back_end = PrivateAccess::register_keyed(service, definition, storage);
+        KeyedStats {
+            witness: PhantomData,
+            back_end,
         }
     }
+
+    ///
+    /// Get the statistics of all values recorded so far, for each key.
+    ///
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
+    ///
+    pub fn snapshot(&self) -> Option<HashMap<String, Summary>> {
+        self.back_end.query(|storage: &KeyedStatsStorage| {
+            storage
+                .values
+                .sorted()
+                .into_iter()
+                .map(|(key, stats)| (key.clone(), stats.summary()))
+                .collect()
+        })
+    }
 }
 
-impl<K> Clone for KeyedCount<K> {
+impl<K, T> Clone for KeyedStats<K, T>
+where
+    T: Flatten,
+{
     fn clone(&self) -> Self {
-        KeyedCount {
+        KeyedStats {
+            witness: PhantomData,
             back_end: self.back_end.clone(),
         }
     }
@@ -470,119 +760,1369 @@ impl<K> Clone for KeyedCount<K> {
 
 ///
 ///
-/// Enumerated histograms.
+/// Privacy-preserving flag histograms.
 ///
-/// Enumerated histogram generalize Count histograms to families of
-/// keys known at compile-time. They are useful, for instance, to know
-/// how often users have picked a specific choice from several, or how
-/// many times each kind of error has been triggered, etc.
+/// As a `KeyedFlag`, this histogram records the set of keys with which
+/// `record()` has been called, but it never stores the keys. Instead,
+/// as in RAPPOR, each key is hashed into a Bloom filter of `bits` bits
+/// with `hashes` hash functions, and each bit of the filter is
+/// encoded with randomized response. A payload therefore reveals
+/// little about a single user, while the proportion of users who have
+/// recorded each of a list of candidate keys may be estimated from
+/// many payloads, see `aggregate::estimate_private_keyed_flags`.
+///
+/// Each bit is encoded with `epsilon / hashes`, so that recording one
+/// more key is `epsilon`-private. Each bit is encoded once when the
+/// histogram is created and once more when a key first sets it, so
+/// neither recording the same key repeatedly nor serializing the same
+/// session several times weakens the guarantee.
 ///
 ///
 /// With `SerializationFormat::SimpleJson`, these histograms are
-/// serialized as an object, one field per key (sorted), with value an
-/// array of numbers, in the order of enum values.
+/// serialized as an object
+/// ````js
+/// {
+///   epsilon: number,
+///   hashes: number,
+///   bits: array of `bits` numbers, each 0 or 1,
+/// }
+/// ````
 ///
-pub struct KeyedEnum<K, T>
+pub struct KeyedPrivateFlag<T> {
+    back_end: BackEnd<Keyed<T>>,
+}
+
+impl<K> KeyedPrivateFlag<K>
 where
-    K: ToString,
-    T: Flatten,
+    K: TelemetryKey,
 {
-    witness: PhantomData<T>,
-    back_end: BackEnd<Keyed<K>>,
+    ///
+    /// Create a new KeyedPrivateFlag histogram with a given name.
+    ///
+    /// - `name` is used as key when processing and exporting
+    ///   the data. Each `name` must be unique to the `Service`.
+    ///
+    /// - `bits` is the size of the Bloom filter and `hashes` the
+    ///   number of bits set by each key. Larger filters make
+    ///   collisions between keys less likely, at the expense of
+    ///   larger payloads.
+    ///
+    /// - `epsilon` is the privacy parameter. The lower, the more
+    ///   private, and the more payloads are needed for an accurate
+    ///   estimate.
+    ///
+    /// Each `name` must be unique to the `Service`: a histogram
+    /// registered with a name that is already used is rejected and
+    /// records nothing, see `Service`.
+    ///
+    /// # Panics
+    ///
+    /// If `hashes` is 0 or greater than `bits`, or if `epsilon` is not
+    /// positive.
+    ///
+    pub fn new(
+        service: &Service,
+        name: impl Into<Definition>,
+        bits: u32,
+        hashes: u32,
+        epsilon: f64,
+    ) -> KeyedPrivateFlag<K> {
+        assert!(hashes > 0 && hashes <= bits);
+        check_epsilon(epsilon);
+        let mut storage = Box::new(KeyedPrivateFlagStorage {
+            epsilon,
+            hashes,
+            rng: Rng::new(PrivateAccess::next_seed(service)),
+            encoded: vec_with_size(bits as usize, false),
+            recorded: vec_with_size(bits as usize, false),
+        });
+        storage.reset();
+        let back_end = PrivateAccess::register_keyed(service, name.into(), storage);
+        KeyedPrivateFlag { back_end }
+    }
 }
 
-// The storage, owned by the Telemetry Task.
-struct KeyedEnumStorage {
-    values: HashMap<String, Vec<u32>>,
+struct KeyedPrivateFlagStorage {
+    epsilon: f64,
+    hashes: u32,
+    rng: Rng,
+
+    /// The encoded Bloom filter. The keys are not stored.
+    encoded: Vec<bool>,
+
+    /// The bits already encoded as true. Each bit is encoded at most
+    /// once, as in RAPPOR's permanent randomized response, so that
+    /// recording the same key repeatedly reveals nothing more than
+    /// recording it once. Never serialized.
+    recorded: Vec<bool>,
 }
 
-impl KeyedRawStorage for KeyedEnumStorage {
-    fn store(&mut self, key: String, value: u32) {
-        match self.values.entry(key) {
-            Occupied(mut e) => {
-                let vec = e.get_mut();
-                vec.resize(value as usize + 1, 0);
-                vec[value as usize] += 1;
-            }
-            Vacant(e) => {
-                let mut vec = Vec::new();
-                vec.resize(value as usize + 1, 0);
-                vec[value as usize] = 1;
-                e.insert(vec);
+impl KeyedPrivateFlagStorage {
+    fn encode(&mut self, bit: usize, value: bool) {
+        let epsilon = self.epsilon / self.hashes as f64;
+        self.encoded[bit] = randomized_response(&mut self.rng, value as u32, 2, epsilon) == 1;
+    }
+}
+
+impl KeyedRawStorage for KeyedPrivateFlagStorage {
+    fn store(&mut self, k: &str, _: u32) {
+        for bit in bloom_bits(k, self.hashes, self.encoded.len() as u32) {
+            let bit = bit as usize;
+            if !self.recorded[bit] {
+                self.recorded[bit] = true;
+                self.encode(bit, true);
             }
         }
     }
+    fn store_n(&mut self, k: &str, value: u32, _: u32) {
+        self.store(k, value)
+    }
+    fn reset(&mut self) {
+        for bit in 0..self.encoded.len() {
+            self.recorded[bit] = false;
+            self.encode(bit, false);
+        }
+    }
+    fn kind(&self) -> Kind {
+        Kind::KeyedPrivateFlag {
+            bits: self.encoded.len() as u32,
+            hashes: self.hashes,
+            epsilon: self.epsilon,
+        }
+    }
     fn to_json(&self, format: &SerializationFormat) -> Json {
         match format {
             SerializationFormat::SimpleJson => {
-                // Sort keys, for easier testing/comparison.
-                let mut values: Vec<_> = self.values.iter().collect();
-                values.sort();
-                // Turn everything into an object.
                 let mut tree = BTreeMap::new();
-                for value in values {
-                    let (name, array) = value;
-                    let vec = array.iter().map(|&x| Json::I64(x as i64)).collect();
-                    tree.insert(name.clone(), Json::Array(vec));
-                }
+                tree.insert("epsilon".to_string(), Json::F64(self.epsilon));
+                tree.insert("hashes".to_string(), Json::I64(self.hashes as i64));
+                tree.insert(
+                    "bits".to_string(),
+                    Json::Array(
+                        self.encoded
+                            .iter()
+                            .map(|&bit| Json::I64(bit as i64))
+                            .collect(),
+                    ),
+                );
                 Json::Object(tree)
             }
         }
     }
+    fn key_count(&self) -> usize {
+        // Keys are not stored.
+        0
+    }
+    fn is_new_key(&self, _: &str) -> bool {
+        false
+    }
+    fn interned_keys(&self) -> Option<usize> {
+        // Keys are never stored, not even by the client.
+        Some(0)
+    }
 }
 
-impl<K, T> KeyedHistogram<K, T> for KeyedEnum<K, T>
+impl<K> KeyedHistogram<K, ()> for KeyedPrivateFlag<K>
 where
-    K: ToString,
-    T: Flatten,
+    K: TelemetryKey,
 {
-    ///
-    /// Record a value.
-    ///
-    /// Actual recording takes place on the background thread.
-    ///
     fn record_cb<F>(&self, cb: F)
     where
-        F: FnOnce() -> Option<(K, T)>,
+        F: FnOnce() -> Option<(K, ())>,
     {
         self.back_end.raw_record_cb(cb);
     }
 }
 
-impl<K, T> KeyedEnum<K, T>
+impl<T> Clone for KeyedPrivateFlag<T> {
+    fn clone(&self) -> Self {
+        KeyedPrivateFlag {
+            back_end: self.back_end.clone(),
+        }
+    }
+}
+
+///
+/// Linear histograms.
+///
+///
+/// Linear histograms classify numeric integer values into same-sized
+/// buckets. This type is typically used for percentages, or to store a
+/// relatively precise of the amount of resources (time, memory) used
+/// by a section.
+///
+///
+/// With `SerializationFormat::SimpleJson`, these histograms are
+/// serialized as an object
+/// ````js
+/// {
+///   key_1: array_1,
+///   key_2: array_2,
+///   ...
+/// }
+/// ````
+///
+/// where each `array_i` is an array of numbers, one per bucket, in
+/// the numeric order of buckets
+///
+pub struct KeyedLinear<K, T>
+where
+    T: Flatten,
+{
+    witness: PhantomData<T>,
+    back_end: BackEnd<Keyed<K>>,
+}
+
+type KeyedLinearBuckets = LinearBuckets;
+
+struct KeyedLinearStorage {
+    values: Slots<Vec<u32>>,
+    shape: KeyedLinearBuckets,
+}
+
+impl KeyedLinearStorage {
+    fn new(definition: &Definition, shape: KeyedLinearBuckets) -> KeyedLinearStorage {
+        KeyedLinearStorage {
+            values: Slots::new(definition, vec_with_size(shape.buckets, 0)),
+            shape,
+        }
+    }
+}
+
+impl KeyedRawStorage for KeyedLinearStorage {
+    fn store(&mut self, key: &str, value: u32) {
+        self.store_n(key, value, 1)
+    }
+    fn store_n(&mut self, key: &str, value: u32, times: u32) {
+        let index = self.shape.get_bucket(value);
+        if let Some(vec) = self.values.get_mut(key) {
+            vec[index] = vec[index].saturating_add(times);
+        }
+    }
+    fn reset(&mut self) {
+        self.values.reset();
+    }
+    fn kind(&self) -> Kind {
+        Kind::KeyedLinear {
+            min: self.shape.min(),
+            max: self.shape.max(),
+            buckets: self.shape.buckets,
+        }
+    }
+    fn to_json(&self, _: &SerializationFormat) -> Json {
+        // Turn everything into an object.
+        let mut tree = BTreeMap::new();
+        for (name, vec) in self.values.sorted() {
+            let array = Json::Array(vec.iter().map(|&x| Json::I64(x as i64)).collect());
+            tree.insert(name.clone(), array);
+        }
+        Json::Object(tree)
+    }
+    fn key_count(&self) -> usize {
+        self.values.len()
+    }
+    fn is_new_key(&self, key: &str) -> bool {
+        self.values.is_new_key(key)
+    }
+    fn interned_keys(&self) -> Option<usize> {
+        self.values.interned_keys()
+    }
+}
+
+impl<K, T> KeyedLinear<K, T>
 where
-    K: ToString,
+    K: TelemetryKey,
     T: Flatten,
 {
     ///
-    /// Create a new Enum histogram with a given name.
+    /// Create a new Linear histogram with a given name.
     ///
     /// Argument `name` is used as key when processing and exporting
-    /// the data. Each `name` must be unique to the `Service`.
+    /// the data. Each `name` must be unique to the `Service`: a
+    /// histogram registered with a name that is already used is
+    /// rejected and records nothing, see `Service`.
+    ///
+    /// `min` is the minimal value expected to be entered in this
+    /// histogram. Any value lower than `min` is rounded up to `min`.
+    ///
+    /// `max` is the maximal value expected to be entered in this
+    /// histogram. Any value higher than `max` is rounded up to `max`.
+    ///
+    /// `buckets` is the number of buckets in this histogram. For
+    /// highest possible precision, use `buckets = max - min + 1`.
+    /// In most cases, however, such precision is not needed, so you
+    /// should use a lower number of buckets.
+    ///
+    ///
+    /// # Performance
+    ///
+    /// Increasing the number of buckets increases the memory usage on
+    /// the client by a few bytes per bucket per key. More
+    /// importantly, it also increases the size of the payload, hence
+    /// the total amount of data that the application will eventually
+    /// upload to a central server. If your application has many
+    /// clients and you wish to keep your server happy and your
+    /// bandwidth costs manageable, don't use too many buckets.
+    ///
     ///
     /// # Panics
     ///
-    /// If `name` is already used by another histogram in `service`.
+    /// If `min >= max`.
     ///
-    pub fn new(service: &Service, name: String) -> KeyedEnum<K, T> {
-        let storage = Box::new(KeyedEnumStorage {
-            values: HashMap::new(),
-        });
-        let key = PrivateAccess::register_keyed(service, name, storage);
-        KeyedEnum {
+    /// If `buckets == 0` or `buckets > max - min + 1`.
+    ///
+    pub fn new(
+        service: &Service,
+        name: impl Into<Definition>,
+        min: u32,
+        max: u32,
+        buckets: usize,
+    ) -> KeyedLinear<K, T> {
+        assert!(size_of::<u32>() <= size_of::<usize>());
+        assert!(min < max);
+        assert!(buckets > 0 && buckets as u64 <= (max - min) as u64 + 1);
+        let shape = KeyedLinearBuckets::new(min, max, buckets);
+        let definition = name.into();
+        let storage = Box::new(KeyedLinearStorage::new(&definition, shape));
+        let back_end = PrivateAccess::register_keyed(service, definition, storage);
+        KeyedLinear {
             witness: PhantomData,
-            back_end: BackEnd::new(service, key),
+            back_end,
         }
     }
+
+    ///
+    /// Get the number of values in each bucket, for each key.
+    ///
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
+    ///
+    pub fn snapshot(&self) -> Option<HashMap<String, Vec<u32>>> {
+        self.back_end
+            .query(|storage: &KeyedLinearStorage| storage.values.to_map())
+    }
 }
 
-impl<K, T> Clone for KeyedEnum<K, T>
+impl<K, T> KeyedHistogram<K, T> for KeyedLinear<K, T>
+where
+    K: TelemetryKey,
+    T: Flatten,
+{
+    fn record_cb<F>(&self, cb: F)
+    where
+        F: FnOnce() -> Option<(K, T)>,
+    {
+        self.back_end.raw_record_cb(cb);
+    }
+}
+
+impl<K, T> Clone for KeyedLinear<K, T>
 where
-    K: ToString,
     T: Flatten,
 {
     fn clone(&self) -> Self {
-        KeyedEnum {
+        KeyedLinear {
+            back_end: self.back_end.clone(),
+            witness: PhantomData,
+        }
+    }
+}
+
+///
+///
+/// Count histograms.
+///
+/// A Count histogram simply accumulates the numbers passed with `record()`.
+/// Each count saturates at `u32::MAX`.
+///
+///
+/// With `SerializationFormat::SimpleJson`, these histograms are
+/// serialized as an object, with keys sorted, in which each field is
+/// a number.
+///
+pub struct KeyedCount<K> {
+    back_end: BackEnd<Keyed<K>>,
+}
+
+// The storage, owned by the Telemetry Task.
+struct KeyedCountStorage {
+    values: Slots<u32>,
+}
+
+impl KeyedRawStorage for KeyedCountStorage {
+    fn store(&mut self, key: &str, value: u32) {
+        self.store_n(key, value, 1)
+    }
+    fn store_n(&mut self, key: &str, value: u32, times: u32) {
+        if let Some(count) = self.values.get_mut(key) {
+            *count = count.saturating_add(value.saturating_mul(times));
+        }
+    }
+    fn reset(&mut self) {
+        self.values.reset();
+    }
+    fn kind(&self) -> Kind {
+        Kind::KeyedCount
+    }
+    fn to_json(&self, format: &SerializationFormat) -> Json {
+        match format {
+            SerializationFormat::SimpleJson => {
+                // Turn everything into an object.
+                let mut tree = BTreeMap::new();
+                for (name, val) in self.values.sorted() {
+                    tree.insert(name.clone(), Json::I64(*val as i64));
+                }
+                Json::Object(tree)
+            }
+        }
+    }
+    fn key_count(&self) -> usize {
+        self.values.len()
+    }
+    fn is_new_key(&self, key: &str) -> bool {
+        self.values.is_new_key(key)
+    }
+    fn interned_keys(&self) -> Option<usize> {
+        self.values.interned_keys()
+    }
+}
+
+impl<K> KeyedHistogram<K, u32> for KeyedCount<K>
+where
+    K: TelemetryKey,
+{
+    fn record_cb<F>(&self, cb: F)
+    where
+        F: FnOnce() -> Option<(K, u32)>,
+    {
+        self.back_end.raw_record_cb(cb);
+    }
+}
+
+impl<K> KeyedCount<K> {
+    ///
+    /// Create a new KeyedCount histogram with a given name.
+    ///
+    /// Argument `name` is used as key when processing and exporting
+    /// the data. Each `name` must be unique to the `Service`: a
+    /// histogram registered with a name that is already used is
+    /// rejected and records nothing, see `Service`.
+    ///
+    pub fn new(service: &Service, name: impl Into<Definition>) -> KeyedCount<K> {
+        let definition = name.into();
+        let storage = Box::new(KeyedCountStorage {
+            values: Slots::new(&definition, 0),
+        });
+        let back_end = PrivateAccess::register_keyed(service, definition, storage);
+        KeyedCount { back_end }
+    }
+
+    ///
+    /// Get the sum of all values recorded so far, for each key.
+    ///
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
+    ///
+    pub fn snapshot(&self) -> Option<HashMap<String, u32>> {
+        self.back_end
+            .query(|storage: &KeyedCountStorage| storage.values.to_map())
+    }
+}
+
+impl<K> Clone for KeyedCount<K> {
+    fn clone(&self) -> Self {
+        KeyedCount {
+            back_end: self.back_end.clone(),
+        }
+    }
+}
+
+///
+///
+/// Enumerated histograms.
+///
+/// Enumerated histogram generalize Count histograms to families of
+/// keys known at compile-time. They are useful, for instance, to know
+/// how often users have picked a specific choice from several, or how
+/// many times each kind of error has been triggered, etc.
+///
+///
+/// With `SerializationFormat::SimpleJson`, these histograms are
+/// serialized as an object, one field per key (sorted), with value an
+/// array of numbers, in the order of enum values.
+///
+pub struct KeyedEnum<K, T>
+where
+    K: TelemetryKey,
+    T: Flatten,
+{
+    witness: PhantomData<T>,
+    back_end: BackEnd<Keyed<K>>,
+}
+
+// The storage, owned by the Telemetry Task.
+struct KeyedEnumStorage {
+    values: Slots<Vec<u32>>,
+}
+
+impl KeyedRawStorage for KeyedEnumStorage {
+    fn store(&mut self, key: &str, value: u32) {
+        self.store_n(key, value, 1)
+    }
+    fn store_n(&mut self, key: &str, value: u32, times: u32) {
+        if let Some(vec) = self.values.get_mut(key) {
+            if vec.len() <= value as usize {
+                vec.resize(value as usize + 1, 0);
+            }
+            let count = &mut vec[value as usize];
+            *count = count.saturating_add(times);
+        }
+    }
+    fn reset(&mut self) {
+        self.values.reset();
+    }
+    fn kind(&self) -> Kind {
+        Kind::KeyedEnum
+    }
+    fn to_json(&self, format: &SerializationFormat) -> Json {
+        match format {
+            SerializationFormat::SimpleJson => {
+                // Turn everything into an object.
+                let mut tree = BTreeMap::new();
+                for (name, array) in self.values.sorted() {
+                    let vec = array.iter().map(|&x| Json::I64(x as i64)).collect();
+                    tree.insert(name.clone(), Json::Array(vec));
+                }
+                Json::Object(tree)
+            }
+        }
+    }
+    fn key_count(&self) -> usize {
+        self.values.len()
+    }
+    fn is_new_key(&self, key: &str) -> bool {
+        self.values.is_new_key(key)
+    }
+    fn interned_keys(&self) -> Option<usize> {
+        self.values.interned_keys()
+    }
+}
+
+impl<K, T> KeyedHistogram<K, T> for KeyedEnum<K, T>
+where
+    K: TelemetryKey,
+    T: Flatten,
+{
+    ///
+    /// Record a value.
+    ///
+    /// Actual recording takes place on the background thread.
+    ///
+    fn record_cb<F>(&self, cb: F)
+    where
+        F: FnOnce() -> Option<(K, T)>,
+    {
+        self.back_end.raw_record_cb(cb);
+    }
+}
+
+impl<K, T> KeyedEnum<K, T>
+where
+    K: TelemetryKey,
+    T: Flatten,
+{
+    ///
+    /// Create a new Enum histogram with a given name.
+    ///
+    /// Argument `name` is used as key when processing and exporting
+    /// the data. Each `name` must be unique to the `Service`: a
+    /// histogram registered with a name that is already used is
+    /// rejected and records nothing, see `Service`.
+    ///
+    pub fn new(service: &Service, name: impl Into<Definition>) -> KeyedEnum<K, T> {
+        let definition = name.into();
+        let storage = Box::new(KeyedEnumStorage {
+            values: Slots::new(&definition, Vec::new()),
+        });
+        let back_end = PrivateAccess::register_keyed(service, definition, storage);
+        KeyedEnum {
+            witness: PhantomData,
+            back_end,
+        }
+    }
+
+    ///
+    /// Get the number of values recorded for each variant, indexed
+    /// by `Flatten::as_u32`, for each key. Trailing variants that have
+    /// never been recorded for a key are omitted.
+    ///
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
+    ///
+    pub fn snapshot(&self) -> Option<HashMap<String, Vec<u32>>> {
+        self.back_end
+            .query(|storage: &KeyedEnumStorage| storage.values.to_map())
+    }
+}
+
+impl<K, T> Clone for KeyedEnum<K, T>
+where
+    K: TelemetryKey,
+    T: Flatten,
+{
+    fn clone(&self) -> Self {
+        KeyedEnum {
+            back_end: self.back_end.clone(),
+            witness: PhantomData,
+        }
+    }
+}
+
+///
+///
+/// Top-N histograms.
+///
+/// A TopN histogram accumulates the numbers passed with `record()`,
+/// as a `KeyedCount` does, but only keeps track of a fixed number of
+/// keys, which makes it suitable for very large sets of keys (domains,
+/// SQL statements, ...) when only the most frequent keys matter.
+///
+/// Counts are maintained with the Space-Saving algorithm. When a new
+/// key is recorded while all counters are in use, the key with the
+/// lowest count is evicted and its count is inherited by the new key.
+/// Consequently, counts are overestimated by at most their `error`,
+/// and any key whose actual count exceeds `total / capacity` is
+/// guaranteed to be present.
+///
+///
+/// With `SerializationFormat::SimpleJson`, these histograms are
+/// serialized as an array of at most `n` objects, by decreasing
+/// count:
+/// ````js
+/// [
+///   { key: string, count: number, error: number },
+///   ...
+/// ]
+/// ````
+///
+/// where the actual count of `key` lies within `[count - error, count]`.
+///
+pub struct KeyedTopN<K> {
+    back_end: BackEnd<Keyed<K>>,
+}
+
+struct TopNCounter {
+    count: u32,
+    error: u32,
+}
+
+// The storage, owned by the Telemetry Task.
+struct KeyedTopNStorage {
+    /// The number of keys to serialize.
+    report: usize,
+
+    /// The number of counters. Invariant: `report <= capacity`.
+    capacity: usize,
+
+    /// Invariant: `counters.len() <= capacity`.
+    counters: HashMap<String, TopNCounter>,
+}
+
+impl KeyedRawStorage for KeyedTopNStorage {
+    fn store(&mut self, key: &str, value: u32) {
+        self.store_n(key, value, 1)
+    }
+    fn store_n(&mut self, key: &str, value: u32, times: u32) {
+        let value = value.saturating_mul(times);
+        if let Some(counter) = self.counters.get_mut(key) {
+            counter.count = counter.count.saturating_add(value);
+            return;
+        }
+        if self.counters.len() < self.capacity {
+            self.counters.insert(
+                key.to_string(),
+                TopNCounter {
+                    count: value,
+                    error: 0,
+                },
+            );
+            return;
+        }
+        // Evict the smallest counter. This is linear in `capacity`,
+        // which is expected to remain small.
+        let (evicted, min) = match self
+            .counters
+            .iter()
+            .min_by(|a, b| (a.1.count, a.0).cmp(&(b.1.count, b.0)))
+        {
+            Some((evicted, counter)) => (evicted.clone(), counter.count),
+            None => return, // `capacity` is 0, we can't store anything.
+        };
+        self.counters.remove(&evicted);
+        self.counters.insert(
+            key.to_string(),
+            TopNCounter {
+                count: min.saturating_add(value),
+                error: min,
+            },
+        );
+    }
+    fn reset(&mut self) {
+        self.counters.clear();
+    }
+    fn kind(&self) -> Kind {
+        Kind::KeyedTopN {
+            n: self.report,
+            capacity: self.capacity,
+        }
+    }
+    fn to_json(&self, format: &SerializationFormat) -> Json {
+        match format {
+            SerializationFormat::SimpleJson => {
+                // Sort by decreasing count, then by key, for easier testing/comparison.
+                let mut values: Vec<_> = self.counters.iter().collect();
+                values.sort_by(|a, b| (b.1.count, a.0).cmp(&(a.1.count, b.0)));
+                let array = values
+                    .iter()
+                    .take(self.report)
+                    .map(|&(key, counter)| {
+                        let mut tree = BTreeMap::new();
+                        tree.insert("key".to_string(), Json::String(key.clone()));
+                        tree.insert("count".to_string(), Json::I64(counter.count as i64));
+                        tree.insert("error".to_string(), Json::I64(counter.error as i64));
+                        Json::Object(tree)
+                    })
+                    .collect();
+                Json::Array(array)
+            }
+        }
+    }
+    fn key_count(&self) -> usize {
+        self.counters.len()
+    }
+    fn is_new_key(&self, key: &str) -> bool {
+        // Once all counters are in use, new keys replace existing ones.
+        self.counters.len() < self.capacity && !self.counters.contains_key(key)
+    }
+    fn interned_keys(&self) -> Option<usize> {
+        Some(self.capacity)
+    }
+}
+
+impl<K> KeyedHistogram<K, u32> for KeyedTopN<K>
+where
+    K: TelemetryKey,
+{
+    fn record_cb<F>(&self, cb: F)
+    where
+        F: FnOnce() -> Option<(K, u32)>,
+    {
+        self.back_end.raw_record_cb(cb);
+    }
+}
+
+impl<K> KeyedTopN<K> {
+    ///
+    /// Create a new KeyedTopN histogram with a given name.
+    ///
+    /// - `name` is used as key when processing and exporting
+    ///   the data. Each `name` must be unique to the `Service`.
+    ///
+    /// - `n` is the number of keys reported during serialization.
+    ///
+    /// - `capacity` is the number of counters kept in memory. Using
+    ///   more counters than reported keys tightens the error bounds
+    ///   of the reported keys, at the expense of memory.
+    ///
+    /// Each `name` must be unique to the `Service`: a histogram
+    /// registered with a name that is already used is rejected and
+    /// records nothing, see `Service`.
+    ///
+    /// # Panics
+    ///
+    /// If `n == 0` or `capacity < n`.
+    ///
+    pub fn new(
+        service: &Service,
+        name: impl Into<Definition>,
+        n: usize,
+        capacity: usize,
+    ) -> KeyedTopN<K> {
+        assert!(n > 0);
+        assert!(capacity >= n);
+        let storage = Box::new(KeyedTopNStorage {
+            report: n,
+            capacity,
+            counters: HashMap::with_capacity(capacity),
+        });
+        let back_end = PrivateAccess::register_keyed(service, name.into(), storage);
+        KeyedTopN { back_end }
+    }
+}
+
+impl<K> Clone for KeyedTopN<K> {
+    fn clone(&self) -> Self {
+        KeyedTopN {
+            back_end: self.back_end.clone(),
+        }
+    }
+}
+
+///
+/// A family of histograms, indexed by two dynamic values, e.g. a
+/// plug-in and an operation. Use these to monitor values that depend
+/// on two dimensions that cannot be determined at compile-time.
+///
+/// Values are stored and serialized as nested maps, by first key,
+/// then by second key. Each dimension may be capped, and key policies
+/// (see `Definition::key_policy`) apply to each key separately. Values
+/// whose keys are rejected, either by the policies or by the caps, are
+/// counted as `rejected_keys` in `__telemetry__`.
+///
+pub trait KeyedHistogram2<K1, K2, T>: Clone {
+    ///
+    /// Record a value in this histogram.
+    ///
+    /// If the service is currently inactive, this is a noop.
+    ///
+    fn record(&self, key1: K1, key2: K2, value: T) {
+        self.record_cb(|| Some((key1, key2, value)))
+    }
+
+    ///
+    /// Record a value in this histogram, as provided by a callback.
+    ///
+    /// If the service is currently inactive, this is a noop.
+    ///
+    /// If the callback returns `None`, no value is recorded.
+    ///
+    fn record_cb<F>(&self, _: F)
+    where
+        F: FnOnce() -> Option<(K1, K2, T)>;
+}
+
+/// One of the keys of a histogram with two dimensions. Both
+/// dimensions share the interned keys of the histogram.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Dimension<K1, K2> {
+    First(K1),
+    Second(K2),
+}
+
+impl<K1, K2> TelemetryKey for Dimension<K1, K2>
+where
+    K1: TelemetryKey,
+    K2: TelemetryKey,
+{
+    fn to_key_string(&self) -> String {
+        match *self {
+            Dimension::First(ref key) => key.to_key_string(),
+            Dimension::Second(ref key) => key.to_key_string(),
+        }
+    }
+}
+
+/// Back-end features specific to keyed histograms with two dimensions.
+impl<K1, K2> BackEnd<Keyed<Dimension<K1, K2>>>
+where
+    K1: TelemetryKey,
+    K2: TelemetryKey,
+{
+    /// Instruct the Telemetry Task to record the result of a callback
+    /// in an already registered histogram.
+    fn raw_record2_cb<F, T>(&self, cb: F) -> bool
+    where
+        F: FnOnce() -> Option<(K1, K2, T)>,
+        T: Flatten,
+    {
+        let k = match self.get_sampled_key() {
+            Some(k) => k,
+            None => return false,
+        };
+        let (key1, key2, value) = match cb() {
+            Some(record) => record,
+            None => return false,
+        };
+        let key1 = Dimension::First(key1);
+        let key2 = Dimension::Second(key2);
+        let value = value.as_u32();
+        // If the telemetry thread is gone, there is nothing to record.
+        match (self.intern(k, &key1), self.intern(k, &key2), &self.batching) {
+            (Some(id1), Some(id2), Some(batching)) => {
+                batching.record_keyed2(k.index, id1, id2, value)
+            }
+            (Some(id1), Some(id2), None) => {
+                let _ = self
+                    .sender
+                    .send_record(Op::RecordKeyed2(k.index, id1, id2, value));
+            }
+            _ => {
+                let _ = self.sender.send_record(Op::RecordKeyed2Raw(
+                    k.index,
+                    key1.to_key_string(),
+                    key2.to_key_string(),
+                    value,
+                ));
+            }
+        }
+        true
+    }
+}
+
+///
+/// The values of a storage with two dimensions, by first key, then by
+/// second key, with optional limits on the number of first keys and
+/// on the number of second keys for each first key.
+///
+struct Nested<V> {
+    /// The value of keys for which nothing has been recorded.
+    zero: V,
+    max_first_keys: Option<usize>,
+    max_second_keys: Option<usize>,
+    values: HashMap<String, HashMap<String, V>>,
+}
+
+impl<V: Clone> Nested<V> {
+    fn new(zero: V, max_first_keys: Option<usize>, max_second_keys: Option<usize>) -> Nested<V> {
+        Nested {
+            zero,
+            max_first_keys,
+            max_second_keys,
+            values: HashMap::new(),
+        }
+    }
+
+    /// The value of keys `(key1, key2)`, inserting it if necessary,
+    /// or `None` if this would exceed the limits.
+    fn get_mut(&mut self, key1: &str, key2: &str) -> Option<&mut V> {
+        // Only allocate for new keys.
+        if !self.values.contains_key(key1) {
+            if let Some(max) = self.max_first_keys {
+                if self.values.len() >= max {
+                    return None;
+                }
+            }
+            self.values.insert(key1.to_string(), HashMap::new());
+        }
+        let row = self.values.get_mut(key1).unwrap();
+        if !row.contains_key(key2) {
+            if let Some(max) = self.max_second_keys {
+                if row.len() >= max {
+                    return None;
+                }
+            }
+            row.insert(key2.to_string(), self.zero.clone());
+        }
+        row.get_mut(key2)
+    }
+
+    fn to_map(&self) -> HashMap<String, HashMap<String, V>> {
+        self.values.clone()
+    }
+
+    /// The values of all second keys, for first key `key1`.
+    fn slice_first(&self, key1: &str) -> HashMap<String, V> {
+        self.values.get(key1).cloned().unwrap_or_default()
+    }
+
+    /// The values of all first keys, for second key `key2`.
+    fn slice_second(&self, key2: &str) -> HashMap<String, V> {
+        self.values
+            .iter()
+            .filter_map(|(key1, row)| row.get(key2).map(|value| (key1.clone(), value.clone())))
+            .collect()
+    }
+
+    /// Serialize as nested objects, with keys sorted.
+    fn to_json<F>(&self, value_to_json: F) -> Json
+    where
+        F: Fn(&V) -> Json,
+    {
+        let tree = self
+            .values
+            .iter()
+            .map(|(key1, row)| {
+                let row = row
+                    .iter()
+                    .map(|(key2, value)| (key2.clone(), value_to_json(value)))
+                    .collect();
+                (key1.clone(), Json::Object(row))
+            })
+            .collect();
+        Json::Object(tree)
+    }
+
+    fn reset(&mut self) {
+        self.values.clear();
+    }
+
+    /// The number of first keys.
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn is_new_key(&self, key1: &str) -> bool {
+        !self.values.contains_key(key1)
+    }
+
+    /// The number of user keys worth interning, see
+    /// `KeyedRawStorage::interned_keys`.
+    fn interned_keys(&self) -> Option<usize> {
+        let first = self.max_first_keys?;
+        let second = self.max_second_keys?;
+        Some(first.saturating_add(first.saturating_mul(second)))
+    }
+}
+
+///
+///
+/// Count histograms with two dimensions.
+///
+/// A KeyedCount2 histogram simply accumulates the numbers passed with
+/// `record()`, for each pair of keys. Each count saturates at `u32::MAX`.
+///
+///
+/// With `SerializationFormat::SimpleJson`, these histograms are
+/// serialized as an object
+/// ````js
+/// {
+///   key1_1: {
+///     key2_1: count,
+///     key2_2: count,
+///     ...
+///   },
+///   ...
+/// }
+/// ````
+///
+/// with keys sorted.
+///
+pub struct KeyedCount2<K1, K2> {
+    back_end: BackEnd<Keyed<Dimension<K1, K2>>>,
+}
+
+// The storage, owned by the Telemetry Task.
+struct KeyedCount2Storage {
+    values: Nested<u32>,
+}
+
+impl KeyedRawStorage for KeyedCount2Storage {
+    fn store(&mut self, _: &str, _: u32) {
+        // Values are always recorded with two keys, see `store2`.
+    }
+    fn store2(&mut self, key1: &str, key2: &str, value: u32, times: u32) -> bool {
+        match self.values.get_mut(key1, key2) {
+            Some(count) => {
+                *count = count.saturating_add(value.saturating_mul(times));
+                true
+            }
+            None => false,
+        }
+    }
+    fn reset(&mut self) {
+        self.values.reset();
+    }
+    fn kind(&self) -> Kind {
+        Kind::KeyedCount2 {
+            max_first_keys: self.values.max_first_keys,
+            max_second_keys: self.values.max_second_keys,
+        }
+    }
+    fn to_json(&self, format: &SerializationFormat) -> Json {
+        match format {
+            SerializationFormat::SimpleJson => {
+                self.values.to_json(|&count| Json::I64(count as i64))
+            }
+        }
+    }
+    fn key_count(&self) -> usize {
+        self.values.len()
+    }
+    fn is_new_key(&self, key: &str) -> bool {
+        self.values.is_new_key(key)
+    }
+    fn interned_keys(&self) -> Option<usize> {
+        self.values.interned_keys()
+    }
+}
+
+impl<K1, K2> KeyedHistogram2<K1, K2, u32> for KeyedCount2<K1, K2>
+where
+    K1: TelemetryKey,
+    K2: TelemetryKey,
+{
+    fn record_cb<F>(&self, cb: F)
+    where
+        F: FnOnce() -> Option<(K1, K2, u32)>,
+    {
+        self.back_end.raw_record2_cb(cb);
+    }
+}
+
+impl<K1, K2> KeyedCount2<K1, K2> {
+    ///
+    /// Create a new KeyedCount2 histogram with a given name.
+    ///
+    /// Argument `name` is used as key when processing and exporting
+    /// the data. Each `name` must be unique to the `Service`: a
+    /// histogram registered with a name that is already used is
+    /// rejected and records nothing, see `Service`.
+    ///
+    /// If `max_first_keys` is specified, values for new first keys
+    /// are rejected once the histogram holds `max_first_keys` first
+    /// keys. If `max_second_keys` is specified, values for new second
+    /// keys are rejected once a first key holds `max_second_keys`
+    /// second keys.
+    ///
+    pub fn new(
+        service: &Service,
+        name: impl Into<Definition>,
+        max_first_keys: Option<usize>,
+        max_second_keys: Option<usize>,
+    ) -> KeyedCount2<K1, K2> {
+        let storage = Box::new(KeyedCount2Storage {
+            values: Nested::new(0, max_first_keys, max_second_keys),
+        });
+        let back_end = PrivateAccess::register_keyed(service, name.into(), storage);
+        KeyedCount2 { back_end }
+    }
+
+    ///
+    /// Get the sum of all values recorded so far, by first key, then
+    /// by second key.
+    ///
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
+    ///
+    pub fn snapshot(&self) -> Option<HashMap<String, HashMap<String, u32>>> {
+        self.back_end
+            .query(|storage: &KeyedCount2Storage| storage.values.to_map())
+    }
+
+    ///
+    /// Get the sum of all values recorded so far with first key
+    /// `key1`, by second key. Keys are compared after applying the key
+    /// policies.
+    ///
+    /// This waits for the Telemetry Task, as `snapshot`.
+    ///
+    pub fn slice_first(&self, key1: &str) -> Option<HashMap<String, u32>> {
+        let key1 = key1.to_string();
+        self.back_end
+            .query(move |storage: &KeyedCount2Storage| storage.values.slice_first(&key1))
+    }
+
+    ///
+    /// Get the sum of all values recorded so far with second key
+    /// `key2`, by first key. Keys are compared after applying the key
+    /// policies.
+    ///
+    /// This waits for the Telemetry Task, as `snapshot`.
+    ///
+    pub fn slice_second(&self, key2: &str) -> Option<HashMap<String, u32>> {
+        let key2 = key2.to_string();
+        self.back_end
+            .query(move |storage: &KeyedCount2Storage| storage.values.slice_second(&key2))
+    }
+}
+
+impl<K1, K2> Clone for KeyedCount2<K1, K2> {
+    fn clone(&self) -> Self {
+        KeyedCount2 {
+            back_end: self.back_end.clone(),
+        }
+    }
+}
+
+///
+/// Linear histograms with two dimensions.
+///
+///
+/// As `KeyedLinear`, but indexed by two keys.
+///
+///
+/// With `SerializationFormat::SimpleJson`, these histograms are
+/// serialized as an object
+/// ````js
+/// {
+///   key1_1: {
+///     key2_1: array,
+///     key2_2: array,
+///     ...
+///   },
+///   ...
+/// }
+/// ````
+///
+/// where each `array` is an array of numbers, one per bucket, in
+/// the numeric order of buckets, with keys sorted.
+///
+pub struct KeyedLinear2<K1, K2, T>
+where
+    T: Flatten,
+{
+    witness: PhantomData<T>,
+    back_end: BackEnd<Keyed<Dimension<K1, K2>>>,
+}
+
+struct KeyedLinear2Storage {
+    values: Nested<Vec<u32>>,
+    shape: KeyedLinearBuckets,
+}
+
+impl KeyedRawStorage for KeyedLinear2Storage {
+    fn store(&mut self, _: &str, _: u32) {
+        // Values are always recorded with two keys, see `store2`.
+    }
+    fn store2(&mut self, key1: &str, key2: &str, value: u32, times: u32) -> bool {
+        let index = self.shape.get_bucket(value);
+        match self.values.get_mut(key1, key2) {
+            Some(vec) => {
+                vec[index] = vec[index].saturating_add(times);
+                true
+            }
+            None => false,
+        }
+    }
+    fn reset(&mut self) {
+        self.values.reset();
+    }
+    fn kind(&self) -> Kind {
+        Kind::KeyedLinear2 {
+            min: self.shape.min(),
+            max: self.shape.max(),
+            buckets: self.shape.buckets,
+            max_first_keys: self.values.max_first_keys,
+            max_second_keys: self.values.max_second_keys,
+        }
+    }
+    fn to_json(&self, _: &SerializationFormat) -> Json {
+        self.values
+            .to_json(|vec| Json::Array(vec.iter().map(|&x| Json::I64(x as i64)).collect()))
+    }
+    fn key_count(&self) -> usize {
+        self.values.len()
+    }
+    fn is_new_key(&self, key: &str) -> bool {
+        self.values.is_new_key(key)
+    }
+    fn interned_keys(&self) -> Option<usize> {
+        self.values.interned_keys()
+    }
+}
+
+impl<K1, K2, T> KeyedLinear2<K1, K2, T>
+where
+    K1: TelemetryKey,
+    K2: TelemetryKey,
+    T: Flatten,
+{
+    ///
+    /// Create a new KeyedLinear2 histogram with a given name.
+    ///
+    /// Arguments `name`, `min`, `max` and `buckets` are as for
+    /// `KeyedLinear::new`, and `max_first_keys` and `max_second_keys`
+    /// as for `KeyedCount2::new`.
+    ///
+    ///
+    /// Each `name` must be unique to the `Service`: a histogram
+    /// registered with a name that is already used is rejected and
+    /// records nothing, see `Service`.
+    ///
+    /// # Panics
+    ///
+    /// If `min >= max`.
+    ///
+    /// If `buckets == 0` or `buckets > max - min + 1`.
+    ///
+    pub fn new(
+        service: &Service,
+        name: impl Into<Definition>,
+        min: u32,
+        max: u32,
+        buckets: usize,
+        max_first_keys: Option<usize>,
+        max_second_keys: Option<usize>,
+    ) -> KeyedLinear2<K1, K2, T> {
+        assert!(size_of::<u32>() <= size_of::<usize>());
+        assert!(min < max);
+        assert!(buckets > 0 && buckets as u64 <= (max - min) as u64 + 1);
+        let shape = KeyedLinearBuckets::new(min, max, buckets);
+        let storage = Box::new(KeyedLinear2Storage {
+            values: Nested::new(vec_with_size(buckets, 0), max_first_keys, max_second_keys),
+            shape,
+        });
+        let back_end = PrivateAccess::register_keyed(service, name.into(), storage);
+        KeyedLinear2 {
+            witness: PhantomData,
+            back_end,
+        }
+    }
+
+    ///
+    /// Get the number of values in each bucket, by first key, then by
+    /// second key.
+    ///
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
+    ///
+    pub fn snapshot(&self) -> Option<HashMap<String, HashMap<String, Vec<u32>>>> {
+        self.back_end
+            .query(|storage: &KeyedLinear2Storage| storage.values.to_map())
+    }
+
+    ///
+    /// Get the number of values in each bucket for first key `key1`,
+    /// by second key. Keys are compared after applying the key
+    /// policies.
+    ///
+    /// This waits for the Telemetry Task, as `snapshot`.
+    ///
+    pub fn slice_first(&self, key1: &str) -> Option<HashMap<String, Vec<u32>>> {
+        let key1 = key1.to_string();
+        self.back_end
+            .query(move |storage: &KeyedLinear2Storage| storage.values.slice_first(&key1))
+    }
+
+    ///
+    /// Get the number of values in each bucket for second key `key2`,
+    /// by first key. Keys are compared after applying the key
+    /// policies.
+    ///
+    /// This waits for the Telemetry Task, as `snapshot`.
+    ///
+    pub fn slice_second(&self, key2: &str) -> Option<HashMap<String, Vec<u32>>> {
+        let key2 = key2.to_string();
+        self.back_end
+            .query(move |storage: &KeyedLinear2Storage| storage.values.slice_second(&key2))
+    }
+}
+
+impl<K1, K2, T> KeyedHistogram2<K1, K2, T> for KeyedLinear2<K1, K2, T>
+where
+    K1: TelemetryKey,
+    K2: TelemetryKey,
+    T: Flatten,
+{
+    fn record_cb<F>(&self, cb: F)
+    where
+        F: FnOnce() -> Option<(K1, K2, T)>,
+    {
+        self.back_end.raw_record2_cb(cb);
+    }
+}
+
+impl<K1, K2, T> Clone for KeyedLinear2<K1, K2, T>
+where
+    T: Flatten,
+{
+    fn clone(&self) -> Self {
+        KeyedLinear2 {
             back_end: self.back_end.clone(),
             witness: PhantomData,
         }
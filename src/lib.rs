@@ -33,6 +33,8 @@
 //! open-source implementation of a server implementing the Telemetry
 //! protocol.
 
+#[cfg(feature = "regex")]
+extern crate regex;
 extern crate rustc_serialize;
 
 mod misc;
@@ -46,6 +48,37 @@ pub use misc::SerializationFormat;
 /// A subset of data to export.
 pub use misc::Subset;
 
+/// The definition of a histogram, with optional metadata.
+pub use misc::Definition;
+
+/// Categories of collected data, for the purpose of user consent.
+pub use misc::Category;
+
+/// How a histogram is sampled.
+pub use misc::Sampling;
+
+/// What keyed histograms with declared keys do with other keys.
+pub use misc::{UnknownKeys, OTHER_KEY};
+
+/// What to do with records when the queue of the telemetry thread is full.
+pub use misc::OverflowPolicy;
+
+/// How histograms are grouped by namespace when serialized.
+pub use misc::Layout;
+
+/// Introspection of the histograms registered with a service.
+pub use misc::{HistogramInfo, Kind};
+
+/// Summary statistics, as reported by `plain::Stats` and `keyed::KeyedStats`.
+pub use misc::Summary;
+
+mod batch;
+
+mod policy;
+
+/// Policies applied to the keys of keyed histograms.
+pub use policy::KeyPolicy;
+
 mod indexing;
 
 mod task;
@@ -62,7 +95,31 @@ pub mod keyed;
 /// Keyed histograms.
 pub use keyed::KeyedHistogram;
 
+/// Keyed histograms with two dimensions of keys.
+pub use keyed::KeyedHistogram2;
+
+/// Keys of keyed histograms.
+pub use keyed::TelemetryKey;
+
+/// Helpers for combining payloads, e.g. on a server.
+pub mod aggregate;
+
 mod service;
 
 /// The Telemetry Service. You need one (or more) per application.
 pub use service::Service;
+
+/// A builder for the Telemetry Service, for finer configuration.
+pub use service::ServiceBuilder;
+
+/// The data returned by `Service::shutdown`.
+pub use service::FinalSnapshot;
+
+/// The result of `Service::shutdown`: the data and the errors of the
+/// shutdown hooks.
+pub use service::ShutdownOutcome;
+
+mod clock;
+
+/// Sources of time, used by time-dependent histograms.
+pub use clock::{Clock, MockClock, SystemClock};
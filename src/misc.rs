@@ -1,6 +1,17 @@
-///!
-///! Misc stuff used throughout the crate.
-///!
+//!
+//! Misc stuff used throughout the crate.
+//!
+
+use rustc_serialize::json::Json;
+
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use policy::KeyPolicy;
 
 ///
 /// A storage with a name attached.
@@ -9,26 +20,414 @@
 ///
 pub struct NamedStorage<T: ?Sized> {
     /// The name of the storage. Also used as a key, must be unique.
+    /// For a histogram registered through a scope (see
+    /// `Service::scope`), this is the full name, e.g. `network.requests`.
     pub name: String,
 
-    ///
+    /// The namespaces in which the histogram was registered, outermost
+    /// first, e.g. `["network"]`.
+    pub namespace: Vec<String>,
+
+    /// `true` if the histogram has expired, in which case it is
+    /// neither recorded nor serialized.
+    pub expired: bool,
+
+    /// The category of data collected by the histogram.
+    pub category: Category,
+
+    /// The kind of the histogram, for introspection, determined upon
+    /// registration so that it is available even if the storage is
+    /// poisoned.
+    pub kind: Kind,
+
+    /// `true` if the user has consented to the collection of data of
+    /// this category. Otherwise, the histogram is neither recorded nor
+    /// serialized.
+    pub consented: bool,
+
+    /// `true` if an operation on the storage has panicked, in which
+    /// case the contents may be inconsistent, so the histogram is
+    /// neither recorded nor serialized anymore.
+    pub poisoned: bool,
+
+    /// How the histogram is sampled, if at all, see
+    /// `Definition::with_sampling`.
+    pub sampling: Option<Sampling>,
+
+    /// `true` if the histogram is sampled per client and this client
+    /// has not been selected, in which case the histogram is neither
+    /// recorded nor serialized.
+    pub sampled_out: bool,
+
+    /// The policies applied to keys, for a keyed histogram, see
+    /// `Definition::key_policy`.
+    pub key_policies: Vec<KeyPolicy>,
+
+    /// The number of keys rejected by `key_policies`.
+    pub rejected_keys: u64,
+
+    /// For a keyed histogram, the user keys, by identifier (see
+    /// `Op::InternKey`), after applying `key_policies`. `None` if the
+    /// key has been rejected.
+    pub keys: Vec<Option<Arc<str>>>,
+
+    /// The actual storage.
     pub contents: Box<T>,
 }
 
+impl<T: ?Sized> NamedStorage<T> {
+    /// `true` if the histogram should appear in serialized data.
+    pub fn is_serialized(&self) -> bool {
+        !self.expired && self.consented && !self.sampled_out
+    }
+
+    /// Apply `key_policies` to a user key, returning `None` if the key
+    /// is rejected. Custom policies may panic, in which case the
+    /// histogram is poisoned.
+    pub fn apply_key_policies(&mut self, key: String) -> Option<Arc<str>> {
+        let policies = &self.key_policies;
+        match catch_unwind(AssertUnwindSafe(|| {
+            policies
+                .iter()
+                .try_fold(key, |key, policy| policy.apply(key))
+        })) {
+            Ok(key) => key.map(Arc::from),
+            Err(_) => {
+                self.poisoned = true;
+                None
+            }
+        }
+    }
+
+    /// The user key designated by `id`, unless it has been rejected.
+    pub fn get_key(&self, id: u32) -> Option<Arc<str>> {
+        match self.keys.get(id as usize) {
+            Some(Some(key)) => Some(key.clone()),
+            _ => None,
+        }
+    }
+
+    ///
+    /// Run an operation on the contents, unless the storage is
+    /// poisoned. If the operation panics, poison the storage instead
+    /// of propagating the panic.
+    ///
+    pub fn protect<F, R>(&mut self, cb: F) -> Option<R>
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        if self.poisoned {
+            return None;
+        }
+        let contents = &mut *self.contents;
+        match catch_unwind(AssertUnwindSafe(move || cb(contents))) {
+            Ok(result) => Some(result),
+            Err(_) => {
+                self.poisoned = true;
+                None
+            }
+        }
+    }
+}
+
 ///
 /// A subset of data to serialize.
 ///
+/// Plain and keyed histograms share a single namespace, so subsets
+/// other than `AllPlain` and `AllKeyed` may select both kinds of
+/// histograms, serialized side by side in the same object.
+///
+#[derive(Clone, PartialEq, Debug)]
 pub enum Subset {
     /// Serialize all plain histograms.
     AllPlain,
 
     /// Serialize all keyed histograms.
     AllKeyed,
+
+    /// Serialize the histograms with these exact names.
+    Names(Vec<String>),
+
+    /// Serialize the histograms whose name starts with a prefix, e.g.
+    /// `"net."`.
+    Prefix(String),
+
+    /// Serialize the histograms whose name matches a pattern, in which
+    /// `*` matches any sequence of characters and `?` matches any
+    /// single character, e.g. `"net.*.errors"`.
+    Glob(String),
+
+    /// Serialize the histograms selected by any of these subsets.
+    Union(Vec<Subset>),
+}
+
+impl Subset {
+    ///
+    /// Determine whether the subset contains a histogram.
+    ///
+    pub fn matches(&self, name: &str, keyed: bool) -> bool {
+        match *self {
+            Subset::AllPlain => !keyed,
+            Subset::AllKeyed => keyed,
+            Subset::Names(ref names) => names.iter().any(|n| n == name),
+            Subset::Prefix(ref prefix) => name.starts_with(prefix.as_str()),
+            Subset::Glob(ref pattern) => glob_matches(pattern, name),
+            Subset::Union(ref subsets) => subsets.iter().any(|s| s.matches(name, keyed)),
+        }
+    }
+}
+
+///
+/// Determine whether `name` matches `pattern`, in which `*` matches
+/// any sequence of characters and `?` matches any single character.
+///
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Classic backtracking on the latest `*`, linear in practice.
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // Let the latest `*` absorb one more character.
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+///
+/// The kind of a histogram, along with its parameters, as reported by
+/// `Service::list`.
+///
+#[derive(Clone, PartialEq, Debug)]
+pub enum Kind {
+    Flag,
+    Boolean,
+    Linear {
+        min: u32,
+        max: u32,
+        buckets: usize,
+    },
+    Count,
+    Enum,
+    LabeledCount {
+        labels: &'static [&'static str],
+    },
+    Stats,
+    DistinctCount {
+        precision: u8,
+    },
+    SlowestN {
+        n: usize,
+    },
+    WindowedCount {
+        interval: Duration,
+        intervals: usize,
+    },
+    WindowedLinear {
+        min: u32,
+        max: u32,
+        buckets: usize,
+        interval: Duration,
+        intervals: usize,
+    },
+    PrivateFlag {
+        epsilon: f64,
+    },
+    PrivateEnum {
+        variants: u32,
+        epsilon: f64,
+    },
+    KeyedFlag,
+    KeyedBoolean,
+    KeyedPrivateFlag {
+        bits: u32,
+        hashes: u32,
+        epsilon: f64,
+    },
+    KeyedLinear {
+        min: u32,
+        max: u32,
+        buckets: usize,
+    },
+    KeyedCount,
+    KeyedEnum,
+    KeyedStats,
+    KeyedTopN {
+        n: usize,
+        capacity: usize,
+    },
+    KeyedCount2 {
+        max_first_keys: Option<usize>,
+        max_second_keys: Option<usize>,
+    },
+    KeyedLinear2 {
+        min: u32,
+        max: u32,
+        buckets: usize,
+        max_first_keys: Option<usize>,
+        max_second_keys: Option<usize>,
+    },
+}
+
+///
+/// Information on a registered histogram, as reported by `Service::list`.
+///
+#[derive(Clone, PartialEq, Debug)]
+pub struct HistogramInfo {
+    /// The name of the histogram.
+    pub name: String,
+
+    /// The kind of the histogram and its parameters.
+    pub kind: Kind,
+
+    /// For a keyed histogram, the number of keys currently stored.
+    /// `None` for a plain histogram.
+    pub keys: Option<usize>,
+
+    /// The category of data collected by the histogram, see
+    /// `Definition::with_category`.
+    pub category: Category,
+
+    /// `true` if the histogram has expired, see `Definition::expires`.
+    pub expired: bool,
+
+    /// `true` if the histogram has been disabled, see `Service::set_enabled`.
+    pub disabled: bool,
+
+    /// `true` if the histogram has stopped recording because an
+    /// operation on its storage has failed.
+    pub poisoned: bool,
+
+    /// How the histogram is sampled, if at all, see `Definition::with_sampling`.
+    pub sampling: Option<Sampling>,
+
+    /// `true` if the histogram is sampled per client and this client
+    /// has not been selected for this session.
+    pub sampled_out: bool,
+}
+
+///
+/// How histograms registered through scopes (see `Service::scope`) are
+/// laid out when serialized.
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Layout {
+    /// A single object, with one field per histogram, named after the
+    /// full name of the histogram, e.g. `{"network.requests": ...}`.
+    Flat,
+
+    /// One object per namespace, e.g. `{"network": {"requests": ...}}`.
+    ///
+    /// A histogram may not have the same name as a namespace.
+    Nested,
+}
+
+///
+/// A category of collected data, for the purpose of user consent, see
+/// `Definition::with_category` and `Service::set_consent`.
+///
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Category {
+    /// Data about the behavior of the application itself, e.g.
+    /// performance or errors. This is the default category.
+    Technical,
+
+    /// Data about the interactions of the user with the application,
+    /// e.g. which features are used.
+    Interaction,
+
+    /// Data about the activity of the user on the web, e.g. visited
+    /// domains.
+    WebActivity,
+
+    /// Data only meant to be collected on prerelease versions of the
+    /// application, e.g. for diagnostics.
+    Prerelease,
+}
+
+impl Category {
+    /// All the categories.
+    pub const ALL: [Category; 4] = [
+        Category::Technical,
+        Category::Interaction,
+        Category::WebActivity,
+        Category::Prerelease,
+    ];
+}
+
+///
+/// What a keyed histogram with declared keys does with other keys,
+/// see `Definition::with_keys`.
+///
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UnknownKeys {
+    /// Reject other keys. They are counted as rejected keys, as with
+    /// `KeyPolicy::Allow`.
+    Reject,
+
+    /// Record values with other keys under key `__other__`.
+    Other,
+}
+
+/// The key under which `UnknownKeys::Other` records unknown keys.
+pub const OTHER_KEY: &str = "__other__";
+
+///
+/// How a histogram is sampled, see `Definition::with_sampling`.
+///
+/// In either case, the rate is a number in `(0, 1]`, serialized along
+/// with the data (see `Service::to_json`), so that the server may scale
+/// the counts back up.
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Sampling {
+    /// Each record is kept with probability `rate`.
+    PerRecord(f64),
+
+    /// The histogram is recorded with probability `rate`, decided once
+    /// per session, upon registration. Otherwise, it records nothing
+    /// and is not serialized.
+    PerClient(f64),
+}
+
+impl Sampling {
+    /// The probability of keeping a record.
+    pub fn rate(&self) -> f64 {
+        match *self {
+            Sampling::PerRecord(rate) | Sampling::PerClient(rate) => rate,
+        }
+    }
+}
+
+///
+/// What to do when recording a value while the queue of the telemetry
+/// thread is full, see `ServiceBuilder::channel_capacity`.
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OverflowPolicy {
+    /// Block the recording thread until there is room in the queue.
+    Block,
+
+    /// Drop the value.
+    Drop,
 }
 
 ///
 /// A subformat of Json to use for serialization.
 ///
+#[derive(Clone, Copy)]
 pub enum SerializationFormat {
     ///
     /// Simple Json:
@@ -36,12 +435,242 @@ pub enum SerializationFormat {
     /// - `KeyedFlag` are represented as an array;
     /// - `Linear` are represented as an array of numbers, one cell per bucket;
     /// - `KeyedLinear` are represented as an object, one field per histogram,
-    ///    with name = key, value = array of numbers as for `Linear`;
+    ///   with name = key, value = array of numbers as for `Linear`;
+    /// - `KeyedCount2` and `KeyedLinear2` are represented as nested
+    ///   objects, with name = first key, value = an object as for
+    ///   `KeyedCount` and `KeyedLinear`, respectively;
     /// - ...
     ///
     SimpleJson,
 }
 
+///
+/// The definition of a histogram: its name and optional metadata.
+///
+/// Histogram constructors accept either a `String`, which is used as
+/// name, or a `Definition`:
+///
+/// ````
+/// # use telemetry::*;
+/// let service = Service::new(true);
+/// let crashes = plain::Count::new(&service, Definition::new("CRASHES").expires("2.0"));
+/// ````
+///
+pub struct Definition {
+    name: String,
+    expires: Option<Version>,
+    category: Category,
+    sampling: Option<Sampling>,
+    key_policies: Vec<KeyPolicy>,
+    keys: Option<(Vec<String>, UnknownKeys)>,
+}
+
+impl Definition {
+    ///
+    /// Create a definition with a given name and no metadata.
+    ///
+    /// The name is used as key when processing and exporting the
+    /// data. Each name must be unique to the `Service`.
+    ///
+    pub fn new<S: Into<String>>(name: S) -> Definition {
+        Definition {
+            name: name.into(),
+            expires: None,
+            category: Category::Technical,
+            sampling: None,
+            key_policies: Vec::new(),
+            keys: None,
+        }
+    }
+
+    ///
+    /// Mark the histogram as expiring with a given version of the
+    /// application, e.g. `"2.0"`.
+    ///
+    /// If the service is configured with an application version
+    /// greater than or equal to `version` (see
+    /// `ServiceBuilder::app_version`), the histogram ignores all
+    /// records and is not serialized.
+    ///
+    pub fn expires(mut self, version: &str) -> Definition {
+        self.expires = Some(Version::parse(version));
+        self
+    }
+
+    ///
+    /// Set the category of the data collected by the histogram.
+    /// Defaults to `Category::Technical`.
+    ///
+    /// The histogram ignores all records and is not serialized unless
+    /// the user has consented to this category (see
+    /// `Service::set_consent`).
+    ///
+    pub fn with_category(mut self, category: Category) -> Definition {
+        self.category = category;
+        self
+    }
+
+    ///
+    /// Sample the records of the histogram. By default, all records
+    /// are kept.
+    ///
+    /// The random number generator may be seeded with
+    /// `ServiceBuilder::seed`.
+    ///
+    /// # Panics
+    ///
+    /// If the rate is not in `(0, 1]`.
+    ///
+    pub fn with_sampling(mut self, sampling: Sampling) -> Definition {
+        let rate = sampling.rate();
+        assert!(
+            rate > 0. && rate <= 1.,
+            "Sampling rate must be in (0, 1], got {}",
+            rate
+        );
+        self.sampling = Some(sampling);
+        self
+    }
+
+    ///
+    /// Add a policy applied to the keys of a keyed histogram, after
+    /// those previously added. Ignored by plain histograms.
+    ///
+    /// ````
+    /// # use telemetry::*;
+    /// let service = Service::new(true);
+    /// let downloads: keyed::KeyedCount<String> = keyed::KeyedCount::new(
+    ///     &service,
+    ///     Definition::new("DOWNLOADS")
+    ///         .key_policy(KeyPolicy::ScrubUrls)
+    ///         .key_policy(KeyPolicy::MaxLength(100)),
+    /// );
+    /// ````
+    ///
+    pub fn key_policy(mut self, policy: KeyPolicy) -> Definition {
+        self.key_policies.push(policy);
+        self
+    }
+
+    ///
+    /// Declare the keys of a keyed histogram. Supported by `KeyedFlag`,
+    /// `KeyedBoolean`, `KeyedCount`, `KeyedEnum`, `KeyedLinear` and
+    /// `KeyedStats`, ignored by other histograms.
+    ///
+    /// The values of all declared keys are preallocated as a dense
+    /// array, and serialized even if nothing has been recorded for
+    /// them. Other keys are handled as specified by `unknown`.
+    ///
+    /// Declared keys are checked after the key policies have been
+    /// applied (see `Definition::key_policy`).
+    ///
+    /// # Panics
+    ///
+    /// If `keys` contains `"__other__"`.
+    ///
+    pub fn with_keys<S: ToString>(mut self, keys: &[S], unknown: UnknownKeys) -> Definition {
+        let keys: Vec<String> = keys.iter().map(ToString::to_string).collect();
+        assert!(
+            !keys.iter().any(|key| key == OTHER_KEY),
+            "Key {} is reserved",
+            OTHER_KEY
+        );
+        self.keys = Some((keys, unknown));
+        self
+    }
+
+    /// The name of the histogram.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The category of the data collected by the histogram.
+    pub fn category(&self) -> Category {
+        self.category
+    }
+
+    /// How the histogram is sampled, if at all.
+    pub fn sampling(&self) -> Option<Sampling> {
+        self.sampling
+    }
+
+    /// The declared keys of a keyed histogram, if any.
+    pub fn keys(&self) -> Option<(&[String], UnknownKeys)> {
+        self.keys
+            .as_ref()
+            .map(|&(ref keys, unknown)| (keys.as_slice(), unknown))
+    }
+
+    /// `true` if the histogram has key policies.
+    #[doc(hidden)]
+    pub fn has_key_policies(&self) -> bool {
+        !self.key_policies.is_empty()
+    }
+
+    /// Remove the key policies, to hand them over to the telemetry thread.
+    ///
+    /// If unknown keys are rejected, this includes a last policy
+    /// rejecting them, so that they are counted.
+    #[doc(hidden)]
+    pub fn take_key_policies(&mut self) -> Vec<KeyPolicy> {
+        let mut policies = std::mem::take(&mut self.key_policies);
+        if let Some((ref keys, UnknownKeys::Reject)) = self.keys {
+            policies.push(KeyPolicy::Allow(keys.clone()));
+        }
+        policies
+    }
+
+    /// `true` if the histogram has expired in application version `version`.
+    pub fn is_expired(&self, version: Option<&Version>) -> bool {
+        match (version, &self.expires) {
+            (Some(version), Some(expires)) => version >= expires,
+            _ => false,
+        }
+    }
+}
+
+impl From<String> for Definition {
+    fn from(name: String) -> Definition {
+        Definition::new(name)
+    }
+}
+
+impl<'a> From<&'a str> for Definition {
+    fn from(name: &'a str) -> Definition {
+        Definition::new(name)
+    }
+}
+
+///
+/// A version number, such as `1.2.3`.
+///
+/// Components are compared numerically. Anything after the leading
+/// digits of a component is ignored, so `2.0b1` is considered equal
+/// to `2.0`.
+///
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+pub struct Version(Vec<u32>);
+
+impl Version {
+    pub fn parse(source: &str) -> Version {
+        let mut components: Vec<u32> = source
+            .split('.')
+            .map(|component| {
+                let digits: String = component
+                    .chars()
+                    .take_while(|c| c.is_ascii_digit())
+                    .collect();
+                digits.parse().unwrap_or(0)
+            })
+            .collect();
+        // Make sure that `2` and `2.0` compare equal.
+        while components.last() == Some(&0) {
+            components.pop();
+        }
+        Version(components)
+    }
+}
+
 ///
 /// A value that can be represented as a u32.
 ///
@@ -83,16 +712,23 @@ pub struct LinearBuckets {
 impl LinearBuckets {
     pub fn new(min: u32, max: u32, buckets: usize) -> LinearBuckets {
         assert!(min < max);
-        assert!(buckets > 0);
-        assert!(buckets < (max - min) as usize);
+        assert!(buckets > 0 && buckets as u64 <= (max - min) as u64 + 1);
         LinearBuckets { min, max, buckets }
     }
 
+    pub fn min(&self) -> u32 {
+        self.min
+    }
+
+    pub fn max(&self) -> u32 {
+        self.max
+    }
+
     pub fn get_bucket(&self, value: u32) -> usize {
         if value <= self.min {
             0
         } else if value >= self.max {
-            self.buckets - 1 as usize
+            self.buckets - 1
         } else {
             let num = value as f32 - self.min as f32;
             let den = self.max as f32 - self.min as f32;
@@ -110,3 +746,428 @@ where
     vec.resize(size, value);
     vec
 }
+
+///
+/// A hasher producing the same values across runs and processes.
+///
+/// `std::collections::hash_map::DefaultHasher` makes no promise of
+/// stability, which makes it unsuitable for data that is eventually
+/// compared or merged across clients. This is FNV-1a, followed by a
+/// finalization step to spread the bits. Integers are hashed as
+/// little-endian bytes, and `usize`/`isize` as 64 bits, so that values
+/// do not depend on the platform either.
+///
+pub struct StableHasher {
+    state: u64,
+}
+
+impl StableHasher {
+    pub fn new() -> StableHasher {
+        StableHasher {
+            state: 0xcbf2_9ce4_8422_2325,
+        }
+    }
+}
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        StableHasher::new()
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= u64::from(*byte);
+            self.state = self.state.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes())
+    }
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes())
+    }
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes())
+    }
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes())
+    }
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64)
+    }
+    fn write_i16(&mut self, i: i16) {
+        self.write_u16(i as u16)
+    }
+    fn write_i32(&mut self, i: i32) {
+        self.write_u32(i as u32)
+    }
+    fn write_i64(&mut self, i: i64) {
+        self.write_u64(i as u64)
+    }
+    fn write_i128(&mut self, i: i128) {
+        self.write_u128(i as u128)
+    }
+    fn write_isize(&mut self, i: isize) {
+        self.write_u64(i as i64 as u64)
+    }
+    fn finish(&self) -> u64 {
+        // Finalizer of MurmurHash3, as FNV alone leaves the high bits
+        // poorly mixed for short inputs.
+        let mut h = self.state;
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        h ^= h >> 33;
+        h
+    }
+}
+
+/// Hash a value to 32 bits with a `StableHasher`.
+pub fn stable_hash_u32<T: ?Sized + Hash>(value: &T) -> u32 {
+    let mut hasher = StableHasher::new();
+    value.hash(&mut hasher);
+    (hasher.finish() >> 32) as u32
+}
+
+///
+/// A fast, seedable, non-cryptographic random number generator
+/// (xorshift64*), used for sampling.
+///
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng {
+            state: rng_seed(seed),
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = rng_step(self.state);
+        rng_output(self.state)
+    }
+
+    /// A uniform number in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        // Keep the 53 bits of precision of `f64`.
+        (self.next_u64() >> 11) as f64 / 9_007_199_254_740_992.
+    }
+}
+
+///
+/// Decides whether to keep each record of a histogram sampled with
+/// `Sampling::PerRecord`.
+///
+/// Shared by all clones of a back-end, hence the atomic state. Races
+/// between threads may cause two records to draw the same number,
+/// which does not matter for sampling.
+///
+pub struct Sampler {
+    /// Keep a record iff the next random number is below this.
+    threshold: u64,
+
+    /// The state of the xorshift64* generator.
+    state: AtomicU64,
+}
+
+impl Sampler {
+    /// Create a sampler keeping records with probability `rate`, in `(0, 1)`.
+    pub fn new(rate: f64, seed: u64) -> Sampler {
+        Sampler {
+            // 2^64, as a float.
+            threshold: (rate * 18_446_744_073_709_551_616.) as u64,
+            state: AtomicU64::new(rng_seed(seed)),
+        }
+    }
+
+    /// `true` if the next record should be kept.
+    pub fn sample(&self) -> bool {
+        let state = rng_step(self.state.load(Ordering::Relaxed));
+        self.state.store(state, Ordering::Relaxed);
+        rng_output(state) < self.threshold
+    }
+}
+
+/// Spread the bits of a seed (splitmix64), as xorshift requires a
+/// non-zero state and behaves poorly with sparse states.
+fn rng_seed(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    if z == 0 {
+        0x9e37_79b9_7f4a_7c15
+    } else {
+        z
+    }
+}
+
+fn rng_step(mut x: u64) -> u64 {
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    x
+}
+
+fn rng_output(state: u64) -> u64 {
+    state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+//
+// Randomized response, shared by the privacy-preserving histograms and
+// by the aggregation helpers, which need to agree on the encoding.
+//
+
+/// Check that `epsilon` is usable as a privacy parameter.
+pub fn check_epsilon(epsilon: f64) {
+    assert!(
+        epsilon > 0. && epsilon.is_finite(),
+        "Epsilon must be positive and finite, got {}",
+        epsilon
+    );
+}
+
+///
+/// The probability with which randomized response over `k` values
+/// keeps the true value. Otherwise, it reports a value picked
+/// uniformly among all `k` values, so that the true value is
+/// reported with probability `e^ε / (e^ε + k - 1)`, as required by
+/// `ε`-local differential privacy.
+///
+pub fn randomized_response_keep(k: u32, epsilon: f64) -> f64 {
+    let exp = epsilon.exp();
+    (exp - 1.) / (exp + k as f64 - 1.)
+}
+
+/// Encode `value`, in `0..k`, with `ε`-randomized response.
+pub fn randomized_response(rng: &mut Rng, value: u32, k: u32, epsilon: f64) -> u32 {
+    if rng.next_f64() < randomized_response_keep(k, epsilon) {
+        value
+    } else {
+        (rng.next_u64() % u64::from(k)) as u32
+    }
+}
+
+/// The bits of a Bloom filter of size `bits` set by `key`.
+pub fn bloom_bits(key: &str, hashes: u32, bits: u32) -> Vec<u32> {
+    (0..hashes)
+        .map(|i| {
+            let mut hasher = StableHasher::new();
+            (i, key).hash(&mut hasher);
+            (hasher.finish() % u64::from(bits)) as u32
+        })
+        .collect()
+}
+
+//
+// Register operations shared by `DistinctCount` and by the aggregation
+// helpers, which need to agree on the sketch layout.
+//
+pub const HYPERLOGLOG_MIN_PRECISION: u8 = 4;
+pub const HYPERLOGLOG_MAX_PRECISION: u8 = 16;
+
+/// Update a set of HyperLogLog registers with a 32-bit hash.
+pub fn hyperloglog_insert(registers: &mut [u8], precision: u8, hash: u32) {
+    let index = (hash >> (32 - precision)) as usize;
+    let rest = hash << precision;
+    let rank = (rest.leading_zeros() + 1).min(32 - precision as u32 + 1) as u8;
+    if registers[index] < rank {
+        registers[index] = rank;
+    }
+}
+
+/// Estimate the cardinality of a set from its HyperLogLog registers.
+pub fn hyperloglog_estimate(registers: &[u8]) -> f64 {
+    let m = registers.len() as f64;
+    let alpha = match registers.len() {
+        16 => 0.673,
+        32 => 0.697,
+        64 => 0.709,
+        _ => 0.7213 / (1.0 + 1.079 / m),
+    };
+    let mut sum = 0.0;
+    let mut zeros = 0;
+    for &register in registers {
+        sum += 2f64.powi(-i32::from(register));
+        if register == 0 {
+            zeros += 1;
+        }
+    }
+    let raw = alpha * m * m / sum;
+    let two_32 = 4_294_967_296.0;
+    if raw <= 2.5 * m && zeros > 0 {
+        // Small range correction: linear counting.
+        m * (m / zeros as f64).ln()
+    } else if raw > two_32 / 30.0 {
+        // Large range correction, as we only use 32 bits of hash.
+        -two_32 * (1.0 - raw / two_32).ln()
+    } else {
+        raw
+    }
+}
+
+///
+/// Summary statistics of the values recorded in a `plain::Stats` or
+/// `keyed::KeyedStats` histogram.
+///
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Summary {
+    /// The number of values.
+    pub count: u64,
+
+    /// The sum of all values.
+    pub sum: u64,
+
+    /// The smallest value, `None` if no value has been recorded.
+    pub min: Option<u32>,
+
+    /// The largest value, `None` if no value has been recorded.
+    pub max: Option<u32>,
+
+    /// The mean of all values, 0 if no value has been recorded.
+    pub mean: f64,
+
+    /// The (population) variance of all values, 0 if no value has
+    /// been recorded.
+    pub variance: f64,
+}
+
+///
+/// Running statistics on a series of values, with the variance
+/// computed by Welford's algorithm, which remains numerically stable
+/// even when the variance is small compared to the mean.
+///
+#[derive(Clone, Default)]
+pub struct RunningStats {
+    count: u64,
+    sum: u64,
+    min: u32,
+    max: u32,
+    mean: f64,
+
+    /// The sum of squared differences to the mean.
+    m2: f64,
+}
+
+impl RunningStats {
+    /// Add the same value `times` times. Values that would overflow
+    /// the count or the sum are ignored.
+    pub fn record_n(&mut self, value: u32, times: u32) {
+        let _ = self.merge(&RunningStats {
+            count: u64::from(times),
+            sum: u64::from(value) * u64::from(times),
+            min: value,
+            max: value,
+            mean: f64::from(value),
+            m2: 0.,
+        });
+    }
+
+    /// Add all the values of another series (Chan et al.'s parallel
+    /// variant of Welford's algorithm).
+    ///
+    /// Returns `None`, leaving `self` unchanged, if the count or the
+    /// sum would overflow.
+    pub fn merge(&mut self, other: &RunningStats) -> Option<()> {
+        if other.count == 0 {
+            return Some(());
+        }
+        if self.count == 0 {
+            *self = other.clone();
+            return Some(());
+        }
+        let count = self.count.checked_add(other.count)?;
+        let sum = self.sum.checked_add(other.sum)?;
+        let delta = other.mean - self.mean;
+        let (n1, n2, n) = (self.count as f64, other.count as f64, count as f64);
+        self.mean += delta * n2 / n;
+        self.m2 += other.m2 + delta * delta * n1 * n2 / n;
+        self.count = count;
+        self.sum = sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        Some(())
+    }
+
+    pub fn summary(&self) -> Summary {
+        let (min, max, variance) = if self.count == 0 {
+            (None, None, 0.)
+        } else {
+            (Some(self.min), Some(self.max), self.m2 / self.count as f64)
+        };
+        Summary {
+            count: self.count,
+            sum: self.sum,
+            min,
+            max,
+            mean: self.mean,
+            variance,
+        }
+    }
+
+    /// Serialize as an object `{count, sum, min, max, mean, variance}`,
+    /// with `min` and `max` `null` if no value has been recorded.
+    pub fn to_json(&self) -> Json {
+        let summary = self.summary();
+        let bound = |bound: Option<u32>| bound.map_or(Json::Null, |x| Json::I64(i64::from(x)));
+        let mut tree = BTreeMap::new();
+        tree.insert("count".to_string(), Json::U64(summary.count));
+        tree.insert("sum".to_string(), Json::U64(summary.sum));
+        tree.insert("min".to_string(), bound(summary.min));
+        tree.insert("max".to_string(), bound(summary.max));
+        tree.insert("mean".to_string(), Json::F64(summary.mean));
+        tree.insert("variance".to_string(), Json::F64(summary.variance));
+        Json::Object(tree)
+    }
+
+    /// Read the output of `to_json`, or `None` if it is malformed,
+    /// including if `sum` may not be the sum of `count` values between
+    /// `min` and `max`.
+    pub fn from_json(json: &Json) -> Option<RunningStats> {
+        let count = json.find("count").and_then(Json::as_u64)?;
+        let sum = json.find("sum").and_then(Json::as_u64)?;
+        let mean = json.find("mean").and_then(Json::as_f64)?;
+        let variance = json.find("variance").and_then(Json::as_f64)?;
+        if !mean.is_finite() || !variance.is_finite() || variance < 0. {
+            return None;
+        }
+        if count == 0 {
+            return Some(RunningStats::default());
+        }
+        let bound = |field: &str| {
+            let bound = json.find(field).and_then(Json::as_u64)?;
+            if bound > u64::from(u32::MAX) {
+                None
+            } else {
+                Some(bound as u32)
+            }
+        };
+        let (min, max) = (bound("min")?, bound("max")?);
+        if min > max {
+            return None;
+        }
+        // If `count * min` overflows, so would have `sum`.
+        let lowest = count.checked_mul(u64::from(min))?;
+        let highest = count.saturating_mul(u64::from(max));
+        if sum < lowest || sum > highest {
+            return None;
+        }
+        let m2 = variance * count as f64;
+        if !m2.is_finite() {
+            return None;
+        }
+        Some(RunningStats {
+            count,
+            sum,
+            min,
+            max,
+            mean,
+            m2,
+        })
+    }
+}
@@ -9,12 +9,23 @@
 
 use rustc_serialize::json::Json;
 
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::hash::Hash;
 use std::marker::PhantomData;
 use std::mem::size_of;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
 
+use clock::Clock;
 use indexing::*;
-use misc::{vec_with_size, Flatten, LinearBuckets, SerializationFormat};
+use misc::{
+    check_epsilon, hyperloglog_estimate, hyperloglog_insert, randomized_response, stable_hash_u32,
+    vec_with_size, Definition, Flatten, Kind, LinearBuckets, Rng, RunningStats,
+    SerializationFormat, Summary, HYPERLOGLOG_MAX_PRECISION, HYPERLOGLOG_MIN_PRECISION, OTHER_KEY,
+};
 use service::{PrivateAccess, Service};
 use task::{BackEnd, Op, PlainRawStorage};
 
@@ -26,7 +37,7 @@ use task::{BackEnd, Op, PlainRawStorage};
 /// cloned as needed for concurrent use.
 ///
 /// # Performance
-////
+///
 /// Cloning a histogram is relatively cheap, both in terms of memory
 /// and in terms of speed (most histograms weigh ~40bytes on a x86-64
 /// architecture).
@@ -64,7 +75,27 @@ impl BackEnd<Plain> {
     /// Instruct the Telemetry Task to record a value in an
     /// already registered histogram.
     fn raw_record(&self, k: &Key<Plain>, value: u32) {
-        self.sender.send(Op::RecordPlain(k.index, value)).unwrap();
+        match self.batching {
+            Some(ref batching) => batching.record_plain(k.index, value),
+            None => {
+                // If the telemetry thread is gone, there is nothing to record.
+                let _ = self.sender.send_record(Op::RecordPlain(k.index, value));
+            }
+        }
+    }
+
+    /// Instruct the Telemetry Task to record a value in a slot of an
+    /// already registered histogram.
+    fn raw_record_at(&self, k: &Key<Plain>, slot: u32, value: u32) {
+        match self.batching {
+            Some(ref batching) => batching.record_plain_at(k.index, slot, value),
+            None => {
+                // If the telemetry thread is gone, there is nothing to record.
+                let _ = self
+                    .sender
+                    .send_record(Op::RecordPlainAt(k.index, slot, value));
+            }
+        }
     }
 
     /// Instruct the Telemetry Task to record the result of a callback
@@ -74,9 +105,61 @@ impl BackEnd<Plain> {
         F: FnOnce() -> Option<T>,
         T: Flatten,
     {
-        if let Some(k) = self.get_key() {
+        if let Some(k) = self.get_sampled_key() {
             if let Some(v) = cb() {
-                self.raw_record(&k, v.as_u32());
+                self.raw_record(k, v.as_u32());
+                true
+            } else {
+                false
+            }
+        } else {
+            false
+        }
+    }
+
+    /// Run a callback on the storage of this histogram, on the
+    /// Telemetry Task, and wait for its result.
+    ///
+    /// Returns `None` if the Telemetry Task is not running anymore.
+    fn query<S, F, R>(&self, cb: F) -> Option<R>
+    where
+        S: PlainRawStorage + 'static,
+        F: FnOnce(&S) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        // Make sure that the records of this thread are visible.
+        if let Some(ref batching) = self.batching {
+            batching.flush_current();
+        }
+        let (sender, receiver) = channel();
+        let callback = Box::new(move |storage: &dyn PlainRawStorage| {
+            // The storage is created alongside the histogram, so its
+            // type cannot mismatch.
+            let storage = storage.as_any().downcast_ref::<S>().unwrap();
+            let _ = sender.send(cb(storage));
+        });
+        if self
+            .sender
+            .send(Op::QueryPlain(self.raw_key().index, callback))
+            .is_err()
+        {
+            return None;
+        }
+        receiver.recv().ok()
+    }
+
+    /// Instruct the Telemetry Task to record the result of a callback,
+    /// along with a label, in an already registered histogram.
+    fn raw_record_labeled_cb<F, T>(&self, cb: F) -> bool
+    where
+        F: FnOnce() -> Option<(T, String)>,
+        T: Flatten,
+    {
+        if let Some(k) = self.get_sampled_key() {
+            if let Some((v, label)) = cb() {
+                let _ = self
+                    .sender
+                    .send_record(Op::RecordPlainLabeled(k.index, v.as_u32(), label));
                 true
             } else {
                 false
@@ -161,6 +244,15 @@ impl PlainRawStorage for FlagStorage {
     fn store(&mut self, _: u32) {
         self.encountered = true;
     }
+    fn store_n(&mut self, value: u32, _: u32) {
+        self.store(value)
+    }
+    fn reset(&mut self) {
+        self.encountered = false;
+    }
+    fn kind(&self) -> Kind {
+        Kind::Flag
+    }
     fn to_json(&self, format: &SerializationFormat) -> Json {
         match format {
             SerializationFormat::SimpleJson => Json::I64(if self.encountered { 1 } else { 0 }),
@@ -189,20 +281,30 @@ impl Flag {
     /// Create a new Flag histogram with a given name.
     ///
     /// Argument `name` is used as key when processing and exporting
-    /// the data. Each `name` must be unique to the `Service`.
-    ///
-    /// # Panics
+    /// the data. Each `name` must be unique to the `Service`: a
+    /// histogram registered with a name that is already used is
+    /// rejected and records nothing, see `Service`.
     ///
-    /// If `name` is already used by another histogram in `service`.
-    ///
-    pub fn new(service: &Service, name: String) -> Flag {
+    pub fn new(service: &Service, name: impl Into<Definition>) -> Flag {
         let storage = Box::new(FlagStorage { encountered: false });
-        let key = PrivateAccess::register_plain(service, name, storage);
+        let back_end = PrivateAccess::register_plain(service, name.into(), storage);
         Flag {
-            back_end: BackEnd::new(service, key),
+            back_end,
             cache: AtomicBool::new(false),
         }
     }
+
+    ///
+    /// Get whether the flag has been set.
+    ///
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
+    ///
+    pub fn snapshot(&self) -> Option<bool> {
+        self.back_end
+            .query(|storage: &FlagStorage| storage.encountered)
+    }
 }
 
 impl Clone for Flag {
@@ -216,6 +318,91 @@ impl Clone for Flag {
     }
 }
 
+///
+///
+/// Boolean histograms.
+///
+/// Unlike a `Flag`, which only records whether `record()` has ever
+/// been called, a Boolean histogram counts how many times `false` and
+/// `true` have been recorded, e.g. how often a check has failed and
+/// succeeded during a session.
+///
+///
+/// With `SerializationFormat::SimpleJson`, these histograms are
+/// serialized as an array `[false_count, true_count]`.
+///
+#[derive(Clone)]
+pub struct Boolean {
+    back_end: BackEnd<Plain>,
+}
+
+// The storage, owned by the Telemetry Task.
+struct BooleanStorage {
+    /// The number of `false` and `true` records, in this order.
+    values: [u32; 2],
+}
+
+impl PlainRawStorage for BooleanStorage {
+    fn store(&mut self, value: u32) {
+        self.store_n(value, 1)
+    }
+    fn store_n(&mut self, value: u32, times: u32) {
+        let count = &mut self.values[(value != 0) as usize];
+        *count = count.saturating_add(times);
+    }
+    fn reset(&mut self) {
+        self.values = [0, 0];
+    }
+    fn kind(&self) -> Kind {
+        Kind::Boolean
+    }
+    fn to_json(&self, format: &SerializationFormat) -> Json {
+        match format {
+            SerializationFormat::SimpleJson => {
+                Json::Array(self.values.iter().map(|&x| Json::I64(x as i64)).collect())
+            }
+        }
+    }
+}
+
+impl Histogram<bool> for Boolean {
+    fn record_cb<F>(&self, cb: F)
+    where
+        F: FnOnce() -> Option<bool>,
+    {
+        self.back_end.raw_record_cb(cb);
+    }
+}
+
+impl Boolean {
+    ///
+    /// Create a new Boolean histogram with a given name.
+    ///
+    /// Argument `name` is used as key when processing and exporting
+    /// the data. Each `name` must be unique to the `Service`: a
+    /// histogram registered with a name that is already used is
+    /// rejected and records nothing, see `Service`.
+    ///
+    pub fn new(service: &Service, name: impl Into<Definition>) -> Boolean {
+        let storage = Box::new(BooleanStorage { values: [0, 0] });
+        let back_end = PrivateAccess::register_plain(service, name.into(), storage);
+        Boolean { back_end }
+    }
+
+    ///
+    /// Get the number of `false` and `true` values recorded so far,
+    /// in this order.
+    ///
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
+    ///
+    pub fn snapshot(&self) -> Option<[u32; 2]> {
+        self.back_end
+            .query(|storage: &BooleanStorage| storage.values)
+    }
+}
+
 ///
 /// Linear histograms.
 ///
@@ -257,19 +444,23 @@ where
     /// Create a new Linear histogram with a given name.
     ///
     /// - `name` is used as key when processing and exporting
-    /// the data. Each `name` must be unique to the `Service`.
+    ///   the data. Each `name` must be unique to the `Service`.
     ///
     /// - `min` is the minimal value expected to be entered in this
-    /// histogram. Any value lower than `min` is rounded up to `min`.
+    ///   histogram. Any value lower than `min` is rounded up to `min`.
     ///
     /// - `max` is the maximal value expected to be entered in this
-    /// histogram. Any value higher than `max` is rounded up to `max`.
+    ///   histogram. Any value higher than `max` is rounded up to `max`.
     ///
     /// - `buckets` is the number of buckets in this histogram. For
-    /// highest possible precision, use `buckets = max - min + 1`.
-    /// In most cases, however, such precision is not needed, so you
-    /// should use a lower number of buckets.
+    ///   highest possible precision, use `buckets = max - min + 1`.
+    ///   In most cases, however, such precision is not needed, so you
+    ///   should use a lower number of buckets.
+    ///
     ///
+    /// Each `name` must be unique to the `Service`: a histogram
+    /// registered with a name that is already used is rejected and
+    /// records nothing, see `Service`.
     ///
     /// # Performance
     ///
@@ -284,24 +475,40 @@ where
     ///
     /// # Panics
     ///
-    /// If `name` is already used by another histogram in `service`.
-    ///
     /// If `min >= max`.
     ///
-    /// If `buckets < max - min + 1`.
+    /// If `buckets == 0` or `buckets > max - min + 1`.
     ///
-    pub fn new(service: &Service, name: String, min: u32, max: u32, buckets: usize) -> Linear<T> {
+    pub fn new(
+        service: &Service,
+        name: impl Into<Definition>,
+        min: u32,
+        max: u32,
+        buckets: usize,
+    ) -> Linear<T> {
         assert!(size_of::<u32>() <= size_of::<usize>());
         assert!(min < max);
-        assert!(max - min + 1 >= buckets as u32);
+        assert!(buckets > 0 && buckets as u64 <= (max - min) as u64 + 1);
         let shape = LinearBuckets::new(min, max, buckets);
         let storage = Box::new(LinearStorage::new(shape));
-        let key = PrivateAccess::register_plain(service, name, storage);
+        let back_end = PrivateAccess::register_plain(service, name.into(), storage);
         Linear {
             witness: PhantomData,
-            back_end: BackEnd::new(service, key),
+            back_end,
         }
     }
+
+    ///
+    /// Get the number of values in each bucket.
+    ///
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
+    ///
+    pub fn snapshot(&self) -> Option<Vec<u32>> {
+        self.back_end
+            .query(|storage: &LinearStorage| storage.values.clone())
+    }
 }
 
 struct LinearStorage {
@@ -318,8 +525,21 @@ impl LinearStorage {
 
 impl PlainRawStorage for LinearStorage {
     fn store(&mut self, value: u32) {
+        self.store_n(value, 1)
+    }
+    fn store_n(&mut self, value: u32, times: u32) {
         let index = self.shape.get_bucket(value);
-        self.values[index] += 1;
+        self.values[index] = self.values[index].saturating_add(times);
+    }
+    fn reset(&mut self) {
+        self.values = vec_with_size(self.shape.buckets, 0);
+    }
+    fn kind(&self) -> Kind {
+        Kind::Linear {
+            min: self.shape.min(),
+            max: self.shape.max(),
+            buckets: self.shape.buckets,
+        }
     }
     fn to_json(&self, _: &SerializationFormat) -> Json {
         let json = Json::Array(self.values.iter().map(|&x| Json::I64(x as i64)).collect());
@@ -346,7 +566,7 @@ where
 /// A Count histogram simply accumulates the numbers passed with
 /// `record()`. Count histograms are useful, for instance, to know how
 /// many times a feature has been used, or how many times an error has
-/// been triggered.
+/// been triggered. The count saturates at `u32::MAX`.
 ///
 ///
 /// With `SerializationFormat::SimpleJson`, these histograms are
@@ -364,7 +584,16 @@ struct CountStorage {
 
 impl PlainRawStorage for CountStorage {
     fn store(&mut self, value: u32) {
-        self.value += value;
+        self.store_n(value, 1)
+    }
+    fn store_n(&mut self, value: u32, times: u32) {
+        self.value = self.value.saturating_add(value.saturating_mul(times));
+    }
+    fn reset(&mut self) {
+        self.value = 0;
+    }
+    fn kind(&self) -> Kind {
+        Kind::Count
     }
     fn to_json(&self, format: &SerializationFormat) -> Json {
         match format {
@@ -387,18 +616,25 @@ impl Count {
     /// Create a new Count histogram with a given name.
     ///
     /// Argument `name` is used as key when processing and exporting
-    /// the data. Each `name` must be unique to the `Service`.
+    /// the data. Each `name` must be unique to the `Service`: a
+    /// histogram registered with a name that is already used is
+    /// rejected and records nothing, see `Service`.
     ///
-    /// # Panics
+    pub fn new(service: &Service, name: impl Into<Definition>) -> Count {
+        let storage = Box::new(CountStorage { value: 0 });
+        let back_end = PrivateAccess::register_plain(service, name.into(), storage);
+        Count { back_end }
+    }
+
     ///
-    /// If `name` is already used by another histogram in `service`.
+    /// Get the sum of all values recorded so far.
     ///
-    pub fn new(service: &Service, name: String) -> Count {
-        let storage = Box::new(CountStorage { value: 0 });
-        let key = PrivateAccess::register_plain(service, name, storage);
-        Count {
-            back_end: BackEnd::new(service, key),
-        }
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
+    ///
+    pub fn snapshot(&self) -> Option<u32> {
+        self.back_end.query(|storage: &CountStorage| storage.value)
     }
 }
 
@@ -430,8 +666,20 @@ struct EnumStorage {
 
 impl PlainRawStorage for EnumStorage {
     fn store(&mut self, value: u32) {
-        self.values.resize(value as usize + 1, 0);
-        self.values[value as usize] += 1;
+        self.store_n(value, 1)
+    }
+    fn store_n(&mut self, value: u32, times: u32) {
+        if self.values.len() <= value as usize {
+            self.values.resize(value as usize + 1, 0);
+        }
+        let count = &mut self.values[value as usize];
+        *count = count.saturating_add(times);
+    }
+    fn reset(&mut self) {
+        self.values.clear();
+    }
+    fn kind(&self) -> Kind {
+        Kind::Enum
     }
     fn to_json(&self, format: &SerializationFormat) -> Json {
         match format {
@@ -462,20 +710,32 @@ where
     /// Create a new Enum histogram with a given name.
     ///
     /// Argument `name` is used as key when processing and exporting
-    /// the data. Each `name` must be unique to the `Service`.
-    ///
-    /// # Panics
+    /// the data. Each `name` must be unique to the `Service`: a
+    /// histogram registered with a name that is already used is
+    /// rejected and records nothing, see `Service`.
     ///
-    /// If `name` is already used by another histogram in `service`.
-    ///
-    pub fn new(service: &Service, name: String) -> Enum<K> {
+    pub fn new(service: &Service, name: impl Into<Definition>) -> Enum<K> {
         let storage = Box::new(EnumStorage { values: Vec::new() });
-        let key = PrivateAccess::register_plain(service, name, storage);
+        let back_end = PrivateAccess::register_plain(service, name.into(), storage);
         Enum {
             witness: PhantomData,
-            back_end: BackEnd::new(service, key),
+            back_end,
         }
     }
+
+    ///
+    /// Get the number of values recorded for each variant, indexed
+    /// by `Flatten::as_u32`. Trailing variants that have never been
+    /// recorded are omitted.
+    ///
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
+    ///
+    pub fn snapshot(&self) -> Option<Vec<u32>> {
+        self.back_end
+            .query(|storage: &EnumStorage| storage.values.clone())
+    }
 }
 
 impl<K> Clone for Enum<K>
@@ -489,3 +749,1302 @@ where
         }
     }
 }
+
+///
+///
+/// Labeled count histograms.
+///
+/// A LabeledCount histogram accumulates the numbers passed with
+/// `record()` for each label of a fixed set of labels, e.g. the
+/// possible outcomes of an operation. Unlike `Enum`, values are
+/// recorded by label rather than by number and, unlike `KeyedCount`,
+/// labels are known at compile-time, so no label needs to be
+/// allocated and all labels are serialized, even if nothing has been
+/// recorded for them. This matches the labeled counters of Glean.
+///
+/// Values recorded with any other label are counted under `__other__`.
+///
+///
+/// With `SerializationFormat::SimpleJson`, these histograms are
+/// serialized as an object
+/// ````js
+/// {
+///   label_1: number,
+///   label_2: number,
+///   ...
+///   __other__: number,
+/// }
+/// ````
+///
+#[derive(Clone)]
+pub struct LabeledCount {
+    labels: &'static [&'static str],
+    back_end: BackEnd<Plain>,
+}
+
+// The storage, owned by the Telemetry Task.
+struct LabeledCountStorage {
+    labels: &'static [&'static str],
+
+    /// The count of each label, in the order of `labels`, followed by
+    /// the count of `__other__`.
+    values: Vec<u32>,
+}
+
+impl PlainRawStorage for LabeledCountStorage {
+    fn store(&mut self, _: u32) {
+        // Values are always recorded with a label, see `store_at`.
+    }
+    fn store_at(&mut self, label: u32, value: u32) {
+        // Labels are validated when recording.
+        let count = &mut self.values[label as usize];
+        *count = count.saturating_add(value);
+    }
+    fn reset(&mut self) {
+        for value in &mut self.values {
+            *value = 0;
+        }
+    }
+    fn kind(&self) -> Kind {
+        Kind::LabeledCount {
+            labels: self.labels,
+        }
+    }
+    fn to_json(&self, format: &SerializationFormat) -> Json {
+        match format {
+            SerializationFormat::SimpleJson => {
+                let tree = self
+                    .labels
+                    .iter()
+                    .chain(Some(&OTHER_KEY))
+                    .zip(&self.values)
+                    .map(|(label, &count)| (label.to_string(), Json::I64(count as i64)))
+                    .collect();
+                Json::Object(tree)
+            }
+        }
+    }
+}
+
+impl<'a> Histogram<(&'a str, u32)> for LabeledCount {
+    fn record_cb<F>(&self, cb: F)
+    where
+        F: FnOnce() -> Option<(&'a str, u32)>,
+    {
+        if let Some(k) = self.back_end.get_sampled_key() {
+            if let Some((label, value)) = cb().filter(|&(_, value)| value > 0) {
+                // The storage holds one count per label, in the slot
+                // of the index of the label.
+                let index = self
+                    .labels
+                    .iter()
+                    .position(|&known| known == label)
+                    .unwrap_or(self.labels.len());
+                self.back_end.raw_record_at(k, index as u32, value);
+            }
+        }
+    }
+}
+
+impl LabeledCount {
+    ///
+    /// Create a new LabeledCount histogram with a given name and set
+    /// of labels.
+    ///
+    /// Argument `name` is used as key when processing and exporting
+    /// the data. Each `name` must be unique to the `Service`: a
+    /// histogram registered with a name that is already used is
+    /// rejected and records nothing, see `Service`.
+    ///
+    /// # Panics
+    ///
+    /// If `labels` contains `"__other__"` or the same label twice.
+    ///
+    pub fn new(
+        service: &Service,
+        name: impl Into<Definition>,
+        labels: &'static [&'static str],
+    ) -> LabeledCount {
+        assert!(
+            !labels.contains(&OTHER_KEY),
+            "Label {} is reserved",
+            OTHER_KEY
+        );
+        for (index, label) in labels.iter().enumerate() {
+            assert!(
+                !labels[..index].contains(label),
+                "Duplicate label {}",
+                label
+            );
+        }
+        let storage = Box::new(LabeledCountStorage {
+            labels,
+            values: vec_with_size(labels.len() + 1, 0),
+        });
+        let back_end = PrivateAccess::register_plain(service, name.into(), storage);
+        LabeledCount { labels, back_end }
+    }
+
+    ///
+    /// Get the sum of all values recorded so far, for each label,
+    /// including `__other__`.
+    ///
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
+    ///
+    pub fn snapshot(&self) -> Option<HashMap<&'static str, u32>> {
+        self.back_end.query(|storage: &LabeledCountStorage| {
+            storage
+                .labels
+                .iter()
+                .chain(Some(&OTHER_KEY))
+                .cloned()
+                .zip(storage.values.iter().cloned())
+                .collect()
+        })
+    }
+}
+
+///
+///
+/// Summary statistics.
+///
+/// A Stats histogram keeps no buckets, only the number, sum, minimum,
+/// maximum, mean and variance of the values passed with `record()`,
+/// e.g. to monitor the size of payloads. Payloads of several clients
+/// may be combined with `aggregate::merge_stats`.
+///
+///
+/// With `SerializationFormat::SimpleJson`, these histograms are
+/// serialized as an object
+/// ````js
+/// {
+///   count: number,
+///   sum: number,
+///   min: number or null,
+///   max: number or null,
+///   mean: number,
+///   variance: number,
+/// }
+/// ````
+///
+/// where `min` and `max` are `null` if no value has been recorded.
+///
+pub struct Stats<T>
+where
+    T: Flatten,
+{
+    witness: PhantomData<T>,
+    back_end: BackEnd<Plain>,
+}
+
+// The storage, owned by the Telemetry Task.
+struct StatsStorage {
+    stats: RunningStats,
+}
+
+impl PlainRawStorage for StatsStorage {
+    fn store(&mut self, value: u32) {
+        self.store_n(value, 1)
+    }
+    fn store_n(&mut self, value: u32, times: u32) {
+        self.stats.record_n(value, times);
+    }
+    fn reset(&mut self) {
+        self.stats = RunningStats::default();
+    }
+    fn kind(&self) -> Kind {
+        Kind::Stats
+    }
+    fn to_json(&self, format: &SerializationFormat) -> Json {
+        match format {
+            SerializationFormat::SimpleJson => self.stats.to_json(),
+        }
+    }
+}
+
+impl<T> Histogram<T> for Stats<T>
+where
+    T: Flatten,
+{
+    fn record_cb<F>(&self, cb: F)
+    where
+        F: FnOnce() -> Option<T>,
+    {
+        self.back_end.raw_record_cb(cb);
+    }
+}
+
+impl<T> Stats<T>
+where
+    T: Flatten,
+{
+    ///
+    /// Create a new Stats histogram with a given name.
+    ///
+    /// Argument `name` is used as key when processing and exporting
+    /// the data. Each `name` must be unique to the `Service`: a
+    /// histogram registered with a name that is already used is
+    /// rejected and records nothing, see `Service`.
+    ///
+    pub fn new(service: &Service, name: impl Into<Definition>) -> Stats<T> {
+        let storage = Box::new(StatsStorage {
+            stats: RunningStats::default(),
+        });
+        let back_end = PrivateAccess::register_plain(service, name.into(), storage);
+        Stats {
+            witness: PhantomData,
+            back_end,
+        }
+    }
+
+    ///
+    /// Get the statistics of all values recorded so far.
+    ///
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
+    ///
+    pub fn snapshot(&self) -> Option<Summary> {
+        self.back_end
+            .query(|storage: &StatsStorage| storage.stats.summary())
+    }
+}
+
+impl<T> Clone for Stats<T>
+where
+    T: Flatten,
+{
+    fn clone(&self) -> Self {
+        Stats {
+            witness: PhantomData,
+            back_end: self.back_end.clone(),
+        }
+    }
+}
+
+///
+///
+/// Privacy-preserving flag histograms.
+///
+/// As a `Flag`, this histogram records whether `record()` has been
+/// called during the session, but it only ever reports an encoding of
+/// this bit with `epsilon`-randomized response: the reported bit is the
+/// true bit with probability `e^ε / (1 + e^ε)` and its opposite
+/// otherwise. A payload therefore reveals little about a single user,
+/// while the proportion of users who have set the flag may be
+/// estimated from many payloads, see `aggregate::estimate_private_flags`.
+///
+/// The bit is encoded once when the histogram is created and once
+/// more when it is first set, so neither recording repeatedly nor
+/// serializing the same session several times weakens the guarantee.
+///
+///
+/// With `SerializationFormat::SimpleJson`, these histograms are
+/// serialized as an object
+/// ````js
+/// {
+///   epsilon: number,
+///   value: 0 or 1,
+/// }
+/// ````
+///
+pub struct PrivateFlag {
+    back_end: BackEnd<Plain>,
+
+    /// A cache used to avoid spamming the Task once the flag has been set.
+    cache: AtomicBool,
+}
+
+/// The storage, owned by the Telemetry Task.
+struct PrivateFlagStorage {
+    epsilon: f64,
+    rng: Rng,
+
+    /// The encoded bit.
+    encoded: bool,
+
+    /// `true` once `true` has been encoded. It is encoded at most once,
+    /// as in RAPPOR's permanent randomized response, so that recording
+    /// repeatedly reveals nothing more than recording once. Never
+    /// serialized.
+    recorded: bool,
+}
+
+impl PrivateFlagStorage {
+    fn encode(&mut self, value: bool) {
+        self.encoded = randomized_response(&mut self.rng, value as u32, 2, self.epsilon) == 1;
+    }
+}
+
+impl PlainRawStorage for PrivateFlagStorage {
+    fn store(&mut self, _: u32) {
+        if !self.recorded {
+            self.recorded = true;
+            self.encode(true);
+        }
+    }
+    fn store_n(&mut self, value: u32, _: u32) {
+        self.store(value)
+    }
+    fn reset(&mut self) {
+        self.recorded = false;
+        self.encode(false);
+    }
+    fn kind(&self) -> Kind {
+        Kind::PrivateFlag {
+            epsilon: self.epsilon,
+        }
+    }
+    fn to_json(&self, format: &SerializationFormat) -> Json {
+        match format {
+            SerializationFormat::SimpleJson => {
+                let mut tree = BTreeMap::new();
+                tree.insert("epsilon".to_string(), Json::F64(self.epsilon));
+                tree.insert("value".to_string(), Json::I64(self.encoded as i64));
+                Json::Object(tree)
+            }
+        }
+    }
+}
+
+impl Histogram<()> for PrivateFlag {
+    fn record_cb<F>(&self, cb: F)
+    where
+        F: FnOnce() -> Option<()>,
+    {
+        if self.cache.load(Ordering::Relaxed) {
+            return;
+        }
+        if self.back_end.raw_record_cb(cb) {
+            self.cache.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl PrivateFlag {
+    ///
+    /// Create a new PrivateFlag histogram with a given name.
+    ///
+    /// - `name` is used as key when processing and exporting
+    ///   the data. Each `name` must be unique to the `Service`.
+    ///
+    /// - `epsilon` is the privacy parameter. The lower, the more
+    ///   private, and the more payloads are needed for an accurate
+    ///   estimate.
+    ///
+    /// Each `name` must be unique to the `Service`: a histogram
+    /// registered with a name that is already used is rejected and
+    /// records nothing, see `Service`.
+    ///
+    /// # Panics
+    ///
+    /// If `epsilon` is not positive.
+    ///
+    pub fn new(service: &Service, name: impl Into<Definition>, epsilon: f64) -> PrivateFlag {
+        check_epsilon(epsilon);
+        let mut storage = Box::new(PrivateFlagStorage {
+            epsilon,
+            rng: Rng::new(PrivateAccess::next_seed(service)),
+            encoded: false,
+            recorded: false,
+        });
+        storage.reset();
+        let back_end = PrivateAccess::register_plain(service, name.into(), storage);
+        PrivateFlag {
+            back_end,
+            cache: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Clone for PrivateFlag {
+    fn clone(&self) -> Self {
+        PrivateFlag {
+            back_end: self.back_end.clone(),
+            cache: AtomicBool::new(self.cache.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+///
+///
+/// Privacy-preserving enumerated histograms.
+///
+/// As an `Enum`, this histogram counts how many times each variant has
+/// been recorded, but each record is first encoded with
+/// `epsilon`-randomized response: it is stored as the recorded variant
+/// with probability `e^ε / (e^ε + variants - 1)`, and as one of the
+/// other variants otherwise. The true counts of a population may be
+/// estimated from many payloads, see `aggregate::estimate_private_enums`.
+///
+/// The guarantee holds for each record. A session recording `n` values
+/// is `n × ε`-private.
+///
+/// Values greater than or equal to `variants` are ignored.
+///
+///
+/// With `SerializationFormat::SimpleJson`, these histograms are
+/// serialized as an object
+/// ````js
+/// {
+///   epsilon: number,
+///   values: array of `variants` numbers, in the order of enum values,
+/// }
+/// ````
+///
+pub struct PrivateEnum<K>
+where
+    K: Flatten,
+{
+    witness: PhantomData<K>,
+    back_end: BackEnd<Plain>,
+}
+
+// The storage, owned by the Telemetry Task.
+struct PrivateEnumStorage {
+    epsilon: f64,
+    rng: Rng,
+
+    /// The number of encoded records for each variant.
+    values: Vec<u32>,
+}
+
+impl PlainRawStorage for PrivateEnumStorage {
+    fn store(&mut self, value: u32) {
+        let variants = self.values.len() as u32;
+        if value >= variants {
+            return;
+        }
+        let encoded = randomized_response(&mut self.rng, value, variants, self.epsilon);
+        let count = &mut self.values[encoded as usize];
+        *count = count.saturating_add(1);
+    }
+    fn reset(&mut self) {
+        for value in &mut self.values {
+            *value = 0;
+        }
+    }
+    fn kind(&self) -> Kind {
+        Kind::PrivateEnum {
+            variants: self.values.len() as u32,
+            epsilon: self.epsilon,
+        }
+    }
+    fn to_json(&self, format: &SerializationFormat) -> Json {
+        match format {
+            SerializationFormat::SimpleJson => {
+                let mut tree = BTreeMap::new();
+                tree.insert("epsilon".to_string(), Json::F64(self.epsilon));
+                tree.insert(
+                    "values".to_string(),
+                    Json::Array(self.values.iter().map(|&x| Json::I64(x as i64)).collect()),
+                );
+                Json::Object(tree)
+            }
+        }
+    }
+}
+
+impl<K> Histogram<K> for PrivateEnum<K>
+where
+    K: Flatten,
+{
+    fn record_cb<F>(&self, cb: F)
+    where
+        F: FnOnce() -> Option<K>,
+    {
+        self.back_end.raw_record_cb(cb);
+    }
+}
+
+impl<K> PrivateEnum<K>
+where
+    K: Flatten,
+{
+    ///
+    /// Create a new PrivateEnum histogram with a given name.
+    ///
+    /// - `name` is used as key when processing and exporting
+    ///   the data. Each `name` must be unique to the `Service`.
+    ///
+    /// - `variants` is the number of variants of `K`, i.e. one more
+    ///   than the greatest value of `Flatten::as_u32`.
+    ///
+    /// - `epsilon` is the privacy parameter. The lower, the more
+    ///   private, and the more payloads are needed for an accurate
+    ///   estimate.
+    ///
+    /// Each `name` must be unique to the `Service`: a histogram
+    /// registered with a name that is already used is rejected and
+    /// records nothing, see `Service`.
+    ///
+    /// # Panics
+    ///
+    /// If `variants` is less than 2 or if `epsilon` is not positive.
+    ///
+    pub fn new(
+        service: &Service,
+        name: impl Into<Definition>,
+        variants: u32,
+        epsilon: f64,
+    ) -> PrivateEnum<K> {
+        assert!(variants >= 2);
+        check_epsilon(epsilon);
+        let storage = Box::new(PrivateEnumStorage {
+            epsilon,
+            rng: Rng::new(PrivateAccess::next_seed(service)),
+            values: vec_with_size(variants as usize, 0),
+        });
+        let back_end = PrivateAccess::register_plain(service, name.into(), storage);
+        PrivateEnum {
+            witness: PhantomData,
+            back_end,
+        }
+    }
+}
+
+impl<K> Clone for PrivateEnum<K>
+where
+    K: Flatten,
+{
+    fn clone(&self) -> Self {
+        PrivateEnum {
+            witness: PhantomData,
+            back_end: self.back_end.clone(),
+        }
+    }
+}
+
+///
+///
+/// Distinct count histograms.
+///
+/// A DistinctCount histogram estimates how many distinct values have
+/// been passed to `record()`, e.g. the number of documents, hosts or
+/// users touched by the application, without storing the values
+/// themselves. Values are hashed on the calling thread and the
+/// estimate is maintained by a HyperLogLog sketch, with a relative
+/// standard error of about `1.04 / sqrt(2^precision)`.
+///
+/// Hashes are stable across runs, so the sketches of several payloads
+/// (e.g. several sessions or several clients) can be merged, see
+/// `aggregate::merge_distinct_counts`.
+///
+///
+/// With `SerializationFormat::SimpleJson`, these histograms are
+/// serialized as an object
+/// ````js
+/// {
+///   precision: number,
+///   registers: array of 2^precision numbers,
+///   estimate: number,
+/// }
+/// ````
+///
+pub struct DistinctCount<T>
+where
+    T: Hash,
+{
+    witness: PhantomData<T>,
+    back_end: BackEnd<Plain>,
+}
+
+// The storage, owned by the Telemetry Task.
+struct DistinctCountStorage {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl PlainRawStorage for DistinctCountStorage {
+    fn store(&mut self, hash: u32) {
+        hyperloglog_insert(&mut self.registers, self.precision, hash);
+    }
+    fn store_n(&mut self, hash: u32, _: u32) {
+        // Inserting the same hash again doesn't change anything.
+        self.store(hash)
+    }
+    fn reset(&mut self) {
+        self.registers = vec_with_size(1 << self.precision, 0);
+    }
+    fn kind(&self) -> Kind {
+        Kind::DistinctCount {
+            precision: self.precision,
+        }
+    }
+    fn to_json(&self, format: &SerializationFormat) -> Json {
+        match format {
+            SerializationFormat::SimpleJson => {
+                let mut tree = BTreeMap::new();
+                tree.insert("precision".to_string(), Json::I64(self.precision as i64));
+                tree.insert(
+                    "registers".to_string(),
+                    Json::Array(
+                        self.registers
+                            .iter()
+                            .map(|&x| Json::I64(x as i64))
+                            .collect(),
+                    ),
+                );
+                tree.insert(
+                    "estimate".to_string(),
+                    Json::I64(hyperloglog_estimate(&self.registers).round() as i64),
+                );
+                Json::Object(tree)
+            }
+        }
+    }
+}
+
+impl<T> Histogram<T> for DistinctCount<T>
+where
+    T: Hash,
+{
+    fn record_cb<F>(&self, cb: F)
+    where
+        F: FnOnce() -> Option<T>,
+    {
+        self.back_end
+            .raw_record_cb(|| cb().map(|value| stable_hash_u32(&value)));
+    }
+}
+
+impl<T> DistinctCount<T>
+where
+    T: Hash,
+{
+    ///
+    /// Create a new DistinctCount histogram with a given name.
+    ///
+    /// - `name` is used as key when processing and exporting
+    ///   the data. Each `name` must be unique to the `Service`.
+    ///
+    /// - `precision` is the number of bits of each hash used to
+    ///   pick a register, between 4 and 16. The sketch holds
+    ///   `2^precision` registers, so each additional bit doubles both
+    ///   the memory used and the size of the payload.
+    ///
+    /// Each `name` must be unique to the `Service`: a histogram
+    /// registered with a name that is already used is rejected and
+    /// records nothing, see `Service`.
+    ///
+    /// # Panics
+    ///
+    /// If `precision` is not in `[4, 16]`.
+    ///
+    pub fn new(service: &Service, name: impl Into<Definition>, precision: u8) -> DistinctCount<T> {
+        assert!(precision >= HYPERLOGLOG_MIN_PRECISION);
+        assert!(precision <= HYPERLOGLOG_MAX_PRECISION);
+        let storage = Box::new(DistinctCountStorage {
+            precision,
+            registers: vec_with_size(1 << precision, 0),
+        });
+        let back_end = PrivateAccess::register_plain(service, name.into(), storage);
+        DistinctCount {
+            witness: PhantomData,
+            back_end,
+        }
+    }
+}
+
+impl<T> Clone for DistinctCount<T>
+where
+    T: Hash,
+{
+    fn clone(&self) -> Self {
+        DistinctCount {
+            witness: PhantomData,
+            back_end: self.back_end.clone(),
+        }
+    }
+}
+
+///
+///
+/// Slowest operations histograms.
+///
+/// A SlowestN histogram keeps the `n` highest values passed to
+/// `record()`, each with a label describing the operation (e.g. an
+/// operation id, a query) and the time at which it was recorded. This
+/// is typically used alongside a `Linear` histogram of durations, to
+/// find out _which_ operations are responsible for the tail of the
+/// distribution.
+///
+/// Labels may contain arbitrary data, so make sure that they do not
+/// contain private information.
+///
+///
+/// With `SerializationFormat::SimpleJson`, these histograms are
+/// serialized as an array of at most `n` objects, by decreasing
+/// value:
+/// ````js
+/// [
+///   { value: number, label: string, timestamp: number },
+///   ...
+/// ]
+/// ````
+///
+/// where `timestamp` is expressed in milliseconds since the Unix epoch,
+/// as given by the `Clock` of the service.
+///
+pub struct SlowestN<T>
+where
+    T: Flatten,
+{
+    witness: PhantomData<T>,
+    back_end: BackEnd<Plain>,
+
+    /// The maximal length of labels, in bytes.
+    max_label_len: usize,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct SlowestEntry {
+    // Field order matters, as it determines the order of entries.
+    value: u32,
+    timestamp: u64,
+    label: String,
+}
+
+// The storage, owned by the Telemetry Task.
+struct SlowestNStorage {
+    /// The number of entries to keep.
+    capacity: usize,
+
+    /// A min-heap, so that the fastest entry can be evicted cheaply.
+    /// Invariant: `entries.len() <= capacity`.
+    entries: BinaryHeap<Reverse<SlowestEntry>>,
+
+    /// The source of timestamps.
+    clock: Arc<dyn Clock>,
+}
+
+impl PlainRawStorage for SlowestNStorage {
+    fn store(&mut self, value: u32) {
+        self.store_labeled(value, String::new())
+    }
+    fn store_labeled(&mut self, value: u32, label: String) {
+        if self.entries.len() >= self.capacity {
+            match self.entries.peek() {
+                Some(Reverse(fastest)) if fastest.value < value => {}
+                _ => return,
+            }
+            self.entries.pop();
+        }
+        let timestamp = match self.clock.system().duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs() * 1000 + duration.subsec_millis() as u64,
+            Err(_) => 0,
+        };
+        self.entries.push(Reverse(SlowestEntry {
+            value,
+            timestamp,
+            label,
+        }));
+    }
+    fn reset(&mut self) {
+        self.entries.clear();
+    }
+    fn kind(&self) -> Kind {
+        Kind::SlowestN { n: self.capacity }
+    }
+    fn to_json(&self, format: &SerializationFormat) -> Json {
+        match format {
+            SerializationFormat::SimpleJson => {
+                let mut entries: Vec<_> = self.entries.iter().map(|x| &x.0).collect();
+                entries.sort_by(|a, b| b.cmp(a));
+                let array = entries
+                    .iter()
+                    .map(|entry| {
+                        let mut tree = BTreeMap::new();
+                        tree.insert("value".to_string(), Json::I64(entry.value as i64));
+                        tree.insert("label".to_string(), Json::String(entry.label.clone()));
+                        tree.insert("timestamp".to_string(), Json::I64(entry.timestamp as i64));
+                        Json::Object(tree)
+                    })
+                    .collect();
+                Json::Array(array)
+            }
+        }
+    }
+}
+
+impl<T> Histogram<(T, String)> for SlowestN<T>
+where
+    T: Flatten,
+{
+    fn record_cb<F>(&self, cb: F)
+    where
+        F: FnOnce() -> Option<(T, String)>,
+    {
+        let max_label_len = self.max_label_len;
+        self.back_end.raw_record_labeled_cb(|| {
+            cb().map(|(value, mut label)| {
+                if label.len() > max_label_len {
+                    let mut len = max_label_len;
+                    while !label.is_char_boundary(len) {
+                        len -= 1;
+                    }
+                    label.truncate(len);
+                }
+                (value, label)
+            })
+        });
+    }
+}
+
+impl<T> SlowestN<T>
+where
+    T: Flatten,
+{
+    ///
+    /// Create a new SlowestN histogram with a given name.
+    ///
+    /// - `name` is used as key when processing and exporting
+    ///   the data. Each `name` must be unique to the `Service`.
+    ///
+    /// - `n` is the number of entries to keep.
+    ///
+    /// - `max_label_len` is the maximal length of labels, in bytes.
+    ///   Longer labels are truncated (on a character boundary) before
+    ///   being sent to the telemetry thread.
+    ///
+    /// Each `name` must be unique to the `Service`: a histogram
+    /// registered with a name that is already used is rejected and
+    /// records nothing, see `Service`.
+    ///
+    /// # Panics
+    ///
+    /// If `n == 0`.
+    ///
+    pub fn new(
+        service: &Service,
+        name: impl Into<Definition>,
+        n: usize,
+        max_label_len: usize,
+    ) -> SlowestN<T> {
+        assert!(n > 0);
+        let storage = Box::new(SlowestNStorage {
+            capacity: n,
+            entries: BinaryHeap::with_capacity(n),
+            clock: PrivateAccess::get_clock(service).clone(),
+        });
+        let back_end = PrivateAccess::register_plain(service, name.into(), storage);
+        SlowestN {
+            witness: PhantomData,
+            back_end,
+            max_label_len,
+        }
+    }
+}
+
+impl<T> Clone for SlowestN<T>
+where
+    T: Flatten,
+{
+    fn clone(&self) -> Self {
+        SlowestN {
+            witness: PhantomData,
+            back_end: self.back_end.clone(),
+            max_label_len: self.max_label_len,
+        }
+    }
+}
+
+//
+// A ring of per-interval values, shared by the windowed histograms.
+//
+// Slots are reused lazily: a slot is reset when it is first written
+// during a new interval, and ignored when read if it was last written
+// outside of the current window.
+//
+struct Window<V> {
+    /// The duration of each interval.
+    interval: Duration,
+
+    /// The source of time.
+    clock: Arc<dyn Clock>,
+
+    /// The monotonic time at which the first interval started.
+    origin: Duration,
+
+    /// The value used to reset a slot.
+    empty: V,
+
+    /// One slot per interval, each tagged with the number of the
+    /// interval during which it was last written.
+    slots: Vec<(u64, V)>,
+}
+
+impl<V> Window<V>
+where
+    V: Clone,
+{
+    fn new(clock: &Arc<dyn Clock>, interval: Duration, intervals: usize, empty: V) -> Window<V> {
+        Window {
+            interval,
+            clock: clock.clone(),
+            origin: clock.monotonic(),
+            slots: vec_with_size(intervals, (0, empty.clone())),
+            empty,
+        }
+    }
+
+    // The number of the current interval.
+    fn current_tick(&self) -> u64 {
+        let elapsed = self
+            .clock
+            .monotonic()
+            .checked_sub(self.origin)
+            .unwrap_or_else(|| Duration::from_secs(0));
+        (elapsed.as_nanos() / self.interval.as_nanos()) as u64
+    }
+
+    // The value for the current interval.
+    fn current_mut(&mut self) -> &mut V {
+        let tick = self.current_tick();
+        let len = self.slots.len();
+        let slot = &mut self.slots[(tick % len as u64) as usize];
+        if slot.0 != tick {
+            *slot = (tick, self.empty.clone());
+        }
+        &mut slot.1
+    }
+
+    // Forget all values.
+    fn clear(&mut self) {
+        for slot in &mut self.slots {
+            slot.1 = self.empty.clone();
+        }
+    }
+
+    // The values of all intervals in the window, oldest first.
+    fn values(&self) -> Vec<&V> {
+        let tick = self.current_tick();
+        let len = self.slots.len() as u64;
+        // Ticks older than the first interval may not exist yet.
+        let first = (tick + 1).saturating_sub(len);
+        (first..tick + 1)
+            .map(|t| {
+                let slot = &self.slots[(t % len) as usize];
+                if slot.0 == t {
+                    &slot.1
+                } else {
+                    &self.empty
+                }
+            })
+            .collect()
+    }
+}
+
+///
+///
+/// Windowed count histograms.
+///
+/// A WindowedCount histogram accumulates the numbers passed with
+/// `record()`, as a `Count` histogram does, but only remembers them
+/// for a rolling window of time, divided into `intervals` intervals
+/// of duration `interval`, as measured by the `Clock` of the service.
+/// This is typically used for health checks such as "number of errors
+/// in the last 60 seconds". The total for the current window is
+/// available through `query()`.
+///
+/// Values are assigned to an interval when the Telemetry Task stores
+/// them, not when they are recorded. Values delayed in the queue or
+/// by batching (see `ServiceBuilder::batching`) may therefore land in
+/// a later interval than the one during which they were recorded.
+///
+///
+/// With `SerializationFormat::SimpleJson`, these histograms are
+/// serialized as an array of numbers, one per interval of the
+/// current window, from the oldest to the current interval.
+///
+#[derive(Clone)]
+pub struct WindowedCount {
+    back_end: BackEnd<Plain>,
+}
+
+// The storage, owned by the Telemetry Task.
+struct WindowedCountStorage {
+    window: Window<u32>,
+}
+
+impl WindowedCountStorage {
+    fn total(&self) -> u32 {
+        self.window
+            .values()
+            .iter()
+            .fold(0, |acc, &&value| acc.saturating_add(value))
+    }
+}
+
+impl PlainRawStorage for WindowedCountStorage {
+    fn store(&mut self, value: u32) {
+        self.store_n(value, 1)
+    }
+    fn store_n(&mut self, value: u32, times: u32) {
+        let current = self.window.current_mut();
+        *current = current.saturating_add(value.saturating_mul(times));
+    }
+    fn reset(&mut self) {
+        self.window.clear();
+    }
+    fn kind(&self) -> Kind {
+        Kind::WindowedCount {
+            interval: self.window.interval,
+            intervals: self.window.slots.len(),
+        }
+    }
+    fn to_json(&self, format: &SerializationFormat) -> Json {
+        match format {
+            SerializationFormat::SimpleJson => Json::Array(
+                self.window
+                    .values()
+                    .iter()
+                    .map(|&&x| Json::I64(x as i64))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl Histogram<u32> for WindowedCount {
+    fn record_cb<F>(&self, cb: F)
+    where
+        F: FnOnce() -> Option<u32>,
+    {
+        self.back_end.raw_record_cb(cb);
+    }
+}
+
+impl WindowedCount {
+    ///
+    /// Create a new WindowedCount histogram with a given name.
+    ///
+    /// - `name` is used as key when processing and exporting
+    ///   the data. Each `name` must be unique to the `Service`.
+    ///
+    /// - `interval` is the duration of each interval.
+    ///
+    /// - `intervals` is the number of intervals in the window, so
+    ///   the window covers `intervals * interval`.
+    ///
+    /// Each `name` must be unique to the `Service`: a histogram
+    /// registered with a name that is already used is rejected and
+    /// records nothing, see `Service`.
+    ///
+    /// # Panics
+    ///
+    /// If `interval` is zero or `intervals == 0`.
+    ///
+    pub fn new(
+        service: &Service,
+        name: impl Into<Definition>,
+        interval: Duration,
+        intervals: usize,
+    ) -> WindowedCount {
+        assert!(interval > Duration::from_secs(0));
+        assert!(intervals > 0);
+        let storage = Box::new(WindowedCountStorage {
+            window: Window::new(PrivateAccess::get_clock(service), interval, intervals, 0),
+        });
+        let back_end = PrivateAccess::register_plain(service, name.into(), storage);
+        WindowedCount { back_end }
+    }
+
+    ///
+    /// Get the total of the values recorded during the current window.
+    ///
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
+    ///
+    pub fn query(&self) -> Option<u32> {
+        self.back_end
+            .query(|storage: &WindowedCountStorage| storage.total())
+    }
+}
+
+///
+///
+/// Windowed linear histograms.
+///
+/// A WindowedLinear histogram classifies values into buckets, as a
+/// `Linear` histogram does, but only remembers them for a rolling
+/// window of time, divided into `intervals` intervals of duration
+/// `interval`, as measured by the `Clock` of the service. This is
+/// typically used for health checks such as "95th percentile of
+/// latency over the last 5 minutes". The buckets for the current
+/// window are available through `query()`.
+///
+/// As for `WindowedCount`, values are assigned to an interval when the
+/// Telemetry Task stores them, not when they are recorded.
+///
+///
+/// With `SerializationFormat::SimpleJson`, these histograms are
+/// serialized as an array with one entry per interval of the current
+/// window, from the oldest to the current interval, each entry being
+/// an array of numbers, one per bucket, as for `Linear`.
+///
+pub struct WindowedLinear<T>
+where
+    T: Flatten,
+{
+    witness: PhantomData<T>,
+    back_end: BackEnd<Plain>,
+}
+
+// The storage, owned by the Telemetry Task.
+struct WindowedLinearStorage {
+    window: Window<Vec<u32>>,
+    shape: LinearBuckets,
+}
+
+impl WindowedLinearStorage {
+    fn merged(&self) -> Vec<u32> {
+        let mut merged = vec_with_size(self.shape.buckets, 0u32);
+        for values in self.window.values() {
+            for (acc, &value) in merged.iter_mut().zip(values.iter()) {
+                *acc = acc.saturating_add(value);
+            }
+        }
+        merged
+    }
+}
+
+impl PlainRawStorage for WindowedLinearStorage {
+    fn store(&mut self, value: u32) {
+        self.store_n(value, 1)
+    }
+    fn store_n(&mut self, value: u32, times: u32) {
+        let index = self.shape.get_bucket(value);
+        let count = &mut self.window.current_mut()[index];
+        *count = count.saturating_add(times);
+    }
+    fn reset(&mut self) {
+        self.window.clear();
+    }
+    fn kind(&self) -> Kind {
+        Kind::WindowedLinear {
+            min: self.shape.min(),
+            max: self.shape.max(),
+            buckets: self.shape.buckets,
+            interval: self.window.interval,
+            intervals: self.window.slots.len(),
+        }
+    }
+    fn to_json(&self, format: &SerializationFormat) -> Json {
+        match format {
+            SerializationFormat::SimpleJson => Json::Array(
+                self.window
+                    .values()
+                    .iter()
+                    .map(|values| {
+                        Json::Array(values.iter().map(|&x| Json::I64(x as i64)).collect())
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl<T> Histogram<T> for WindowedLinear<T>
+where
+    T: Flatten,
+{
+    fn record_cb<F>(&self, cb: F)
+    where
+        F: FnOnce() -> Option<T>,
+    {
+        self.back_end.raw_record_cb(cb);
+    }
+}
+
+impl<T> WindowedLinear<T>
+where
+    T: Flatten,
+{
+    ///
+    /// Create a new WindowedLinear histogram with a given name.
+    ///
+    /// - `name`, `min`, `max` and `buckets` have the same meaning as
+    ///   for `Linear::new`.
+    ///
+    /// - `interval` is the duration of each interval.
+    ///
+    /// - `intervals` is the number of intervals in the window, so
+    ///   the window covers `intervals * interval`.
+    ///
+    /// Each `name` must be unique to the `Service`: a histogram
+    /// registered with a name that is already used is rejected and
+    /// records nothing, see `Service`.
+    ///
+    /// # Performance
+    ///
+    /// The memory used on the client is proportional to
+    /// `buckets * intervals`, and so is the size of the payload.
+    ///
+    /// # Panics
+    ///
+    /// If `min >= max`.
+    ///
+    /// If `buckets == 0` or `buckets > max - min + 1`.
+    ///
+    /// If `interval` is zero or `intervals == 0`.
+    ///
+    pub fn new(
+        service: &Service,
+        name: impl Into<Definition>,
+        min: u32,
+        max: u32,
+        buckets: usize,
+        interval: Duration,
+        intervals: usize,
+    ) -> WindowedLinear<T> {
+        assert!(size_of::<u32>() <= size_of::<usize>());
+        assert!(min < max);
+        assert!(buckets > 0 && buckets as u64 <= (max - min) as u64 + 1);
+        assert!(interval > Duration::from_secs(0));
+        assert!(intervals > 0);
+        let shape = LinearBuckets::new(min, max, buckets);
+        let storage = Box::new(WindowedLinearStorage {
+            window: Window::new(
+                PrivateAccess::get_clock(service),
+                interval,
+                intervals,
+                vec_with_size(buckets, 0),
+            ),
+            shape,
+        });
+        let back_end = PrivateAccess::register_plain(service, name.into(), storage);
+        WindowedLinear {
+            witness: PhantomData,
+            back_end,
+        }
+    }
+
+    ///
+    /// Get the number of values in each bucket, for all values
+    /// recorded during the current window.
+    ///
+    /// This waits for the Telemetry Task to process all values
+    /// previously recorded from this thread. Returns `None` if the
+    /// Telemetry Task is not running anymore.
+    ///
+    pub fn query(&self) -> Option<Vec<u32>> {
+        self.back_end
+            .query(|storage: &WindowedLinearStorage| storage.merged())
+    }
+}
+
+impl<T> Clone for WindowedLinear<T>
+where
+    T: Flatten,
+{
+    fn clone(&self) -> Self {
+        WindowedLinear {
+            witness: PhantomData,
+            back_end: self.back_end.clone(),
+        }
+    }
+}
@@ -1,14 +1,30 @@
 extern crate rustc_serialize;
 use self::rustc_serialize::json::Json;
 
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{channel, Sender};
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use batch::Batching;
+use clock::{Clock, SystemClock};
 use indexing::*;
-use misc::{NamedStorage, SerializationFormat, Subset};
-use task::{KeyedRawStorage, Op, PlainRawStorage, TelemetryTask};
+use misc::{
+    glob_matches, Category, Definition, HistogramInfo, Kind, Layout, NamedStorage, OverflowPolicy,
+    Rng, Sampler, Sampling, SerializationFormat, Subset, Version,
+};
+use task::{
+    BackEnd, KeyedRawStorage, Op, OpSender, PlainRawStorage, TelemetryTask, SELF_TELEMETRY,
+    STATE_DISABLED, STATE_EXPIRED, STATE_INACTIVE, STATE_NO_CONSENT, STATE_REJECTED,
+    STATE_SAMPLED_OUT,
+};
 
 ///
 /// The Telemetry service.
@@ -19,10 +35,11 @@ use task::{KeyedRawStorage, Op, PlainRawStorage, TelemetryTask};
 /// data is stored and processed in a dedicated background thread and
 /// the memory is recollected only when the service is dropped.
 ///
-/// # Panics
-///
-/// The service will panic if an attempt is made to register two
-/// histograms with the same key.
+/// Registering a histogram with a name that is already used, that is
+/// reserved (`__telemetry__`) or that conflicts with a namespace (see
+/// `Layout::Nested`) does not panic: the histogram simply records
+/// nothing, and the name is serialized with plain histograms, in
+/// field `rejected_names` of an object called `__telemetry__`.
 ///
 impl Service {
     ///
@@ -34,32 +51,217 @@ impl Service {
     /// records. Otherwise, the service will only start recording once
     /// `set_active(true)` has been called.
     ///
+    /// # Panics
+    ///
+    /// If the thread cannot be launched. Use `ServiceBuilder` to
+    /// handle this error.
+    ///
     pub fn new(is_active: bool) -> Service {
-        let (sender, receiver) = channel();
-        thread::spawn(|| {
-            let mut task = TelemetryTask::new(receiver);
-            task.run()
-        });
+        ServiceBuilder::new()
+            .active(is_active)
+            .build()
+            .expect("Could not launch the telemetry thread")
+    }
+
+    ///
+    /// Get a handle on the service through which every registered
+    /// histogram is named `name.x` rather than `x`, e.g. `network.requests`
+    /// for a histogram `requests` registered through
+    /// `service.scope("network")`. Scopes may be nested.
+    ///
+    /// Empty components of `name` are ignored, e.g. `scope("")` adds no
+    /// scope and `scope("network..http")` is `scope("network.http")`.
+    ///
+    /// The handle shares everything else with this service: data,
+    /// activation, configuration, etc. Serializing through the handle
+    /// serializes all histograms of the service. Dropping the handle
+    /// does not stop the service.
+    ///
+    pub fn scope(&self, name: &str) -> Service {
+        let mut namespace = self.namespace.clone();
+        namespace.extend(
+            name.split('.')
+                .filter(|component| !component.is_empty())
+                .map(str::to_string),
+        );
         Service {
-            keys_plain: KeyGenerator::new(),
-            keys_keyed: KeyGenerator::new(),
-            sender,
-            is_active: Arc::new(AtomicBool::new(is_active)),
+            keys_plain: self.keys_plain.clone(),
+            keys_keyed: self.keys_keyed.clone(),
+            registry: self.registry.clone(),
+            consent: self.consent.clone(),
+            sender: self.sender.clone(),
+            batching: self.batching.clone(),
+            max_keys: self.max_keys,
+            clock: self.clock.clone(),
+            app_version: self.app_version.clone(),
+            persistence_path: self.persistence_path.clone(),
+            config_path: self.config_path.clone(),
+            format: self.format,
+            namespace,
+            thread: None,
+            persist_on_shutdown: false,
+            shutdown_hooks: Mutex::new(Vec::new()),
         }
     }
 
     ///
     /// Serialize all histograms as json, in a given format.
     ///
-    /// # Panics
-    ///
-    /// The service will panic if the sender is closed by the time serialization
-    /// is complete.
+    /// If the telemetry thread is not running anymore, `sender` is
+    /// dropped without receiving anything.
     ///
     pub fn to_json(&self, what: Subset, format: SerializationFormat, sender: Sender<Json>) {
+        let _ = self.sender.send(Op::Serialize(what, format, sender));
+    }
+
+    ///
+    /// Serialize all histograms as json, in the default format of the
+    /// service (see `ServiceBuilder::format`).
+    ///
+    /// If the telemetry thread is not running anymore, `sender` is
+    /// dropped without receiving anything.
+    ///
+    pub fn serialize(&self, what: Subset, sender: Sender<Json>) {
+        self.to_json(what, self.format, sender)
+    }
+
+    ///
+    /// Write all histograms to the persistence path of the service
+    /// (see `ServiceBuilder::persistence_path`), in the default format,
+    /// as an object `{ plain: ..., keyed: ... }`.
+    ///
+    /// This waits until the telemetry thread has serialized the data.
+    ///
+    /// # Errors
+    ///
+    /// If no persistence path was configured or the file cannot be written.
+    ///
+    pub fn persist(&self) -> io::Result<()> {
+        let path = match self.persistence_path {
+            Some(ref path) => path,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "No persistence path configured",
+                ))
+            }
+        };
+        self.snapshot()?.write_to(path)
+    }
+
+    ///
+    /// Stop the service and return the data it holds.
+    ///
+    /// This sends the values accumulated by batching threads (see
+    /// `flush`), waits until the telemetry thread has processed all
+    /// pending operations, serializes all histograms in the default
+    /// format, stops the thread and waits for it to finish. Then, it
+    /// runs the shutdown hooks (see `ServiceBuilder::persist_on_shutdown`
+    /// and `ServiceBuilder::on_shutdown`), in order. Hooks that fail
+    /// don't prevent the other hooks from running, and their errors are
+    /// returned along with the data.
+    ///
+    /// # Errors
+    ///
+    /// If the telemetry thread is not running anymore.
+    ///
+    /// Also, if this is a handle obtained through `scope`, which cannot
+    /// stop the service.
+    ///
+    pub fn shutdown(mut self) -> io::Result<ShutdownOutcome> {
+        if self.thread.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Only the service itself may be shut down, not a scope",
+            ));
+        }
+        let snapshot = self.snapshot();
+        let _ = self.sender.send(Op::Terminate);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Telemetry thread has panicked",
+                ));
+            }
+        }
+        let snapshot = snapshot?;
+        let mut hooks = Vec::new();
+        if self.persist_on_shutdown {
+            if let Some(path) = self.persistence_path.take() {
+                hooks.push(
+                    Box::new(move |snapshot: &FinalSnapshot| snapshot.write_to(&path))
+                        as ShutdownHook,
+                );
+            }
+        }
+        hooks.append(&mut self.shutdown_hooks.lock().unwrap());
+        let hook_errors = hooks
+            .into_iter()
+            .filter_map(|hook| hook(&snapshot).err())
+            .collect();
+        Ok(ShutdownOutcome {
+            snapshot,
+            hook_errors,
+        })
+    }
+
+    ///
+    /// Serialize all histograms in the default format, after flushing
+    /// batches.
+    ///
+    fn snapshot(&self) -> io::Result<FinalSnapshot> {
+        self.flush();
+        let (sender, receiver) = channel();
+        let disconnected =
+            || io::Error::new(io::ErrorKind::BrokenPipe, "Telemetry thread is not running");
+        self.sender
+            .send(Op::Serialize(Subset::AllPlain, self.format, sender.clone()))
+            .map_err(|_| disconnected())?;
         self.sender
-            .send(Op::Serialize(what, format, sender))
-            .unwrap();
+            .send(Op::Serialize(Subset::AllKeyed, self.format, sender))
+            .map_err(|_| disconnected())?;
+        Ok(FinalSnapshot {
+            plain: receiver.recv().map_err(|_| disconnected())?,
+            keyed: receiver.recv().map_err(|_| disconnected())?,
+        })
+    }
+
+    ///
+    /// Send to the telemetry thread all the values accumulated by all
+    /// threads, if the service batches records (see
+    /// `ServiceBuilder::batching`). Values recorded before the call are
+    /// then included in any subsequent serialization.
+    ///
+    /// Without batching, this is a noop.
+    ///
+    pub fn flush(&self) {
+        if let Some(ref batching) = self.batching {
+            batching.flush_all();
+        }
+    }
+
+    ///
+    /// Describe all the histograms registered with this service, plain
+    /// histograms first, each in order of registration.
+    ///
+    /// This waits until the telemetry thread has processed all pending
+    /// operations. If the thread is not running anymore, the list is
+    /// empty.
+    ///
+    pub fn list(&self) -> Vec<HistogramInfo> {
+        let (sender, receiver) = channel();
+        if self.sender.send(Op::List(sender)).is_err() {
+            return Vec::new();
+        }
+        let mut list = receiver.recv().unwrap_or_default();
+        let registry = self.registry.lock().unwrap();
+        for info in &mut list {
+            if let Some(registered) = registry.histograms.get(&info.name) {
+                info.disabled = registered.state.load(Ordering::Relaxed) & STATE_DISABLED != 0;
+            }
+        }
+        list
     }
 
     ///
@@ -68,94 +270,926 @@ impl Service {
     /// Any data recorded on a histogram while the service is inactive will be ignored.
     ///
     pub fn set_active(&self, value: bool) {
-        self.is_active.store(value, Ordering::Relaxed);
+        let mut registry = self.registry.lock().unwrap();
+        registry.is_active = value;
+        registry.update();
     }
 
     pub fn is_active(&self) -> bool {
-        self.is_active.load(Ordering::Relaxed)
+        self.registry.lock().unwrap().is_active
     }
 
     ///
-    /// Register a plain histogram, returning a fresh key.
+    /// Give or revoke the consent of the user to the collection of data
+    /// of a category (see `Definition::with_category`).
     ///
-    fn register_plain(&self, name: String, storage: Box<dyn PlainRawStorage>) -> Key<Plain> {
-        let key = self.keys_plain.next();
-        let named = NamedStorage {
-            name,
-            contents: storage,
+    /// Without consent, histograms of this category ignore all records
+    /// and are not serialized. Revoking consent also purges the data
+    /// recorded so far by these histograms, including values not yet
+    /// sent by batching threads.
+    ///
+    /// By default, the user consents to all categories, see
+    /// `ServiceBuilder::consent`.
+    ///
+    pub fn set_consent(&self, category: Category, consented: bool) {
+        // Changes of consent reach the telemetry thread in the order in
+        // which they are applied to the registry.
+        let _guard = self.consent.lock().unwrap();
+        {
+            let mut registry = self.registry.lock().unwrap();
+            if consented {
+                registry.consented.insert(category);
+            } else {
+                registry.consented.remove(&category);
+            }
+            registry.update();
+        }
+        // Values accumulated before the change are sent first, so that
+        // they are purged if necessary.
+        self.flush();
+        let _ = self.sender.send(Op::SetConsent(category, consented));
+    }
+
+    ///
+    /// `true` if the user consents to the collection of data of a
+    /// category.
+    ///
+    pub fn has_consent(&self, category: Category) -> bool {
+        self.registry.lock().unwrap().consented.contains(&category)
+    }
+
+    ///
+    /// Enable or disable the histograms whose full name matches a
+    /// pattern, in which `*` matches any sequence of characters and `?`
+    /// any single character, e.g. `"network.*"`. A name without
+    /// wildcards designates a single histogram.
+    ///
+    /// This also applies to histograms registered later. If several
+    /// calls concern the same histogram, the latest one wins. Values
+    /// recorded on a disabled histogram are ignored, but the data
+    /// recorded so far is still serialized.
+    ///
+    /// Histograms are enabled by default.
+    ///
+    pub fn set_enabled(&self, pattern: &str, enabled: bool) {
+        let mut registry = self.registry.lock().unwrap();
+        // Only the latest rule for a pattern matters.
+        registry.rules.retain(|rule| rule.0 != pattern);
+        registry.rules.push((pattern.to_string(), enabled));
+        registry.update();
+    }
+
+    ///
+    /// Replace the rules set with `set_enabled` with the contents of the
+    /// configuration file (see `ServiceBuilder::config_path`).
+    ///
+    /// The file is a Json object `{"disabled": [...], "enabled": [...]}`,
+    /// in which both fields are optional arrays of patterns, as accepted
+    /// by `set_enabled`. A histogram is disabled if its name matches any
+    /// pattern of `disabled` and no pattern of `enabled`.
+    ///
+    /// # Errors
+    ///
+    /// If no configuration path was configured or the file cannot be
+    /// read or parsed. In this case, the rules are unchanged.
+    ///
+    pub fn reload_config(&self) -> io::Result<()> {
+        let path = match self.config_path {
+            Some(ref path) => path,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "No configuration path configured",
+                ))
+            }
         };
-        self.sender
-            .send(Op::RegisterPlain(key.index, named))
-            .unwrap();
-        key
+        let mut source = String::new();
+        File::open(path)?.read_to_string(&mut source)?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid configuration");
+        let json = Json::from_str(&source).map_err(|_| invalid())?;
+        if !json.is_object() {
+            return Err(invalid());
+        }
+        let mut rules = Vec::new();
+        // Rules are applied in order, so `enabled` overrides `disabled`.
+        for &(field, enabled) in &[("disabled", false), ("enabled", true)] {
+            let patterns = match json.find(field) {
+                None => continue,
+                Some(patterns) => patterns.as_array().ok_or_else(invalid)?,
+            };
+            for pattern in patterns {
+                let pattern = pattern.as_string().ok_or_else(invalid)?;
+                rules.push((pattern.to_string(), enabled));
+            }
+        }
+        let mut registry = self.registry.lock().unwrap();
+        registry.rules = rules;
+        registry.update();
+        Ok(())
+    }
+
+    ///
+    /// The number of values dropped so far because the queue of the
+    /// telemetry thread was full.
+    ///
+    /// This is always 0 unless the service was built with a
+    /// `channel_capacity` and `OverflowPolicy::Drop`. In that case, the
+    /// number is also serialized with plain histograms, as field
+    /// `dropped_samples` of an object called `__telemetry__`.
+    ///
+    pub fn dropped_samples(&self) -> usize {
+        match self.sender {
+            OpSender::Bounded(_, OverflowPolicy::Drop, ref dropped) => {
+                dropped.load(Ordering::Relaxed)
+            }
+            _ => 0,
+        }
+    }
+
+    ///
+    /// The name of a histogram registered through this handle.
+    ///
+    fn full_name(&self, name: &str) -> String {
+        let mut full_name = String::new();
+        for component in &self.namespace {
+            full_name.push_str(component);
+            full_name.push('.');
+        }
+        full_name.push_str(name);
+        full_name
     }
 
     ///
-    /// Register a keyed histogram, returning a fresh key.
+    /// Register a plain histogram, returning its back-end.
     ///
-    fn register_keyed<T>(&self, name: String, storage: Box<dyn KeyedRawStorage>) -> Key<Keyed<T>> {
+    fn register_plain(
+        &self,
+        definition: Definition,
+        storage: Box<dyn PlainRawStorage>,
+    ) -> BackEnd<Plain> {
+        let key = self.keys_plain.next();
+        let kind = storage.kind();
+        // Keep the registry locked until the storage has been sent, so
+        // that it is not missed by a concurrent `set_consent`.
+        let mut registry = self.registry.lock().unwrap();
+        match self.named(&mut registry, definition, storage, kind) {
+            Ok((named, state, sampler)) => {
+                // If the telemetry thread is gone, the histogram simply records nothing.
+                let _ = self
+                    .sender
+                    .send(Op::RegisterPlain(key.index, Box::new(named)));
+                BackEnd::new(self, key, state, sampler)
+            }
+            Err(state) => BackEnd::new(self, key, state, None),
+        }
+    }
+
+    ///
+    /// Register a keyed histogram, returning its back-end.
+    ///
+    fn register_keyed<T>(
+        &self,
+        definition: Definition,
+        storage: Box<dyn KeyedRawStorage>,
+    ) -> BackEnd<Keyed<T>> {
         let key = self.keys_keyed.next();
+        let kind = storage.kind();
+        let mut interned_keys = match (self.max_keys, storage.interned_keys()) {
+            (Some(max), Some(interned)) => Some(max.min(interned)),
+            (max, interned) => max.or(interned),
+        };
+        // Keys rejected or rewritten by the policies would otherwise be
+        // interned forever, along with the raw strings.
+        if definition.has_key_policies() {
+            interned_keys = Some(
+                interned_keys.map_or(MAX_INTERNED_KEYS_WITH_POLICIES, |interned| {
+                    interned.min(MAX_INTERNED_KEYS_WITH_POLICIES)
+                }),
+            );
+        }
+        // Keep the registry locked until the storage has been sent, so
+        // that it is not missed by a concurrent `set_consent`.
+        let mut registry = self.registry.lock().unwrap();
+        match self.named(&mut registry, definition, storage, kind) {
+            Ok((named, state, sampler)) => {
+                // If the telemetry thread is gone, the histogram simply records nothing.
+                let _ = self
+                    .sender
+                    .send(Op::RegisterKeyed(key.index, Box::new(named)));
+                let mut back_end = BackEnd::new(self, key, state, sampler);
+                back_end.data = Arc::new(Interner::new(interned_keys));
+                back_end
+            }
+            Err(state) => BackEnd::new(self, key, state, None),
+        }
+    }
+
+    ///
+    /// Draw a seed for the random number generator of a histogram.
+    ///
+    fn next_seed(&self) -> u64 {
+        self.registry.lock().unwrap().rng.next_u64()
+    }
+
+    ///
+    /// Add a histogram to the registry, returning its storage, as
+    /// expected by the telemetry thread, and its state and sampler, as
+    /// expected by `BackEnd::new`.
+    ///
+    /// If the name of the histogram may not be used, report it to the
+    /// telemetry thread and return a state in which the histogram
+    /// records nothing.
+    ///
+    fn named<T: ?Sized>(
+        &self,
+        registry: &mut Registry,
+        mut definition: Definition,
+        storage: Box<T>,
+        kind: Kind,
+    ) -> Result<Registration<T>, Arc<AtomicUsize>> {
+        let name = self.full_name(definition.name());
+        if !registry.reserve(&name, &self.namespace) {
+            let _ = self.sender.send(Op::RejectName(name));
+            return Err(Arc::new(AtomicUsize::new(STATE_REJECTED)));
+        }
+        let category = definition.category();
+        let expired = definition.is_expired(self.app_version.as_ref());
+        let sampling = definition.sampling();
+        let mut sampler = None;
+        let mut sampled_out = false;
+        match sampling {
+            // A rate of 1 keeps everything, no need to draw numbers.
+            Some(Sampling::PerRecord(rate)) if rate < 1. => {
+                sampler = Some(Arc::new(Sampler::new(rate, registry.rng.next_u64())));
+            }
+            Some(Sampling::PerClient(rate)) => {
+                sampled_out = registry.rng.next_f64() >= rate;
+            }
+            _ => {}
+        }
+        let state = registry.register(&name, category, expired, sampled_out);
         let named = NamedStorage {
             name,
+            namespace: self.namespace.clone(),
+            expired,
+            category,
+            kind,
+            consented: registry.consented.contains(&category),
+            poisoned: false,
+            sampling,
+            sampled_out,
+            key_policies: definition.take_key_policies(),
+            rejected_keys: 0,
+            keys: Vec::new(),
             contents: storage,
         };
-        self.sender
-            .send(Op::RegisterKeyed(key.index, named))
-            .unwrap();
-        key
+        Ok((named, state, sampler))
     }
 }
 
 /// Upon death of the service, terminate the thread and recollect all
 /// owned memory.
+///
+/// This neither waits for the thread nor runs the shutdown hooks, use
+/// `Service::shutdown` for that.
+///
+/// Dropping a handle obtained through `Service::scope` only sends the
+/// values accumulated by batching threads.
 impl Drop for Service {
     fn drop(&mut self) {
-        let _ = self.sender.send(Op::Terminate);
+        self.flush();
+        if self.thread.is_some() {
+            let _ = self.sender.send(Op::Terminate);
+        }
     }
 }
 
 pub struct Service {
     /// A key generator for registration of new plain histograms. Uses
-    /// atomic to avoid the use of &mut.
-    keys_plain: KeyGenerator<Plain>,
+    /// atomic to avoid the use of &mut. Shared with scopes.
+    keys_plain: Arc<KeyGenerator<Plain>>,
 
     /// A key generator for registration of new keyed histograms. Uses
-    /// atomic to avoid the use of &mut.
-    keys_keyed: KeyGenerator<Map>,
+    /// atomic to avoid the use of &mut. Shared with scopes.
+    keys_keyed: Arc<KeyGenerator<Map>>,
 
-    /// A shared boolean that may be turned on/off to (de)activate
-    /// Telemetry.
-    is_active: Arc<AtomicBool>,
+    /// Whether the service is active, whether each histogram is
+    /// enabled, etc. Shared with scopes.
+    registry: Arc<Mutex<Registry>>,
+
+    /// Held by `set_consent` while it sends a change of consent to the
+    /// telemetry thread. Shared with scopes.
+    consent: Arc<Mutex<()>>,
 
     /// Connection to the thread holding all the storage of this
     /// instance of the service.
-    sender: Sender<Op>,
+    sender: OpSender,
+
+    /// If records are batched, the batching configuration.
+    batching: Option<Arc<Batching>>,
+
+    /// The maximal number of keys of each keyed histogram, if any.
+    /// Keyed histograms intern at most this number of keys.
+    max_keys: Option<usize>,
+
+    /// The source of time for all histograms of this service.
+    clock: Arc<dyn Clock>,
+
+    /// The version of the application, used to determine whether
+    /// histograms have expired.
+    app_version: Option<Version>,
+
+    /// Where `persist` writes the data.
+    persistence_path: Option<PathBuf>,
+
+    /// Where `reload_config` reads the configuration.
+    config_path: Option<PathBuf>,
+
+    /// The format used by `serialize` and `persist`.
+    format: SerializationFormat,
+
+    /// The namespace in which histograms are registered, outermost
+    /// first. Empty unless this is a handle obtained through `scope`.
+    namespace: Vec<String>,
+
+    /// The telemetry thread, until it is joined by `shutdown`. Always
+    /// `None` for a handle obtained through `scope`.
+    thread: Option<JoinHandle<()>>,
+
+    /// If `true`, `shutdown` writes the data to `persistence_path`.
+    persist_on_shutdown: bool,
+
+    /// Callbacks executed by `shutdown`.
+    shutdown_hooks: Mutex<Vec<ShutdownHook>>,
+}
+
+///
+/// The state of all the histograms of a service.
+///
+impl Registry {
+    ///
+    /// Reserve the name of a new histogram, registered in `namespace`,
+    /// and, with `Layout::Nested`, its namespaces.
+    ///
+    /// Returns `false` if the name is reserved, if it is already used
+    /// or, with `Layout::Nested`, if it conflicts with a namespace.
+    ///
+    fn reserve(&mut self, name: &str, namespace: &[String]) -> bool {
+        if name == SELF_TELEMETRY || self.histograms.contains_key(name) {
+            return false;
+        }
+        if self.layout == Layout::Nested {
+            let namespaces: Vec<String> = (1..namespace.len() + 1)
+                .map(|depth| namespace[..depth].join("."))
+                .collect();
+            let conflict = self.namespaces.contains(name)
+                || namespaces
+                    .iter()
+                    .any(|ns| ns == SELF_TELEMETRY || self.histograms.contains_key(ns));
+            if conflict {
+                return false;
+            }
+            self.namespaces.extend(namespaces);
+        }
+        true
+    }
+
+    ///
+    /// Register a histogram, returning its state, as expected by
+    /// `BackEnd::new`.
+    ///
+    fn register(
+        &mut self,
+        name: &str,
+        category: Category,
+        expired: bool,
+        sampled_out: bool,
+    ) -> Arc<AtomicUsize> {
+        let registered = Registered {
+            category,
+            expired,
+            sampled_out,
+            state: Arc::new(AtomicUsize::new(0)),
+        };
+        let state = registered.state.clone();
+        state.store(self.state_of(name, &registered), Ordering::Relaxed);
+        self.histograms.insert(name.to_string(), registered);
+        state
+    }
+
+    ///
+    /// Update the state of all histograms after a change of activity,
+    /// rules or consent.
+    ///
+    fn update(&self) {
+        for (name, registered) in &self.histograms {
+            let state = self.state_of(name, registered);
+            registered.state.store(state, Ordering::Relaxed);
+        }
+    }
+
+    fn state_of(&self, name: &str, registered: &Registered) -> usize {
+        let mut state = 0;
+        if !self.is_active {
+            state |= STATE_INACTIVE;
+        }
+        if !self.consented.contains(&registered.category) {
+            state |= STATE_NO_CONSENT;
+        }
+        let enabled = self
+            .rules
+            .iter()
+            .rev()
+            .find(|rule| glob_matches(&rule.0, name))
+            .map_or(true, |rule| rule.1);
+        if !enabled {
+            state |= STATE_DISABLED;
+        }
+        if registered.expired {
+            state |= STATE_EXPIRED;
+        }
+        if registered.sampled_out {
+            state |= STATE_SAMPLED_OUT;
+        }
+        state
+    }
+}
+
+struct Registry {
+    /// `true` if the service is active, see `Service::set_active`.
+    is_active: bool,
+
+    /// The rules set by `Service::set_enabled` and
+    /// `Service::reload_config`, as `(pattern, enabled)`, oldest first.
+    rules: Vec<(String, bool)>,
+
+    /// The categories to which the user consents, see
+    /// `Service::set_consent`.
+    consented: HashSet<Category>,
+
+    /// All histograms, by full name.
+    histograms: HashMap<String, Registered>,
+
+    /// How histograms are grouped by namespace when serialized.
+    layout: Layout,
+
+    /// The full names of all namespaces, e.g. `network.http`, with
+    /// `Layout::Nested` only.
+    namespaces: HashSet<String>,
+
+    /// The source of randomness for sampling, see `ServiceBuilder::seed`.
+    rng: Rng,
+}
+
+/// A histogram, as known to the registry.
+struct Registered {
+    category: Category,
+    expired: bool,
+    sampled_out: bool,
+
+    /// The state shared with the back-end of the histogram.
+    state: Arc<AtomicUsize>,
+}
+
+/// A registered histogram, as returned by `Service::named`: its storage,
+/// its state and its sampler.
+type Registration<T> = (NamedStorage<T>, Arc<AtomicUsize>, Option<Arc<Sampler>>);
+
+/// A callback executed by `Service::shutdown`.
+type ShutdownHook = Box<dyn FnOnce(&FinalSnapshot) -> io::Result<()> + Send>;
+
+///
+/// The data held by a service when it was shut down, in the default
+/// format of the service.
+///
+impl FinalSnapshot {
+    ///
+    /// The data as an object `{ plain: ..., keyed: ... }`, as written
+    /// by `Service::persist`.
+    ///
+    pub fn to_json(&self) -> Json {
+        let mut payload = BTreeMap::new();
+        payload.insert("plain".to_string(), self.plain.clone());
+        payload.insert("keyed".to_string(), self.keyed.clone());
+        Json::Object(payload)
+    }
+
+    fn write_to(&self, path: &PathBuf) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        file.write_all(format!("{}\n", self.to_json()).as_bytes())
+    }
+}
+
+pub struct FinalSnapshot {
+    /// All plain histograms, as serialized with `Subset::AllPlain`.
+    pub plain: Json,
+
+    /// All keyed histograms, as serialized with `Subset::AllKeyed`.
+    pub keyed: Json,
+}
+
+///
+/// The result of `Service::shutdown`, once the telemetry thread has
+/// stopped.
+///
+pub struct ShutdownOutcome {
+    /// The data held by the service.
+    pub snapshot: FinalSnapshot,
+
+    /// The errors of the shutdown hooks that have failed, in the order
+    /// in which the hooks were executed.
+    pub hook_errors: Vec<io::Error>,
+}
+
+///
+/// A builder for `Service`, for applications that need more control
+/// than offered by `Service::new`.
+///
+impl ServiceBuilder {
+    ///
+    /// Create a builder with the default configuration: the service
+    /// is inactive, uses the clock of the operating system, an
+    /// unbounded queue and a thread called `telemetry`, doesn't batch
+    /// records, has no limit on keys, no application version, no
+    /// persistence path, and serializes to
+    /// `SerializationFormat::SimpleJson`.
+    ///
+    pub fn new() -> ServiceBuilder {
+        ServiceBuilder {
+            is_active: false,
+            clock: Arc::new(SystemClock::new()),
+            channel_capacity: None,
+            overflow_policy: OverflowPolicy::Block,
+            thread_name: "telemetry".to_string(),
+            stack_size: None,
+            batching: None,
+            max_keys: None,
+            app_version: None,
+            persistence_path: None,
+            config_path: None,
+            consented: Category::ALL.to_vec(),
+            format: SerializationFormat::SimpleJson,
+            persist_on_shutdown: false,
+            shutdown_hooks: Vec::new(),
+            layout: Layout::Flat,
+            seed: None,
+        }
+    }
+
+    ///
+    /// Determine whether the service should accept records
+    /// immediately, as `set_active`.
+    ///
+    pub fn active(mut self, is_active: bool) -> ServiceBuilder {
+        self.is_active = is_active;
+        self
+    }
+
+    ///
+    /// Use a specific clock for all time-dependent histograms, e.g.
+    /// a `MockClock` for testing.
+    ///
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> ServiceBuilder {
+        self.clock = clock;
+        self
+    }
+
+    ///
+    /// Bound the number of operations waiting to be processed by the
+    /// telemetry thread. By default, the queue is unbounded, so memory
+    /// may grow without limit if the thread falls behind.
+    ///
+    /// What happens to records while the queue is full depends on the
+    /// `overflow_policy`. Other operations (registration,
+    /// serialization, ...) always block.
+    ///
+    pub fn channel_capacity(mut self, capacity: usize) -> ServiceBuilder {
+        self.channel_capacity = Some(capacity);
+        self
+    }
+
+    ///
+    /// Determine what happens when recording a value while the queue is
+    /// full. Only meaningful with `channel_capacity`. Defaults to
+    /// `OverflowPolicy::Block`.
+    ///
+    /// With `OverflowPolicy::Drop`, the number of dropped values is
+    /// available through `Service::dropped_samples` and serialized with
+    /// plain histograms.
+    ///
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> ServiceBuilder {
+        self.overflow_policy = policy;
+        self
+    }
+
+    ///
+    /// Set the name of the telemetry thread.
+    ///
+    pub fn thread_name(mut self, name: String) -> ServiceBuilder {
+        self.thread_name = name;
+        self
+    }
+
+    ///
+    /// Set the stack size of the telemetry thread, in bytes.
+    ///
+    pub fn stack_size(mut self, size: usize) -> ServiceBuilder {
+        self.stack_size = Some(size);
+        self
+    }
+
+    ///
+    /// Batch records in thread-local accumulators, to reduce the
+    /// number of operations sent to the telemetry thread.
+    ///
+    /// Each thread merges the values it records and sends them once
+    /// it has recorded `max_samples` values, when it records a value
+    /// at least `period` after its latest batch was sent, upon
+    /// `Service::flush` and when the thread exits. There is no timer:
+    /// a thread that stops recording keeps its values until it is
+    /// flushed or exits. Until then, values are not serialized, so call
+    /// `Service::flush` before serializing.
+    ///
+    /// Counts saturate at `u32::MAX` instead of overflowing.
+    ///
+    pub fn batching(mut self, max_samples: usize, period: Duration) -> ServiceBuilder {
+        self.batching = Some((max_samples, period));
+        self
+    }
+
+    ///
+    /// Limit the number of keys of each keyed histogram. Once a keyed
+    /// histogram holds `max` keys, values recorded for new keys are
+    /// ignored.
+    ///
+    pub fn max_keys(mut self, max: usize) -> ServiceBuilder {
+        self.max_keys = Some(max);
+        self
+    }
+
+    ///
+    /// Set the version of the application, e.g. `"1.4.2"`. Histograms
+    /// defined as expiring with this version or an earlier one (see
+    /// `Definition::expires`) ignore all records and are not
+    /// serialized.
+    ///
+    pub fn app_version(mut self, version: &str) -> ServiceBuilder {
+        self.app_version = Some(Version::parse(version));
+        self
+    }
+
+    ///
+    /// Set the file to which `Service::persist` writes the data.
+    ///
+    pub fn persistence_path(mut self, path: PathBuf) -> ServiceBuilder {
+        self.persistence_path = Some(path);
+        self
+    }
+
+    ///
+    /// Make `Service::shutdown` write the final data to the
+    /// persistence path, if any, before running the other hooks.
+    ///
+    pub fn persist_on_shutdown(mut self) -> ServiceBuilder {
+        self.persist_on_shutdown = true;
+        self
+    }
+
+    ///
+    /// Add a callback executed by `Service::shutdown` with the final
+    /// data, e.g. to enqueue an upload. Hooks run in the order in which
+    /// they were added, after the telemetry thread has stopped.
+    ///
+    pub fn on_shutdown<F>(mut self, hook: F) -> ServiceBuilder
+    where
+        F: FnOnce(&FinalSnapshot) -> io::Result<()> + Send + 'static,
+    {
+        self.shutdown_hooks.push(Box::new(hook));
+        self
+    }
+
+    ///
+    /// Determine how histograms registered through `Service::scope`
+    /// are laid out when serialized. Defaults to `Layout::Flat`.
+    ///
+    pub fn layout(mut self, layout: Layout) -> ServiceBuilder {
+        self.layout = layout;
+        self
+    }
+
+    ///
+    /// Set the categories of data to which the user initially
+    /// consents, see `Service::set_consent`. By default, the user
+    /// consents to all categories.
+    ///
+    pub fn consent(mut self, categories: &[Category]) -> ServiceBuilder {
+        self.consented = categories.to_vec();
+        self
+    }
+
+    ///
+    /// Seed the random number generator used for sampling (see
+    /// `Definition::with_sampling`), e.g. to make tests reproducible. By
+    /// default, the generator is seeded from the current time.
+    ///
+    pub fn seed(mut self, seed: u64) -> ServiceBuilder {
+        self.seed = Some(seed);
+        self
+    }
+
+    ///
+    /// Set the file from which `Service::reload_config` reads which
+    /// histograms are enabled. The file is not read until then.
+    ///
+    pub fn config_path(mut self, path: PathBuf) -> ServiceBuilder {
+        self.config_path = Some(path);
+        self
+    }
+
+    ///
+    /// Set the format used by `Service::serialize` and `Service::persist`.
+    ///
+    pub fn format(mut self, format: SerializationFormat) -> ServiceBuilder {
+        self.format = format;
+        self
+    }
+
+    ///
+    /// Create the service.
+    ///
+    /// This immediately launches the thread owning the data.
+    ///
+    /// # Errors
+    ///
+    /// If the thread cannot be launched.
+    ///
+    pub fn build(self) -> io::Result<Service> {
+        let (sender, receiver, dropped) = match self.channel_capacity {
+            None => {
+                let (sender, receiver) = channel();
+                (OpSender::Unbounded(sender), receiver, None)
+            }
+            Some(capacity) => {
+                let (sender, receiver) = sync_channel(capacity);
+                let dropped = Arc::new(AtomicUsize::new(0));
+                // Only report drops if drops may happen.
+                let reported = match self.overflow_policy {
+                    OverflowPolicy::Drop => Some(dropped.clone()),
+                    OverflowPolicy::Block => None,
+                };
+                (
+                    OpSender::Bounded(sender, self.overflow_policy, dropped),
+                    receiver,
+                    reported,
+                )
+            }
+        };
+        let batching = self.batching.map(|(max_samples, period)| {
+            Arc::new(Batching::new(
+                sender.clone(),
+                self.clock.clone(),
+                max_samples,
+                period,
+            ))
+        });
+        let mut thread = thread::Builder::new().name(self.thread_name);
+        if let Some(size) = self.stack_size {
+            thread = thread.stack_size(size);
+        }
+        let max_keys = self.max_keys;
+        let layout = self.layout;
+        let seed = self.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs() ^ (u64::from(elapsed.subsec_nanos()) << 32))
+                .unwrap_or(0)
+                ^ u64::from(std::process::id())
+        });
+        let thread = thread.spawn(move || {
+            let mut task = TelemetryTask::new(receiver, max_keys, dropped, layout);
+            task.run()
+        })?;
+        Ok(Service {
+            keys_plain: Arc::new(KeyGenerator::new()),
+            keys_keyed: Arc::new(KeyGenerator::new()),
+            sender,
+            batching,
+            max_keys,
+            registry: Arc::new(Mutex::new(Registry {
+                is_active: self.is_active,
+                rules: Vec::new(),
+                consented: self.consented.iter().cloned().collect(),
+                histograms: HashMap::new(),
+                layout,
+                namespaces: HashSet::new(),
+                rng: Rng::new(seed),
+            })),
+            consent: Arc::new(Mutex::new(())),
+            clock: self.clock,
+            app_version: self.app_version,
+            persistence_path: self.persistence_path,
+            config_path: self.config_path,
+            format: self.format,
+            namespace: Vec::new(),
+            thread: Some(thread),
+            persist_on_shutdown: self.persist_on_shutdown,
+            shutdown_hooks: Mutex::new(self.shutdown_hooks),
+        })
+    }
+}
+
+impl Default for ServiceBuilder {
+    fn default() -> Self {
+        ServiceBuilder::new()
+    }
+}
+
+pub struct ServiceBuilder {
+    /// Whether the service is initially active.
+    is_active: bool,
+
+    /// The source of time for all histograms of the service.
+    clock: Arc<dyn Clock>,
+
+    /// The capacity of the queue, or `None` for an unbounded queue.
+    channel_capacity: Option<usize>,
+
+    /// What to do with records when the queue is full.
+    overflow_policy: OverflowPolicy,
+
+    /// The name of the telemetry thread.
+    thread_name: String,
+
+    /// The stack size of the telemetry thread, or `None` for the default.
+    stack_size: Option<usize>,
+
+    /// The maximal number of samples and the period of batches, if
+    /// records are batched.
+    batching: Option<(usize, Duration)>,
+
+    /// The maximal number of keys of each keyed histogram, if any.
+    max_keys: Option<usize>,
+
+    /// The version of the application, if known.
+    app_version: Option<Version>,
+
+    /// Where `Service::persist` writes the data.
+    persistence_path: Option<PathBuf>,
+
+    /// Where `Service::reload_config` reads the configuration.
+    config_path: Option<PathBuf>,
+
+    /// The categories to which the user initially consents.
+    consented: Vec<Category>,
+
+    /// The format used by `Service::serialize` and `Service::persist`.
+    format: SerializationFormat,
+
+    /// Whether `Service::shutdown` writes to the persistence path.
+    persist_on_shutdown: bool,
+
+    /// Callbacks executed by `Service::shutdown`.
+    shutdown_hooks: Vec<ShutdownHook>,
+
+    /// How histograms are grouped by namespace when serialized.
+    layout: Layout,
+
+    /// The seed of the random number generator used for sampling.
+    seed: Option<u64>,
 }
 
 // Backstage pass used inside the crate.
 impl PrivateAccess {
     pub fn register_plain(
         service: &Service,
-        name: String,
+        definition: Definition,
         storage: Box<dyn PlainRawStorage>,
-    ) -> Key<Plain> {
-        service.register_plain(name, storage)
+    ) -> BackEnd<Plain> {
+        service.register_plain(definition, storage)
     }
 
     pub fn register_keyed<T>(
         service: &Service,
-        name: String,
+        definition: Definition,
         storage: Box<dyn KeyedRawStorage>,
-    ) -> Key<Keyed<T>> {
-        service.register_keyed(name, storage)
+    ) -> BackEnd<Keyed<T>> {
+        service.register_keyed(definition, storage)
     }
 
-    pub fn get_sender(service: &Service) -> &Sender<Op> {
+    pub fn get_sender(service: &Service) -> &OpSender {
         &service.sender
     }
 
-    pub fn get_is_active(service: &Service) -> &Arc<AtomicBool> {
-        &service.is_active
+    pub fn get_batching(service: &Service) -> &Option<Arc<Batching>> {
+        &service.batching
+    }
+
+    pub fn get_clock(service: &Service) -> &Arc<dyn Clock> {
+        &service.clock
+    }
+
+    pub fn next_seed(service: &Service) -> u64 {
+        service.next_seed()
     }
 }
 
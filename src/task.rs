@@ -12,120 +12,724 @@ use self::vec_map::VecMap;
 extern crate rustc_serialize;
 use self::rustc_serialize::json::Json;
 
-use std::collections::{BTreeMap, HashSet};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{Receiver, Sender};
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, SendError, Sender, SyncSender, TrySendError};
 use std::sync::Arc;
 
-use indexing::Key;
+use batch::Batching;
+use indexing::{Key, Witness};
 use misc::*;
 use service::{PrivateAccess, Service};
 
+///
+/// Access to a storage as `Any`, so that a histogram can downcast
+/// its own storage when querying it through `Op::QueryPlain` or
+/// `Op::QueryKeyed`.
+///
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
 ///
 /// Low-level, untyped, implementation of plain histogram storage.
 ///
-pub trait PlainRawStorage: Send {
+pub trait PlainRawStorage: Send + AsAny {
     fn store(&mut self, value: u32);
+
+    /// Store a value along with a label. By default, the label is ignored.
+    fn store_labeled(&mut self, value: u32, _label: String) {
+        self.store(value)
+    }
+
+    /// Store the same value `times` times.
+    fn store_n(&mut self, value: u32, times: u32) {
+        for _ in 0..times {
+            self.store(value)
+        }
+    }
+
+    /// Store a value in a slot, for histograms whose records carry
+    /// both, e.g. the index of a label and a count. Values recorded in
+    /// the same slot may be summed before being stored, e.g. by
+    /// batching. By default, the record is ignored.
+    fn store_at(&mut self, _slot: u32, _value: u32) {}
+
     fn to_json(&self, format: &SerializationFormat) -> Json;
+
+    /// Forget all the values stored so far.
+    fn reset(&mut self);
+
+    /// The kind of the histogram, for introspection.
+    fn kind(&self) -> Kind;
 }
 
 ///
 /// Low-level, untyped, implementation of keyed histogram storage.
 ///
-pub trait KeyedRawStorage: Send {
-    fn store(&mut self, key: String, value: u32);
+pub trait KeyedRawStorage: Send + AsAny {
+    fn store(&mut self, key: &str, value: u32);
     fn to_json(&self, format: &SerializationFormat) -> Json;
+
+    /// Forget all the values stored so far.
+    fn reset(&mut self);
+
+    /// The kind of the histogram, for introspection.
+    fn kind(&self) -> Kind;
+
+    /// Store the same value with the same key `times` times.
+    fn store_n(&mut self, key: &str, value: u32, times: u32) {
+        for _ in 0..times {
+            self.store(key, value)
+        }
+    }
+
+    /// The number of keys currently stored.
+    fn key_count(&self) -> usize;
+
+    /// `true` if storing a value with key `key` would require
+    /// storing one more key.
+    fn is_new_key(&self, key: &str) -> bool;
+
+    /// The number of distinct user keys that clients should intern, if
+    /// bounded, e.g. because the histogram stores a bounded number of
+    /// keys. Other keys are sent as strings along with each record.
+    fn interned_keys(&self) -> Option<usize> {
+        None
+    }
+
+    /// Store the same value with keys `(key1, key2)` `times` times, in
+    /// a histogram with two dimensions of keys. Returns `false` if the
+    /// keys exceed the limits of the histogram.
+    ///
+    /// By default, histograms have a single dimension and reject all keys.
+    fn store2(&mut self, _key1: &str, _key2: &str, _value: u32, _times: u32) -> bool {
+        false
+    }
 }
 
+/// The name under which data about telemetry itself is serialized,
+/// alongside plain histograms.
+pub const SELF_TELEMETRY: &str = "__telemetry__";
+
+/// A callback run on the Telemetry Task, see `Op::QueryPlain`.
+pub type PlainQuery = Box<dyn FnOnce(&dyn PlainRawStorage) + Send>;
+
+/// A callback run on the Telemetry Task, see `Op::QueryKeyed`.
+pub type KeyedQuery = Box<dyn FnOnce(&dyn KeyedRawStorage) + Send>;
+
 /// Operations used to communicate with the TelemetryTask.
 pub enum Op {
-    /// `RegisterPlain(key, storage)` returns a plain histogram with
-    /// key `key`. The key must be previously unused, otherwise panic.
-    /// Unicity of the key is enforced through the use of a
-    /// [KeyGenerator](../misc/struct.KeyGenerator.html).
-    RegisterPlain(usize, NamedStorage<dyn PlainRawStorage>),
-
-    /// `RegisterPlain(key, storage)` returns a plain histogram with
-    /// key `key`. The key must be previously unused, otherwise panic.
-    /// Unicity of the key is enforced through the use of a
-    /// [KeyGenerator](../misc/struct.KeyGenerator.html).
-    RegisterKeyed(usize, NamedStorage<dyn KeyedRawStorage>),
+    /// `RegisterPlain(key, storage)` registers a plain histogram with
+    /// key `key`. The key must be previously unused. Unicity of the
+    /// key is enforced through the use of a
+    /// [KeyGenerator](../misc/struct.KeyGenerator.html). Unicity of
+    /// the name is checked by the service.
+    RegisterPlain(usize, Box<NamedStorage<dyn PlainRawStorage>>),
+
+    /// `RegisterKeyed(key, storage)` registers a keyed histogram with
+    /// key `key`. The key must be previously unused. Unicity of the
+    /// key is enforced through the use of a
+    /// [KeyGenerator](../misc/struct.KeyGenerator.html). Unicity of
+    /// the name is checked by the service.
+    RegisterKeyed(usize, Box<NamedStorage<dyn KeyedRawStorage>>),
+
+    /// `RejectName(name)` reports that a histogram could not be
+    /// registered with name `name`, e.g. because the name was already
+    /// used, so that this is serialized with data about telemetry itself.
+    RejectName(String),
 
     /// `RecordPlain(key, value)` records value `value` in the plain
-    /// histogram registered with key `key`.` The key must be
-    /// registered to a plain histogram, otherwise panic.
+    /// histogram registered with key `key`. If no plain histogram is
+    /// registered with this key, e.g. because its name was rejected,
+    /// the value is ignored.
     RecordPlain(usize, u32),
 
-    /// `RecordKeyed(key, userkey, value)` records value `(userkey,
-    /// value)` in the plain histogram registered with histogram key
-    /// `key`.` The key must be registered to a plain histogram,
-    /// otherwise panic.
-    RecordKeyed(usize, String, u32),
+    /// `RecordPlainAt(key, slot, value)` records value `value` in slot
+    /// `slot` of the plain histogram registered with key `key`, see
+    /// `PlainRawStorage::store_at`. Ignored if no plain histogram is
+    /// registered with this key.
+    RecordPlainAt(usize, u32, u32),
+
+    /// `RecordPlainLabeled(key, value, label)` records value `value`,
+    /// annotated with `label`, in the plain histogram registered with
+    /// key `key`. Ignored if no plain histogram is registered with
+    /// this key.
+    RecordPlainLabeled(usize, u32, String),
+
+    /// `InternKey(key, id, userkey)` declares that user key `userkey`
+    /// is designated by identifier `id` in the keyed histogram
+    /// registered with histogram key `key`. Sent before any record
+    /// using `id`.
+    InternKey(usize, u32, String),
+
+    /// `RecordKeyed(key, id, value)` records value `(userkey, value)`
+    /// in the keyed histogram registered with histogram key `key`,
+    /// where `userkey` is the user key interned as `id`. If no keyed
+    /// histogram is registered with this key, e.g. because its name
+    /// was rejected, the value is ignored.
+    RecordKeyed(usize, u32, u32),
+
+    /// `RecordKeyed2(key, id1, id2, value)` records value `(userkey1,
+    /// userkey2, value)` in the keyed histogram with two dimensions
+    /// registered with histogram key `key`, where `userkey1` and
+    /// `userkey2` are the user keys interned as `id1` and `id2`.
+    /// Ignored if no keyed histogram is registered with this key.
+    RecordKeyed2(usize, u32, u32, u32),
+
+    /// `RecordKeyedRaw(key, userkey, value)` records value `(userkey,
+    /// value)` in the keyed histogram registered with histogram key
+    /// `key`, for user keys that have not been interned, e.g. because
+    /// the histogram interns a bounded number of keys. Ignored if no
+    /// keyed histogram is registered with this key.
+    RecordKeyedRaw(usize, String, u32),
+
+    /// `RecordKeyed2Raw(key, userkey1, userkey2, value)` records value
+    /// `(userkey1, userkey2, value)` in the keyed histogram with two
+    /// dimensions registered with histogram key `key`, for user keys
+    /// that have not both been interned. Ignored if no keyed histogram
+    /// is registered with this key.
+    RecordKeyed2Raw(usize, String, String, u32),
+
+    /// `QueryPlain(key, callback)` runs `callback` on the storage of
+    /// the plain histogram registered with key `key`. If there is no
+    /// such histogram, `callback` is dropped without being run.
+    QueryPlain(usize, PlainQuery),
+
+    /// `QueryKeyed(key, callback)` runs `callback` on the storage of
+    /// the keyed histogram registered with key `key`. If there is no
+    /// such histogram, `callback` is dropped without being run.
+    QueryKeyed(usize, KeyedQuery),
+
+    /// `RecordBatch(plain, plain_at, keyed, keyed2)` records several
+    /// values at once, as `(key, value, times)` for plain histograms,
+    /// `(key, slot, value)` for values recorded in a slot of a plain
+    /// histogram (see `RecordPlainAt`), `(key, id, value, times)` for
+    /// keyed histograms and `(key, id1, id2, value, times)` for keyed
+    /// histograms with two dimensions, where `times` is the number of
+    /// times the value was recorded.
+    RecordBatch(
+        Vec<(usize, u32, u32)>,
+        Vec<(usize, u32, u32)>,
+        Vec<(usize, u32, u32, u32)>,
+        Vec<(usize, u32, u32, u32, u32)>,
+    ),
+
+    /// `SetConsent(category, consented)` determines whether histograms
+    /// of a category record and serialize their data. Revoking consent
+    /// also purges the data of these histograms.
+    SetConsent(Category, bool),
+
+    /// Describe all registered histograms.
+    List(Sender<Vec<HistogramInfo>>),
 
     /// Proceed to serialization in a given format.
     Serialize(Subset, SerializationFormat, Sender<Json>),
 
-    /// Terminate the thread immediately. Any further attempt to
-    /// communicate with the tread will panic.
+    /// Terminate the thread immediately. Any further operation sent to
+    /// the thread is dropped.
     Terminate,
 }
 
+impl Op {
+    /// The number of values recorded by this operation.
+    fn samples(&self) -> usize {
+        match *self {
+            Op::RecordPlain(..)
+            | Op::RecordPlainAt(..)
+            | Op::RecordPlainLabeled(..)
+            | Op::RecordKeyed(..)
+            | Op::RecordKeyed2(..)
+            | Op::RecordKeyedRaw(..)
+            | Op::RecordKeyed2Raw(..) => 1,
+            Op::RecordBatch(ref plain, ref plain_at, ref keyed, ref keyed2) => {
+                // Values summed in the same slot count as a single sample.
+                plain.iter().map(|x| x.2 as usize).sum::<usize>()
+                    + plain_at.len()
+                    + keyed.iter().map(|x| x.3 as usize).sum::<usize>()
+                    + keyed2.iter().map(|x| x.4 as usize).sum::<usize>()
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// A user key of a keyed histogram, as received by the TelemetryTask.
+enum UserKey {
+    /// A key interned with `Op::InternKey`.
+    Interned(u32),
+
+    /// A key sent along with the record, before applying the key policies.
+    Raw(String),
+}
+
+impl UserKey {
+    /// The key after applying the key policies of `storage`, or `None`
+    /// if the key has been rejected.
+    fn resolve(self, storage: &mut NamedStorage<dyn KeyedRawStorage>) -> Option<Arc<str>> {
+        match self {
+            UserKey::Interned(id) => storage.get_key(id),
+            UserKey::Raw(key) => storage.apply_key_policies(key),
+        }
+    }
+}
+
+///
+/// The sending half of the channel used to communicate with the
+/// TelemetryTask.
+///
+#[derive(Clone)]
+pub enum OpSender {
+    /// An unbounded channel.
+    Unbounded(Sender<Op>),
+
+    /// A bounded channel, with the policy used when recording a
+    /// value while it is full and the number of values dropped so far.
+    Bounded(SyncSender<Op>, OverflowPolicy, Arc<AtomicUsize>),
+}
+
+impl OpSender {
+    /// Send an operation, blocking if the channel is bounded and full.
+    pub fn send(&self, op: Op) -> Result<(), SendError<Op>> {
+        match *self {
+            OpSender::Unbounded(ref sender) => sender.send(op),
+            OpSender::Bounded(ref sender, _, _) => sender.send(op),
+        }
+    }
+
+    /// Send an operation recording a value. If the channel is bounded
+    /// and full, this either blocks or drops the operation, depending
+    /// on the policy.
+    pub fn send_record(&self, op: Op) -> Result<(), SendError<Op>> {
+        match *self {
+            OpSender::Bounded(ref sender, OverflowPolicy::Drop, ref dropped) => {
+                match sender.try_send(op) {
+                    Ok(()) => Ok(()),
+                    Err(TrySendError::Full(op)) => {
+                        dropped.fetch_add(op.samples(), Ordering::Relaxed);
+                        Ok(())
+                    }
+                    Err(TrySendError::Disconnected(op)) => Err(SendError(op)),
+                }
+            }
+            _ => self.send(op),
+        }
+    }
+}
+
 ///
 /// The thread responsible for storing, bucketing and serializing data.
 ///
 impl TelemetryTask {
     /// Create a new thread listening on a given channel.
-    pub fn new(receiver: Receiver<Op>) -> TelemetryTask {
+    ///
+    /// If `max_keys` is specified, keyed histograms ignore values for
+    /// new keys once they hold `max_keys` keys.
+    ///
+    /// If `dropped` is specified, it is reported with plain histograms
+    /// as the number of values dropped because the channel was full.
+    ///
+    /// With `Layout::Nested`, histograms are serialized in one object
+    /// per namespace.
+    pub fn new(
+        receiver: Receiver<Op>,
+        max_keys: Option<usize>,
+        dropped: Option<Arc<AtomicUsize>>,
+        layout: Layout,
+    ) -> TelemetryTask {
         TelemetryTask {
             plain: VecMap::new(),
             keyed: VecMap::new(),
             receiver,
-            keys: HashSet::new(),
+            rejected_names: BTreeSet::new(),
+            max_keys,
+            dropped,
+            layout,
+        }
+    }
+
+    /// Add the serialized data of a histogram to an object, as per the layout.
+    fn insert(
+        &self,
+        object: &mut BTreeMap<String, Json>,
+        name: &str,
+        namespace: &[String],
+        json: Json,
+    ) {
+        if self.layout == Layout::Flat || namespace.is_empty() {
+            object.insert(name.to_string(), json);
+            return;
+        }
+        let mut object = object;
+        for component in namespace {
+            let child = object
+                .entry(component.clone())
+                .or_insert_with(|| Json::Object(BTreeMap::new()));
+            object = match *child {
+                Json::Object(ref mut child) => child,
+                // Names conflicting with namespaces are rejected by the
+                // service, so this should not happen.
+                _ => return,
+            };
+        }
+        // Strip `namespace.` from the full name.
+        let prefix_len: usize = namespace.iter().map(|c| c.len() + 1).sum();
+        object.insert(name[prefix_len..].to_string(), json);
+    }
+
+    /// Data about telemetry itself, if there is anything to report.
+    ///
+    /// Data about histograms is limited to the histograms of `what`.
+    /// Data about the service as a whole is reported only if `what`
+    /// contains `__telemetry__` as a plain histogram, so that it
+    /// appears in a single payload.
+    fn self_telemetry(&self, what: &Subset) -> Option<Json> {
+        let mut object = BTreeMap::new();
+        let global = what.matches(SELF_TELEMETRY, false);
+        if let (true, Some(dropped)) = (global, &self.dropped) {
+            object.insert(
+                "dropped_samples".to_string(),
+                Json::I64(dropped.load(Ordering::Relaxed) as i64),
+            );
+        }
+        let mut poisoned: Vec<_> = self
+            .plain
+            .values()
+            .filter(|h| h.poisoned)
+            .map(|h| h.name.clone())
+            .chain(
+                self.keyed
+                    .values()
+                    .filter(|h| h.poisoned)
+                    .map(|h| h.name.clone()),
+            )
+            .collect();
+        if global && !poisoned.is_empty() {
+            poisoned.sort();
+            let poisoned = poisoned.into_iter().map(Json::String).collect();
+            object.insert("poisoned".to_string(), Json::Array(poisoned));
+        }
+        // The sampling rates, so that the server may scale the counts
+        // back up.
+        let sampled: BTreeMap<_, _> = self
+            .plain
+            .values()
+            .filter(|h| h.is_serialized() && what.matches(&h.name, false))
+            .map(|h| (&h.name, h.sampling))
+            .chain(
+                self.keyed
+                    .values()
+                    .filter(|h| h.is_serialized() && what.matches(&h.name, true))
+                    .map(|h| (&h.name, h.sampling)),
+            )
+            .filter_map(|(name, sampling)| {
+                sampling.map(|sampling| (name.clone(), Json::F64(sampling.rate())))
+            })
+            .collect();
+        if !sampled.is_empty() {
+            object.insert("sample_rates".to_string(), Json::Object(sampled));
+        }
+        let rejected: BTreeMap<_, _> = self
+            .keyed
+            .values()
+            .filter(|h| h.rejected_keys > 0 && what.matches(&h.name, true))
+            .map(|h| (h.name.clone(), Json::I64(h.rejected_keys as i64)))
+            .collect();
+        if !rejected.is_empty() {
+            object.insert("rejected_keys".to_string(), Json::Object(rejected));
+        }
+        if global && !self.rejected_names.is_empty() {
+            let names = self.rejected_names.iter().cloned().map(Json::String);
+            object.insert("rejected_names".to_string(), Json::Array(names.collect()));
+        }
+        if object.is_empty() {
+            None
+        } else {
+            Some(Json::Object(object))
+        }
+    }
+
+    /// Record the user key designated by `id` in a keyed histogram,
+    /// after applying the key policies.
+    fn intern_key(&mut self, index: usize, id: u32, key: String) {
+        let storage = match self.keyed.get_mut(index) {
+            Some(storage) => storage,
+            None => return,
+        };
+        let key = storage.apply_key_policies(key);
+        let id = id as usize;
+        if storage.keys.len() <= id {
+            storage.keys.resize(id + 1, None);
+        }
+        storage.keys[id] = key;
+    }
+
+    /// Get the storage of a keyed histogram to store a value with user
+    /// key `key`, along with the key, unless the key has been rejected
+    /// by the key policies or storing it would exceed the limit on keys.
+    ///
+    /// Keys rejected by the key policies are counted `times` times.
+    fn keyed_for_key(
+        &mut self,
+        index: usize,
+        key: UserKey,
+        times: u32,
+    ) -> Option<(&mut NamedStorage<dyn KeyedRawStorage>, Arc<str>)> {
+        let max_keys = self.max_keys;
+        let storage = self.keyed.get_mut(index).filter(|h| h.consented)?;
+        if storage.poisoned {
+            return None;
+        }
+        let key = match key.resolve(storage) {
+            Some(key) => key,
+            None => {
+                storage.rejected_keys += u64::from(times);
+                return None;
+            }
+        };
+        if let Some(max_keys) = max_keys {
+            let full = storage.protect(|contents| {
+                contents.key_count() >= max_keys && contents.is_new_key(&key)
+            })?;
+            if full {
+                return None;
+            }
+        }
+        Some((storage, key))
+    }
+
+    /// Record a value `times` times in a keyed histogram with two
+    /// dimensions, with user keys `key1` and `key2`.
+    ///
+    /// Values whose keys are rejected by the key policies or exceed the
+    /// limits of the histogram are counted as rejected keys.
+    fn record_keyed2(
+        &mut self,
+        index: usize,
+        key1: UserKey,
+        key2: UserKey,
+        value: u32,
+        times: u32,
+    ) {
+        let max_keys = self.max_keys;
+        let storage = match self.keyed.get_mut(index).filter(|h| h.consented) {
+            Some(storage) if !storage.poisoned => storage,
+            _ => return,
+        };
+        let (key1, key2) = match (key1.resolve(storage), key2.resolve(storage)) {
+            (Some(key1), Some(key2)) => (key1, key2),
+            _ => {
+                storage.rejected_keys += u64::from(times);
+                return;
+            }
+        };
+        let stored = storage.protect(|contents| {
+            if let Some(max_keys) = max_keys {
+                if contents.key_count() >= max_keys && contents.is_new_key(&key1) {
+                    return true;
+                }
+            }
+            contents.store2(&key1, &key2, value, times)
+        });
+        if stored == Some(false) {
+            storage.rejected_keys += u64::from(times);
         }
     }
 
     /// Code executed by the thread.
     /// This thread runs until it receives message `Terminate`.
     pub fn run(&mut self) {
-        for msg in &self.receiver {
+        while let Ok(msg) = self.receiver.recv() {
             match msg {
                 Op::RegisterPlain(index, storage) => {
-                    assert!(self.keys.insert(storage.name.clone()));
-                    self.plain.insert(index, storage);
+                    self.plain.insert(index, *storage);
                 }
                 Op::RegisterKeyed(index, storage) => {
-                    assert!(self.keys.insert(storage.name.clone()));
-                    self.keyed.insert(index, storage);
+                    self.keyed.insert(index, *storage);
+                }
+                Op::RejectName(name) => {
+                    self.rejected_names.insert(name);
                 }
+                // Operations on a storage may panic, e.g. on overflow. In
+                // this case, the storage is poisoned but the thread
+                // survives, along with all other histograms.
                 Op::RecordPlain(index, value) => {
-                    let storage = self.plain.get_mut(index).unwrap();
-                    storage.contents.store(value);
+                    if let Some(storage) = self.plain.get_mut(index).filter(|h| h.consented) {
+                        storage.protect(|contents| contents.store(value));
+                    }
                 }
-                Op::RecordKeyed(index, key, value) => {
-                    let storage = self.keyed.get_mut(index).unwrap();
-                    storage.contents.store(key, value);
+                Op::RecordPlainAt(index, slot, value) => {
+                    if let Some(storage) = self.plain.get_mut(index).filter(|h| h.consented) {
+                        storage.protect(|contents| contents.store_at(slot, value));
+                    }
+                }
+                Op::RecordPlainLabeled(index, value, label) => {
+                    if let Some(storage) = self.plain.get_mut(index).filter(|h| h.consented) {
+                        storage.protect(|contents| contents.store_labeled(value, label));
+                    }
+                }
+                Op::InternKey(index, id, key) => {
+                    self.intern_key(index, id, key);
+                }
+                Op::RecordKeyed(index, id, value) => {
+                    if let Some((storage, key)) =
+                        self.keyed_for_key(index, UserKey::Interned(id), 1)
+                    {
+                        storage.protect(|contents| contents.store(&key, value));
+                    }
+                }
+                Op::RecordKeyedRaw(index, key, value) => {
+                    if let Some((storage, key)) = self.keyed_for_key(index, UserKey::Raw(key), 1) {
+                        storage.protect(|contents| contents.store(&key, value));
+                    }
+                }
+                Op::RecordKeyed2Raw(index, key1, key2, value) => {
+                    self.record_keyed2(index, UserKey::Raw(key1), UserKey::Raw(key2), value, 1);
+                }
+                Op::RecordKeyed2(index, id1, id2, value) => {
+                    self.record_keyed2(
+                        index,
+                        UserKey::Interned(id1),
+                        UserKey::Interned(id2),
+                        value,
+                        1,
+                    );
+                }
+                Op::RecordBatch(plain, plain_at, keyed, keyed2) => {
+                    for (index, value, times) in plain {
+                        if let Some(storage) = self.plain.get_mut(index).filter(|h| h.consented) {
+                            storage.protect(|contents| contents.store_n(value, times));
+                        }
+                    }
+                    for (index, slot, value) in plain_at {
+                        if let Some(storage) = self.plain.get_mut(index).filter(|h| h.consented) {
+                            storage.protect(|contents| contents.store_at(slot, value));
+                        }
+                    }
+                    for (index, id, value, times) in keyed {
+                        if let Some((storage, key)) =
+                            self.keyed_for_key(index, UserKey::Interned(id), times)
+                        {
+                            storage.protect(|contents| contents.store_n(&key, value, times));
+                        }
+                    }
+                    for (index, id1, id2, value, times) in keyed2 {
+                        self.record_keyed2(
+                            index,
+                            UserKey::Interned(id1),
+                            UserKey::Interned(id2),
+                            value,
+                            times,
+                        );
+                    }
+                }
+                Op::QueryPlain(index, callback) => {
+                    // If the callback is not executed, the histogram receives no
+                    // answer and knows that the query has failed.
+                    if let Some(storage) = self.plain.get_mut(index) {
+                        storage.protect(|contents| callback(contents));
+                    }
+                }
+                Op::QueryKeyed(index, callback) => {
+                    if let Some(storage) = self.keyed.get_mut(index) {
+                        storage.protect(|contents| callback(contents));
+                    }
                 }
                 Op::Serialize(what, format, sender) => {
+                    let mut serialized = Vec::new();
+                    for histogram in self
+                        .plain
+                        .values_mut()
+                        .filter(|h| h.is_serialized() && what.matches(&h.name, false))
+                    {
+                        if let Some(json) = histogram.protect(|contents| contents.to_json(&format))
+                        {
+                            serialized.push((
+                                histogram.name.clone(),
+                                histogram.namespace.clone(),
+                                json,
+                            ));
+                        }
+                    }
+                    for histogram in self
+                        .keyed
+                        .values_mut()
+                        .filter(|h| h.is_serialized() && what.matches(&h.name, true))
+                    {
+                        if let Some(json) = histogram.protect(|contents| contents.to_json(&format))
+                        {
+                            serialized.push((
+                                histogram.name.clone(),
+                                histogram.namespace.clone(),
+                                json,
+                            ));
+                        }
+                    }
                     let mut object = BTreeMap::new();
-                    match what {
-                        Subset::AllPlain => {
-                            for ref histogram in self.plain.values() {
-                                object.insert(
-                                    histogram.name.clone(),
-                                    histogram.contents.to_json(&format),
-                                );
+                    for (name, namespace, json) in serialized {
+                        self.insert(&mut object, &name, &namespace, json);
+                    }
+                    // Data about telemetry itself is serialized alongside the
+                    // histograms, e.g. so that the sampling rates of keyed
+                    // histograms are part of the keyed payload.
+                    if let Some(json) = self.self_telemetry(&what) {
+                        object.insert(SELF_TELEMETRY.to_string(), json);
+                    }
+                    // The caller may have stopped waiting.
+                    let _ = sender.send(Json::Object(object));
+                }
+                Op::SetConsent(category, consented) => {
+                    for histogram in self.plain.values_mut() {
+                        if histogram.category == category {
+                            histogram.consented = consented;
+                            if !consented {
+                                histogram.protect(|contents| contents.reset());
                             }
                         }
-                        Subset::AllKeyed => {
-                            for ref histogram in self.keyed.values() {
-                                object.insert(
-                                    histogram.name.clone(),
-                                    histogram.contents.to_json(&format),
-                                );
+                    }
+                    for histogram in self.keyed.values_mut() {
+                        if histogram.category == category {
+                            histogram.consented = consented;
+                            if !consented {
+                                histogram.rejected_keys = 0;
+                                histogram.protect(|contents| contents.reset());
                             }
                         }
                     }
-                    sender.send(Json::Object(object)).unwrap();
+                }
+                Op::List(sender) => {
+                    let plain = self.plain.values().map(|h| HistogramInfo {
+                        name: h.name.clone(),
+                        kind: h.kind.clone(),
+                        keys: None,
+                        category: h.category,
+                        expired: h.expired,
+                        // Only known to the service.
+                        disabled: false,
+                        poisoned: h.poisoned,
+                        sampling: h.sampling,
+                        sampled_out: h.sampled_out,
+                    });
+                    let keyed = self.keyed.values_mut().map(|h| HistogramInfo {
+                        name: h.name.clone(),
+                        kind: h.kind.clone(),
+                        // A poisoned storage holds no key that matters.
+                        keys: Some(h.protect(|contents| contents.key_count()).unwrap_or(0)),
+                        category: h.category,
+                        expired: h.expired,
+                        disabled: false,
+                        poisoned: h.poisoned,
+                        sampling: h.sampling,
+                        sampled_out: h.sampled_out,
+                    });
+                    let _ = sender.send(plain.chain(keyed).collect());
                 }
                 Op::Terminate => {
                     return;
@@ -145,8 +749,19 @@ pub struct TelemetryTask {
     /// The channel used by the task to receive data.
     receiver: Receiver<Op>,
 
-    /// The set of all histogram names, used for sanity checking only.
-    keys: HashSet<String>,
+    /// The names with which histograms could not be registered, see
+    /// `Op::RejectName`.
+    rejected_names: BTreeSet<String>,
+
+    /// How histograms are grouped by namespace when serialized.
+    layout: Layout,
+
+    /// The maximal number of keys in each keyed histogram, if any.
+    max_keys: Option<usize>,
+
+    /// The number of values dropped because the channel was full, if
+    /// values may be dropped.
+    dropped: Option<Arc<AtomicUsize>>,
 }
 
 ///
@@ -157,38 +772,96 @@ pub struct TelemetryTask {
 /// type `T`.
 impl<K> BackEnd<K>
 where
-    K: Clone,
+    K: Witness,
 {
     /// Create a new back-end attached to a service and a key.
-    pub fn new(service: &Service, key: Key<K>) -> BackEnd<K> {
+    ///
+    /// The back-end records values only while `state` is 0. If
+    /// `sampler` is provided, it only keeps the records it selects.
+    pub fn new(
+        service: &Service,
+        key: Key<K>,
+        state: Arc<AtomicUsize>,
+        sampler: Option<Arc<Sampler>>,
+    ) -> BackEnd<K> {
         BackEnd {
             key,
-            is_active: PrivateAccess::get_is_active(service).clone(),
+            state,
+            sampler,
+            data: K::Data::default(),
             sender: PrivateAccess::get_sender(service).clone(),
+            batching: PrivateAccess::get_batching(service).clone(),
         }
     }
 
-    /// Get the key _if_ the service is currently active.
+    /// Get the key, regardless of whether the service is active.
+    pub fn raw_key(&self) -> &Key<K> {
+        &self.key
+    }
+
+    /// Get the key _if_ the histogram currently records values.
     pub fn get_key(&self) -> Option<&Key<K>> {
-        if self.is_active.load(Ordering::Relaxed) {
+        if self.state.load(Ordering::Relaxed) == 0 {
             Some(&self.key)
         } else {
             None
         }
     }
+
+    /// Get the key _if_ the histogram currently records values and
+    /// the next record is kept by sampling.
+    pub fn get_sampled_key(&self) -> Option<&Key<K>> {
+        let key = self.get_key()?;
+        match self.sampler {
+            Some(ref sampler) if !sampler.sample() => None,
+            _ => Some(key),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct BackEnd<K>
 where
-    K: Clone,
+    K: Witness,
 {
     /// The key used to communicate with the `TelemetryTask`.
     key: Key<K>,
 
     /// The channel used to communicate with the `TelemetryTask`.
-    pub sender: Sender<Op>,
+    pub sender: OpSender,
 
-    /// `true` if the Service is active, `false` otherwise.
-    is_active: Arc<AtomicBool>,
+    /// If the service batches records, the batching configuration.
+    pub batching: Option<Arc<Batching>>,
+
+    /// A combination of `STATE_*` bits, 0 if the histogram records values.
+    /// Shared with the service, which updates it.
+    state: Arc<AtomicUsize>,
+
+    /// If the histogram is sampled per record, the sampler.
+    sampler: Option<Arc<Sampler>>,
+
+    /// Data specific to the kind of histogram, e.g. the interned keys
+    /// of a keyed histogram.
+    pub data: K::Data,
 }
+
+/// Bit of `BackEnd::state` set while the service is inactive.
+pub const STATE_INACTIVE: usize = 1;
+
+/// Bit of `BackEnd::state` set while the histogram is disabled.
+pub const STATE_DISABLED: usize = 2;
+
+/// Bit of `BackEnd::state` set if the histogram has expired.
+pub const STATE_EXPIRED: usize = 4;
+
+/// Bit of `BackEnd::state` set while the user has not consented to the
+/// category of the histogram.
+pub const STATE_NO_CONSENT: usize = 8;
+
+/// Bit of `BackEnd::state` set if the histogram is sampled per client
+/// and this client has not been selected.
+pub const STATE_SAMPLED_OUT: usize = 16;
+
+/// Bit of `BackEnd::state` set if the histogram could not be
+/// registered, e.g. because its name is already used. Never cleared.
+pub const STATE_REJECTED: usize = 32;
@@ -1,11 +1,17 @@
 extern crate rustc_serialize;
 use self::rustc_serialize::json::Json;
 
+#[cfg(feature = "regex")]
+extern crate regex;
 extern crate telemetry;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::channel;
 use std::sync::Arc;
+use std::time::Duration;
 
 use telemetry::*;
 
@@ -64,10 +70,66 @@ fn create_linears_bad_3() {
     // Not enough histograms.
 }
 
+#[test]
+#[should_panic]
+fn create_linears_bad_4() {
+    let telemetry = Arc::new(Service::new(false));
+    let _: plain::Linear<u32> =
+        plain::Linear::new(&telemetry, "Test linear plain".to_string(), 0, 100, 102);
+    // More buckets than values.
+}
+
+#[test]
+#[should_panic]
+fn create_keyed_linears_bad() {
+    let telemetry = Arc::new(Service::new(false));
+    let _: keyed::KeyedLinear<String, u32> =
+        keyed::KeyedLinear::new(&telemetry, "Test linear map".to_string(), 0, 100, 102);
+    // More buckets than values.
+}
+
+#[test]
+fn create_linears_one_bucket_per_value() {
+    let telemetry = Service::new(true);
+    let linear = plain::Linear::new(&telemetry, "Linear".to_string(), 0, 100, 101);
+    let _: keyed::KeyedLinear<String, u32> =
+        keyed::KeyedLinear::new(&telemetry, "KeyedLinear".to_string(), 0, 100, 101);
+    let _: keyed::KeyedLinear2<String, String, u32> = keyed::KeyedLinear2::new(
+        &telemetry,
+        "KeyedLinear2".to_string(),
+        0,
+        100,
+        101,
+        None,
+        None,
+    );
+    let _: plain::WindowedLinear<u32> = plain::WindowedLinear::new(
+        &telemetry,
+        "WindowedLinear".to_string(),
+        0,
+        100,
+        101,
+        Duration::from_secs(1),
+        1,
+    );
+
+    linear.record(0);
+    linear.record(50);
+    linear.record(100);
+    let (plain, _) = get_all_serialized(&telemetry);
+    let buckets = plain.find("Linear").unwrap().as_array().unwrap();
+    assert_eq!(buckets.len(), 101);
+    for (i, bucket) in buckets.iter().enumerate() {
+        let expected = if i == 0 || i == 50 || i == 100 { 1 } else { 0 };
+        assert_eq!(bucket.as_i64(), Some(expected), "bucket {}", i);
+    }
+}
+
 enum TestEnum {
     Case1,
     Case2,
-    Case3(String),
+    // Only the variant is recorded, not its payload.
+    Case3(#[allow(dead_code)] String),
 }
 
 impl Flatten for TestEnum {
@@ -162,8 +224,8 @@ fn test_serialize_simple() {
 
     let (plain, keyed) = get_all_serialized(&telemetry);
     if let Json::Object(plain_btree) = plain {
-        if let Some(&Json::Array(ref array)) = plain_btree.get(&"Test linear plain".to_string()) {
-            let expect: Vec<Json> = vec![0, 0, 1, 0, 0, 0, 0, 0, 0, 3]
+        if let Some(Json::Array(array)) = plain_btree.get("Test linear plain") {
+            let expect: Vec<Json> = [0, 0, 1, 0, 0, 0, 0, 0, 0, 3]
                 .iter()
                 .cloned()
                 .map(Json::I64)
@@ -177,12 +239,10 @@ fn test_serialize_simple() {
     }
 
     if let Json::Object(keyed_btree) = keyed {
-        if let Some(&Json::Object(ref hist_btree)) =
-            keyed_btree.get(&"Test linear dynamic".to_string())
-        {
+        if let Some(Json::Object(hist_btree)) = keyed_btree.get("Test linear dynamic") {
             assert_eq!(hist_btree.len(), 2);
-            if let Some(&Json::Array(ref array)) = hist_btree.get(&"Key 1".to_string()) {
-                let expect: Vec<Json> = vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 2]
+            if let Some(Json::Array(array)) = hist_btree.get("Key 1") {
+                let expect: Vec<Json> = [0, 0, 0, 0, 0, 0, 0, 0, 0, 2]
                     .iter()
                     .cloned()
                     .map(Json::I64)
@@ -191,8 +251,8 @@ fn test_serialize_simple() {
             } else {
                 panic!("No key 1");
             }
-            if let Some(&Json::Array(ref array)) = hist_btree.get(&"Key 2".to_string()) {
-                let expect: Vec<Json> = vec![0, 0, 0, 1, 0, 1, 0, 0, 0, 0]
+            if let Some(Json::Array(array)) = hist_btree.get("Key 2") {
+                let expect: Vec<Json> = [0, 0, 0, 1, 0, 1, 0, 0, 0, 0]
                     .iter()
                     .cloned()
                     .map(Json::I64)
@@ -222,7 +282,7 @@ fn test_serialize_simple() {
 
     let (plain, keyed) = get_all_serialized(&telemetry);
     if let Json::Object(plain_btree) = plain {
-        if let Some(&Json::I64(ref num)) = plain_btree.get(&"Count 1".to_string()) {
+        if let Some(Json::I64(num)) = plain_btree.get("Count 1") {
             assert_eq!(*num, 15);
         } else {
             panic!("No record for the histogram or not a num");
@@ -232,7 +292,7 @@ fn test_serialize_simple() {
     }
 
     if let Json::Object(keyed_btree) = keyed {
-        if let Some(ref hist) = keyed_btree.get(&"Keyed count 1".to_string()) {
+        if let Some(ref hist) = keyed_btree.get("Keyed count 1") {
             let json = format!("{}", hist);
             assert_eq!(json, "{\"Key A\":92,\"Key B\":100,\"Key C\":1}");
         } else {
@@ -256,7 +316,7 @@ fn test_serialize_simple() {
 
     let (plain, keyed) = get_all_serialized(&telemetry);
     if let Json::Object(plain_btree) = plain {
-        if let Some(ref hist) = plain_btree.get(&"Enum 1".to_string()) {
+        if let Some(ref hist) = plain_btree.get("Enum 1") {
             let json = format!("{}", hist);
             assert_eq!(json, "[0,2,1]");
         } else {
@@ -267,7 +327,7 @@ fn test_serialize_simple() {
     }
 
     if let Json::Object(keyed_btree) = keyed {
-        if let Some(ref hist) = keyed_btree.get(&"Keyed enum 1".to_string()) {
+        if let Some(ref hist) = keyed_btree.get("Keyed enum 1") {
             let json = format!("{}", hist);
             assert_eq!(json, "{\"Key 1\":[1,2],\"Key 2\":[1]}");
         } else {
@@ -277,3 +337,1735 @@ fn test_serialize_simple() {
         panic!("Not a Json object");
     }
 }
+
+#[test]
+fn test_distinct_count() {
+    let telemetry = Service::new(true);
+    let distinct_1 = plain::DistinctCount::new(&telemetry, "Distinct 1".to_string(), 12);
+    let distinct_2 = plain::DistinctCount::new(&telemetry, "Distinct 2".to_string(), 12);
+    for i in 0..10_000u32 {
+        distinct_1.record(format!("host {}", i));
+        distinct_1.record(format!("host {}", i));
+        // Overlaps with `distinct_1` on half of the values.
+        distinct_2.record(format!("host {}", i + 5_000));
+    }
+
+    let (plain, _) = get_all_serialized(&telemetry);
+    let hist_1 = plain.find("Distinct 1").unwrap();
+    let hist_2 = plain.find("Distinct 2").unwrap();
+
+    // Precision 12 gives a standard error of ~1.6%.
+    let estimate = aggregate::estimate_distinct_count(hist_1).unwrap();
+    assert!((estimate - 10_000.).abs() < 500., "{}", estimate);
+    let estimate = hist_1.find("estimate").unwrap().as_i64().unwrap();
+    assert!((estimate - 10_000).abs() < 500, "{}", estimate);
+
+    let merged = aggregate::merge_distinct_counts(vec![hist_1, hist_2]).unwrap();
+    let estimate = aggregate::estimate_distinct_count(&merged).unwrap();
+    assert!((estimate - 15_000.).abs() < 750., "{}", estimate);
+}
+
+#[test]
+#[should_panic]
+fn create_distinct_count_bad_precision() {
+    let telemetry = Service::new(false);
+    let _: plain::DistinctCount<u32> =
+        plain::DistinctCount::new(&telemetry, "Distinct".to_string(), 20);
+}
+
+#[test]
+fn test_keyed_top_n() {
+    let telemetry = Service::new(true);
+    let top = keyed::KeyedTopN::new(&telemetry, "Top domains".to_string(), 2, 4);
+    top.record("a.example".to_string(), 50);
+    top.record("b.example".to_string(), 30);
+    top.record("c.example".to_string(), 1);
+    top.record("d.example".to_string(), 2);
+    // Evicts "c.example", which has the smallest count.
+    top.record("e.example".to_string(), 1);
+    top.record("b.example".to_string(), 30);
+
+    let (_, keyed) = get_all_serialized(&telemetry);
+    let json = format!("{}", keyed.find("Top domains").unwrap());
+    assert_eq!(
+        json,
+        "[{\"count\":60,\"error\":0,\"key\":\"b.example\"},\
+         {\"count\":50,\"error\":0,\"key\":\"a.example\"}]"
+    );
+
+    // Keep recording rare keys: the heavy hitters stay on top.
+    for i in 0..40 {
+        top.record(format!("rare {}", i), 1);
+    }
+    let (_, keyed) = get_all_serialized(&telemetry);
+    let array = keyed.find("Top domains").unwrap().as_array().unwrap();
+    assert_eq!(array.len(), 2);
+    assert_eq!(array[0].find("key").unwrap().as_string(), Some("b.example"));
+    assert_eq!(array[1].find("key").unwrap().as_string(), Some("a.example"));
+}
+
+#[test]
+fn test_slowest_n() {
+    let telemetry = Service::new(true);
+    let slowest = plain::SlowestN::new(&telemetry, "Slowest queries".to_string(), 3, 8);
+    slowest.record((10, "SELECT 1".to_string()));
+    slowest.record((50, "SELECT * FROM table".to_string()));
+    slowest.record((5, "fast".to_string()));
+    slowest.record((30, "medium".to_string()));
+    slowest.record((20, "évènement".to_string()));
+
+    let (plain, _) = get_all_serialized(&telemetry);
+    let array = plain.find("Slowest queries").unwrap().as_array().unwrap();
+    let values: Vec<_> = array
+        .iter()
+        .map(|entry| {
+            assert!(entry.find("timestamp").unwrap().as_i64().unwrap() > 0);
+            (
+                entry.find("value").unwrap().as_i64().unwrap(),
+                entry
+                    .find("label")
+                    .unwrap()
+                    .as_string()
+                    .unwrap()
+                    .to_string(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            (50, "SELECT *".to_string()),
+            (30, "medium".to_string()),
+            // Truncated on a character boundary.
+            (20, "évènem".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn test_windowed() {
+    let clock = Arc::new(MockClock::new());
+    let telemetry = ServiceBuilder::new()
+        .active(true)
+        .clock(clock.clone())
+        .build()
+        .unwrap();
+    let interval = Duration::from_secs(30);
+    let errors = plain::WindowedCount::new(&telemetry, "Errors".to_string(), interval, 2);
+    let latency =
+        plain::WindowedLinear::new(&telemetry, "Latency".to_string(), 0, 100, 10, interval, 2);
+    errors.record(3);
+    latency.record(5);
+    latency.record(95);
+    assert_eq!(errors.query(), Some(3));
+    assert_eq!(latency.query(), Some(vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 1]));
+
+    // Move to the second interval of the window.
+    clock.advance(Duration::from_secs(30));
+    errors.record(4);
+    latency.record(99);
+    assert_eq!(errors.query(), Some(7));
+    assert_eq!(latency.query(), Some(vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 2]));
+
+    let (plain, _) = get_all_serialized(&telemetry);
+    assert_eq!(format!("{}", plain.find("Errors").unwrap()), "[3,4]");
+    assert_eq!(
+        format!("{}", plain.find("Latency").unwrap()),
+        "[[1,0,0,0,0,0,0,0,0,1],[0,0,0,0,0,0,0,0,0,1]]"
+    );
+
+    // The first interval leaves the window.
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(errors.query(), Some(4));
+    let (plain, _) = get_all_serialized(&telemetry);
+    assert_eq!(format!("{}", plain.find("Errors").unwrap()), "[4,0]");
+
+    // Once the window has elapsed, everything is forgotten.
+    clock.advance(Duration::from_secs(300));
+    assert_eq!(errors.query(), Some(0));
+    assert_eq!(latency.query(), Some(vec![0; 10]));
+    errors.record(1);
+    assert_eq!(errors.query(), Some(1));
+}
+
+#[test]
+fn test_slowest_n_timestamp() {
+    let clock = Arc::new(MockClock::new());
+    let telemetry = ServiceBuilder::new()
+        .active(true)
+        .clock(clock.clone())
+        .build()
+        .unwrap();
+    let slowest = plain::SlowestN::new(&telemetry, "Slowest".to_string(), 2, 10);
+    clock.advance(Duration::from_millis(1500));
+    slowest.record((10, "first".to_string()));
+    // Timestamps are taken by the telemetry thread, so wait until it
+    // has processed the record before moving the clock.
+    let _ = get_all_serialized(&telemetry);
+    clock.advance(Duration::from_millis(1500));
+    slowest.record((5, "second".to_string()));
+
+    let (plain, _) = get_all_serialized(&telemetry);
+    assert_eq!(
+        format!("{}", plain.find("Slowest").unwrap()),
+        "[{\"label\":\"first\",\"timestamp\":1500,\"value\":10},\
+         {\"label\":\"second\",\"timestamp\":3000,\"value\":5}]"
+    );
+}
+
+#[test]
+fn test_service_builder() {
+    let path = std::env::temp_dir().join(format!("telemetry-test-{}.json", std::process::id()));
+    let telemetry = ServiceBuilder::new()
+        .active(true)
+        .channel_capacity(1)
+        .overflow_policy(OverflowPolicy::Block)
+        .thread_name("telemetry-test".to_string())
+        .stack_size(256 * 1024)
+        .max_keys(2)
+        .app_version("2.1")
+        .persistence_path(path.clone())
+        .format(SerializationFormat::SimpleJson)
+        .build()
+        .unwrap();
+
+    // Expiry.
+    let expired = plain::Count::new(&telemetry, Definition::new("Expired").expires("2.0"));
+    let alive = plain::Count::new(&telemetry, Definition::new("Alive").expires("2.1.1"));
+    expired.record(1);
+    alive.record(1);
+
+    // Limit on keys.
+    let keyed = keyed::KeyedCount::new(&telemetry, "Keyed".to_string());
+    keyed.record("a".to_string(), 1);
+    keyed.record("b".to_string(), 1);
+    keyed.record("c".to_string(), 1);
+    keyed.record("a".to_string(), 1);
+
+    // With `OverflowPolicy::Block`, nothing is lost, however small the queue.
+    let count = plain::Count::new(&telemetry, "Count".to_string());
+    for _ in 0..1000 {
+        count.record(1);
+    }
+
+    telemetry.persist().unwrap();
+    let mut source = String::new();
+    File::open(&path)
+        .unwrap()
+        .read_to_string(&mut source)
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let persisted = Json::from_str(&source).unwrap();
+    assert_eq!(
+        format!("{}", persisted),
+        "{\"keyed\":{\"Keyed\":{\"a\":2,\"b\":1}},\"plain\":{\"Alive\":1,\"Count\":1000}}"
+    );
+}
+
+#[test]
+fn test_persist_without_path() {
+    let telemetry = Service::new(true);
+    assert!(telemetry.persist().is_err());
+}
+
+#[test]
+fn test_dropped_samples() {
+    let telemetry = ServiceBuilder::new()
+        .active(true)
+        .channel_capacity(2)
+        .overflow_policy(OverflowPolicy::Drop)
+        .build()
+        .unwrap();
+    let count = plain::Count::new(&telemetry, "Count".to_string());
+    for _ in 0..10_000 {
+        count.record(1);
+    }
+    let (plain, _) = get_all_serialized(&telemetry);
+    let recorded = plain.find("Count").unwrap().as_i64().unwrap();
+    let dropped = plain
+        .find_path(&["__telemetry__", "dropped_samples"])
+        .unwrap()
+        .as_i64()
+        .unwrap();
+    assert_eq!(recorded + dropped, 10_000);
+    assert_eq!(dropped as usize, telemetry.dropped_samples());
+
+    // Without a bounded queue, nothing is dropped and there is nothing to report.
+    let telemetry = Service::new(true);
+    let (plain, _) = get_all_serialized(&telemetry);
+    assert_eq!(plain, Json::Object(BTreeMap::new()));
+    assert_eq!(telemetry.dropped_samples(), 0);
+}
+
+#[test]
+fn test_batching() {
+    let telemetry = ServiceBuilder::new()
+        .active(true)
+        .batching(1_000, Duration::from_secs(3600))
+        .build()
+        .unwrap();
+    let count = plain::Count::new(&telemetry, "Count".to_string());
+    let linear = plain::Linear::new(&telemetry, "Linear".to_string(), 0, 100, 10);
+    let keyed = keyed::KeyedCount::new(&telemetry, "Keyed".to_string());
+    for i in 0..50 {
+        count.record(2);
+        linear.record(i);
+        keyed.record("key".to_string(), 1);
+    }
+
+    // Nothing has been sent yet.
+    let (plain, keyed_json) = get_all_serialized(&telemetry);
+    assert_eq!(plain.find("Count").unwrap().as_i64(), Some(0));
+    assert_eq!(keyed_json.find_path(&["Keyed", "key"]), None);
+
+    telemetry.flush();
+    let (plain, keyed_json) = get_all_serialized(&telemetry);
+    assert_eq!(plain.find("Count").unwrap().as_i64(), Some(100));
+    let buckets: Vec<_> = plain
+        .find("Linear")
+        .unwrap()
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|x| x.as_i64().unwrap())
+        .collect();
+    assert_eq!(buckets.iter().sum::<i64>(), 50);
+    assert_eq!(
+        keyed_json.find_path(&["Keyed", "key"]).unwrap().as_i64(),
+        Some(50)
+    );
+
+    // Records of other threads are sent when these threads exit.
+    let count = Arc::new(count);
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let count = count.clone();
+            std::thread::spawn(move || {
+                for _ in 0..10 {
+                    count.record(1);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let (plain, _) = get_all_serialized(&telemetry);
+    assert_eq!(plain.find("Count").unwrap().as_i64(), Some(140));
+
+    // Batches are sent once they are full.
+    for _ in 0..1_000 {
+        count.record(1);
+    }
+    let (plain, _) = get_all_serialized(&telemetry);
+    assert_eq!(plain.find("Count").unwrap().as_i64(), Some(1_140));
+}
+
+#[test]
+fn test_shutdown() {
+    let path = std::env::temp_dir().join(format!("telemetry-shutdown-{}.json", std::process::id()));
+    let (sender, receiver) = channel();
+    let telemetry = ServiceBuilder::new()
+        .active(true)
+        .batching(1_000, Duration::from_secs(3600))
+        .persistence_path(path.clone())
+        .persist_on_shutdown()
+        .on_shutdown(move |snapshot| {
+            sender.send(snapshot.to_json()).unwrap();
+            Ok(())
+        })
+        .build()
+        .unwrap();
+    let count = plain::Count::new(&telemetry, "Count".to_string());
+    let keyed = keyed::KeyedFlag::new(&telemetry, "Keyed".to_string());
+    for _ in 0..10 {
+        count.record(1);
+    }
+    keyed.record("key".to_string(), ());
+
+    // Batched and queued records are part of the snapshot.
+    let outcome = telemetry.shutdown().unwrap();
+    assert!(outcome.hook_errors.is_empty());
+    let snapshot = outcome.snapshot;
+    assert_eq!(snapshot.plain.find("Count").unwrap().as_i64(), Some(10));
+    assert_eq!(
+        snapshot
+            .keyed
+            .find("Keyed")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .len(),
+        1
+    );
+
+    // Hooks have been executed.
+    assert_eq!(receiver.recv().unwrap(), snapshot.to_json());
+    let mut contents = String::new();
+    File::open(&path)
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(contents.trim(), snapshot.to_json().to_string());
+
+    // Recording after shutdown is harmless, even once the records are
+    // sent to the stopped thread, i.e. when the last histogram is gone.
+    count.record(1);
+    keyed.record("key".to_string(), ());
+    drop(count);
+    drop(keyed);
+}
+
+#[test]
+fn test_shutdown_hook_error() {
+    let (sender, receiver) = channel();
+    let telemetry = ServiceBuilder::new()
+        .active(true)
+        .on_shutdown(|_| {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                "Upload failed",
+            ))
+        })
+        .on_shutdown(move |_| {
+            sender.send(()).unwrap();
+            Ok(())
+        })
+        .build()
+        .unwrap();
+    let count = plain::Count::new(&telemetry, "Count".to_string());
+    count.record(1);
+
+    // The data survives the failure of a hook, and later hooks still run.
+    let outcome = telemetry.shutdown().unwrap();
+    assert_eq!(
+        outcome.snapshot.plain.find("Count").unwrap().as_i64(),
+        Some(1)
+    );
+    assert_eq!(outcome.hook_errors.len(), 1);
+    assert_eq!(outcome.hook_errors[0].to_string(), "Upload failed");
+    receiver.recv().unwrap();
+}
+
+#[test]
+fn test_poisoned() {
+    let telemetry = Service::new(true);
+    let overflow = plain::Count::new(&telemetry, "Overflow".to_string());
+    let count = plain::Count::new(&telemetry, "Count".to_string());
+    let fragile: keyed::KeyedCount<&str> = keyed::KeyedCount::new(
+        &telemetry,
+        Definition::new("Fragile").key_policy(KeyPolicy::Custom(Box::new(|key: String| {
+            if key == "boom" {
+                panic!("Policy failure");
+            }
+            Some(key)
+        }))),
+    );
+    overflow.record(u32::MAX);
+    count.record(1);
+    fragile.record("ok", 1);
+    let (plain, keyed) = get_all_serialized(&telemetry);
+    assert_eq!(
+        plain.find("Overflow").unwrap().as_i64(),
+        Some(u32::MAX as i64)
+    );
+    assert!(keyed.find("Fragile").is_some());
+
+    // Counts saturate rather than overflow.
+    overflow.record(1);
+    // A panic poisons the histogram, but the other histograms are not
+    // affected.
+    fragile.record("boom", 1);
+    count.record(1);
+    let (plain, keyed) = get_all_serialized(&telemetry);
+    assert_eq!(plain.find("Count").unwrap().as_i64(), Some(2));
+    assert_eq!(
+        plain.find("Overflow").unwrap().as_i64(),
+        Some(u32::MAX as i64)
+    );
+    assert_eq!(keyed.find("Fragile"), None);
+    let poisoned = plain
+        .find_path(&["__telemetry__", "poisoned"])
+        .unwrap()
+        .as_array()
+        .unwrap();
+    assert_eq!(poisoned, &vec![Json::String("Fragile".to_string())]);
+}
+
+#[test]
+fn test_record_after_shutdown() {
+    let telemetry = Service::new(true);
+    let count = plain::Count::new(&telemetry, "Count".to_string());
+    let keyed = keyed::KeyedCount::new(&telemetry, "Keyed".to_string());
+    let slowest = plain::SlowestN::new(&telemetry, "Slowest".to_string(), 2, 10);
+    telemetry.shutdown().unwrap();
+    count.record(1);
+    keyed.record("key".to_string(), 1);
+    slowest.record((1, "label".to_string()));
+}
+
+#[test]
+fn test_list() {
+    let telemetry = ServiceBuilder::new()
+        .active(true)
+        .app_version("2.0")
+        .build()
+        .unwrap();
+    let _flag = plain::Flag::new(&telemetry, "Flag".to_string());
+    let _linear: plain::Linear<u32> =
+        plain::Linear::new(&telemetry, "Linear".to_string(), 0, 100, 10);
+    let _expired = plain::Count::new(&telemetry, Definition::new("Expired").expires("1.0"));
+    let _windowed = plain::WindowedCount::new(
+        &telemetry,
+        "Windowed".to_string(),
+        Duration::from_secs(60),
+        5,
+    );
+    let keyed = keyed::KeyedCount::new(&telemetry, "Keyed".to_string());
+    let _top: keyed::KeyedTopN<String> = keyed::KeyedTopN::new(&telemetry, "Top".to_string(), 2, 4);
+    keyed.record("a".to_string(), 1);
+    keyed.record("b".to_string(), 1);
+
+    let list = telemetry.list();
+    let summary: Vec<_> = list
+        .iter()
+        .map(|info| {
+            (
+                info.name.as_str(),
+                info.kind.clone(),
+                info.keys,
+                info.expired,
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("Flag", Kind::Flag, None, false),
+            (
+                "Linear",
+                Kind::Linear {
+                    min: 0,
+                    max: 100,
+                    buckets: 10
+                },
+                None,
+                false
+            ),
+            ("Expired", Kind::Count, None, true),
+            (
+                "Windowed",
+                Kind::WindowedCount {
+                    interval: Duration::from_secs(60),
+                    intervals: 5
+                },
+                None,
+                false
+            ),
+            ("Keyed", Kind::KeyedCount, Some(2), false),
+            ("Top", Kind::KeyedTopN { n: 2, capacity: 4 }, Some(0), false),
+        ]
+    );
+    assert!(list.iter().all(|info| !info.poisoned));
+}
+
+#[test]
+fn test_snapshot() {
+    let telemetry = Service::new(true);
+    let flag = plain::Flag::new(&telemetry, "Flag".to_string());
+    let linear = plain::Linear::new(&telemetry, "Linear".to_string(), 0, 100, 10);
+    let count = plain::Count::new(&telemetry, "Count".to_string());
+    let enm = plain::Enum::new(&telemetry, "Enum".to_string());
+    let keyed_flag = keyed::KeyedFlag::new(&telemetry, "Keyed Flag".to_string());
+    let keyed_linear = keyed::KeyedLinear::new(&telemetry, "Keyed Linear".to_string(), 0, 100, 10);
+    let keyed_count = keyed::KeyedCount::new(&telemetry, "Keyed Count".to_string());
+    let keyed_enum = keyed::KeyedEnum::new(&telemetry, "Keyed Enum".to_string());
+
+    assert_eq!(flag.snapshot(), Some(false));
+    assert_eq!(count.snapshot(), Some(0));
+    assert_eq!(keyed_count.snapshot(), Some(HashMap::new()));
+
+    flag.record(());
+    linear.record(5);
+    linear.record(95);
+    count.record(3);
+    count.record(4);
+    enm.record(TestEnum::Case2);
+    keyed_flag.record("a".to_string(), ());
+    keyed_linear.record("a".to_string(), 15);
+    keyed_count.record("a".to_string(), 2);
+    keyed_count.record("b".to_string(), 1);
+    keyed_enum.record("a".to_string(), TestEnum::Case1);
+
+    assert_eq!(flag.snapshot(), Some(true));
+    assert_eq!(linear.snapshot(), Some(vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 1]));
+    assert_eq!(count.snapshot(), Some(7));
+    assert_eq!(enm.snapshot(), Some(vec![0, 1]));
+    assert_eq!(
+        keyed_flag.snapshot(),
+        Some(["a".to_string()].iter().cloned().collect())
+    );
+    let mut expected = HashMap::new();
+    expected.insert("a".to_string(), vec![0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(keyed_linear.snapshot(), Some(expected));
+    let mut expected = HashMap::new();
+    expected.insert("a".to_string(), 2);
+    expected.insert("b".to_string(), 1);
+    assert_eq!(keyed_count.snapshot(), Some(expected));
+    let mut expected = HashMap::new();
+    expected.insert("a".to_string(), vec![1]);
+    assert_eq!(keyed_enum.snapshot(), Some(expected));
+
+    telemetry.shutdown().unwrap();
+    assert_eq!(count.snapshot(), None);
+    assert_eq!(keyed_count.snapshot(), None);
+}
+
+#[test]
+fn test_subsets() {
+    let telemetry = Service::new(true);
+    let requests = plain::Count::new(&telemetry, "net.requests".to_string());
+    let errors = keyed::KeyedCount::new(&telemetry, "net.http.errors".to_string());
+    let _clicks = plain::Count::new(&telemetry, "ui.clicks".to_string());
+    let _other = plain::Count::new(&telemetry, "ui.scrolls".to_string());
+    requests.record(3);
+    errors.record("404".to_string(), 1);
+
+    let serialize = |what: Subset| {
+        let (sender, receiver) = channel();
+        telemetry.to_json(what, SerializationFormat::SimpleJson, sender);
+        let json = receiver.recv().unwrap();
+        let mut names: Vec<_> = json.as_object().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    };
+
+    assert_eq!(
+        serialize(Subset::Names(vec![
+            "ui.clicks".to_string(),
+            "net.http.errors".to_string(),
+            "unknown".to_string(),
+        ])),
+        vec!["net.http.errors", "ui.clicks"]
+    );
+    assert_eq!(
+        serialize(Subset::Prefix("net.".to_string())),
+        vec!["net.http.errors", "net.requests"]
+    );
+    assert_eq!(
+        serialize(Subset::Glob("*.*s".to_string())),
+        vec!["net.http.errors", "net.requests", "ui.clicks", "ui.scrolls"]
+    );
+    assert_eq!(
+        serialize(Subset::Glob("net.*.errors".to_string())),
+        vec!["net.http.errors"]
+    );
+    assert_eq!(
+        serialize(Subset::Glob("ui.?licks".to_string())),
+        vec!["ui.clicks"]
+    );
+    assert_eq!(
+        serialize(Subset::Union(vec![
+            Subset::AllKeyed,
+            Subset::Names(vec!["ui.scrolls".to_string()]),
+        ])),
+        vec!["net.http.errors", "ui.scrolls"]
+    );
+
+    let (sender, receiver) = channel();
+    telemetry.to_json(
+        Subset::Prefix("net.".to_string()),
+        SerializationFormat::SimpleJson,
+        sender,
+    );
+    let json = receiver.recv().unwrap();
+    assert_eq!(json.find("net.requests").unwrap().as_i64(), Some(3));
+    assert_eq!(
+        json.find_path(&["net.http.errors", "404"])
+            .unwrap()
+            .as_i64(),
+        Some(1)
+    );
+}
+
+#[test]
+fn test_scope() {
+    let telemetry = Service::new(true);
+    let network = telemetry.scope("network");
+    let requests = plain::Count::new(&network, "requests".to_string());
+    let http = network.scope("http");
+    let errors = keyed::KeyedCount::new(&http, "errors".to_string());
+    let requests_ui = plain::Count::new(&telemetry.scope("ui"), "requests".to_string());
+    requests.record(2);
+    requests_ui.record(3);
+    errors.record("404".to_string(), 1);
+
+    // Dropping a scope neither stops the service nor unregisters histograms.
+    drop(network);
+    drop(http);
+    let (plain, keyed) = get_all_serialized(&telemetry);
+    assert_eq!(plain.find("network.requests").unwrap().as_i64(), Some(2));
+    assert_eq!(plain.find("ui.requests").unwrap().as_i64(), Some(3));
+    assert_eq!(
+        keyed
+            .find_path(&["network.http.errors", "404"])
+            .unwrap()
+            .as_i64(),
+        Some(1)
+    );
+
+    // Only the service itself may be shut down.
+    assert!(telemetry.scope("network").shutdown().is_err());
+    requests.record(1);
+    assert_eq!(requests.snapshot(), Some(3));
+
+    // Empty components add no scope.
+    let total = plain::Count::new(&telemetry.scope(""), "total".to_string());
+    let latency = plain::Count::new(&telemetry.scope(".ui..paint."), "latency".to_string());
+    total.record(1);
+    latency.record(1);
+    let (plain, _) = get_all_serialized(&telemetry);
+    assert_eq!(plain.find("total").unwrap().as_i64(), Some(1));
+    assert_eq!(plain.find("ui.paint.latency").unwrap().as_i64(), Some(1));
+}
+
+#[test]
+fn test_scope_nested_layout() {
+    let telemetry = ServiceBuilder::new()
+        .active(true)
+        .layout(Layout::Nested)
+        .build()
+        .unwrap();
+    let network = telemetry.scope("network");
+    let requests = plain::Count::new(&network, "requests".to_string());
+    let errors = keyed::KeyedCount::new(&telemetry.scope("network.http"), "errors".to_string());
+    let root = plain::Count::new(&telemetry, "root.count".to_string());
+    requests.record(2);
+    errors.record("404".to_string(), 1);
+    root.record(1);
+
+    let (plain, keyed) = get_all_serialized(&telemetry);
+    assert_eq!(
+        plain.find_path(&["network", "requests"]).unwrap().as_i64(),
+        Some(2)
+    );
+    // Names registered outside of scopes are not split.
+    assert_eq!(plain.find("root.count").unwrap().as_i64(), Some(1));
+    assert_eq!(
+        keyed
+            .find_path(&["network", "http", "errors", "404"])
+            .unwrap()
+            .as_i64(),
+        Some(1)
+    );
+}
+
+#[test]
+fn create_name_conflicting_with_namespace() {
+    let telemetry = ServiceBuilder::new()
+        .active(true)
+        .layout(Layout::Nested)
+        .build()
+        .unwrap();
+    let requests = plain::Count::new(&telemetry.scope("network"), "requests".to_string());
+    let network = plain::Count::new(&telemetry, "network".to_string());
+    let _ = plain::Count::new(&telemetry.scope("network.requests"), "errors".to_string());
+    requests.record(1);
+    network.record(1);
+
+    // The service survives, only the conflicting histograms are rejected.
+    let (plain, _) = get_all_serialized(&telemetry);
+    assert_eq!(
+        plain.find_path(&["network", "requests"]).unwrap().as_i64(),
+        Some(1)
+    );
+    assert_eq!(network.snapshot(), None);
+    assert_eq!(
+        plain.find_path(&["__telemetry__", "rejected_names"]),
+        Some(&Json::Array(vec![
+            Json::String("network".to_string()),
+            Json::String("network.requests.errors".to_string()),
+        ]))
+    );
+}
+
+#[test]
+fn test_set_enabled() {
+    let telemetry = Service::new(true);
+    let requests = plain::Count::new(&telemetry, "net.requests".to_string());
+    let errors = keyed::KeyedCount::new(&telemetry, "net.errors".to_string());
+    let clicks = plain::Count::new(&telemetry, "ui.clicks".to_string());
+
+    telemetry.set_enabled("net.*", false);
+    telemetry.set_enabled("net.errors", true);
+    // Rules also apply to histograms registered later.
+    let latency = plain::Count::new(&telemetry, "net.latency".to_string());
+    requests.record(1);
+    errors.record("404".to_string(), 1);
+    clicks.record(1);
+    latency.record(1);
+    assert_eq!(requests.snapshot(), Some(0));
+    assert_eq!(errors.snapshot().unwrap().len(), 1);
+    assert_eq!(clicks.snapshot(), Some(1));
+    assert_eq!(latency.snapshot(), Some(0));
+    let disabled: Vec<_> = telemetry
+        .list()
+        .into_iter()
+        .filter(|info| info.disabled)
+        .map(|info| info.name)
+        .collect();
+    assert_eq!(disabled, vec!["net.requests", "net.latency"]);
+
+    // Deactivating and reactivating the service preserves the rules.
+    telemetry.set_active(false);
+    clicks.record(1);
+    telemetry.set_active(true);
+    requests.record(1);
+    clicks.record(1);
+    assert_eq!(requests.snapshot(), Some(0));
+    assert_eq!(clicks.snapshot(), Some(2));
+
+    telemetry.set_enabled("*", true);
+    requests.record(1);
+    assert_eq!(requests.snapshot(), Some(1));
+}
+
+#[test]
+fn test_reload_config() {
+    let path = std::env::temp_dir().join(format!("telemetry-config-{}.json", std::process::id()));
+    let telemetry = ServiceBuilder::new()
+        .active(true)
+        .config_path(path.clone())
+        .build()
+        .unwrap();
+    let requests = plain::Count::new(&telemetry, "net.requests".to_string());
+    let errors = plain::Count::new(&telemetry, "net.errors".to_string());
+
+    // No file yet.
+    assert!(telemetry.reload_config().is_err());
+
+    std::fs::write(
+        &path,
+        r#"{"disabled": ["net.*"], "enabled": ["net.errors"]}"#,
+    )
+    .unwrap();
+    telemetry.reload_config().unwrap();
+    requests.record(1);
+    errors.record(1);
+    assert_eq!(requests.snapshot(), Some(0));
+    assert_eq!(errors.snapshot(), Some(1));
+
+    // An invalid file leaves the rules unchanged.
+    std::fs::write(&path, r#"{"disabled": "net.*"}"#).unwrap();
+    assert!(telemetry.reload_config().is_err());
+    requests.record(1);
+    assert_eq!(requests.snapshot(), Some(0));
+
+    // Reloading replaces all rules.
+    std::fs::write(&path, "{}").unwrap();
+    telemetry.reload_config().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    requests.record(1);
+    assert_eq!(requests.snapshot(), Some(1));
+}
+
+#[test]
+fn test_consent() {
+    let telemetry = ServiceBuilder::new()
+        .active(true)
+        .consent(&[Category::Technical, Category::Interaction])
+        .build()
+        .unwrap();
+    let crashes = plain::Count::new(&telemetry, "crashes".to_string());
+    let clicks = plain::Count::new(
+        &telemetry,
+        Definition::new("clicks").with_category(Category::Interaction),
+    );
+    let domains = keyed::KeyedCount::new(
+        &telemetry,
+        Definition::new("domains").with_category(Category::WebActivity),
+    );
+    assert!(telemetry.has_consent(Category::Interaction));
+    assert!(!telemetry.has_consent(Category::WebActivity));
+
+    crashes.record(1);
+    clicks.record(1);
+    domains.record("example.org".to_string(), 1);
+    let (plain, keyed) = get_all_serialized(&telemetry);
+    assert_eq!(plain.find("crashes").unwrap().as_i64(), Some(1));
+    assert_eq!(plain.find("clicks").unwrap().as_i64(), Some(1));
+    assert_eq!(keyed.find("domains"), None);
+
+    // Revoking consent purges the data.
+    telemetry.set_consent(Category::Interaction, false);
+    clicks.record(1);
+    let (plain, _) = get_all_serialized(&telemetry);
+    assert_eq!(plain.find("clicks"), None);
+    assert_eq!(plain.find("crashes").unwrap().as_i64(), Some(1));
+
+    telemetry.set_consent(Category::Interaction, true);
+    telemetry.set_consent(Category::WebActivity, true);
+    clicks.record(2);
+    domains.record("example.org".to_string(), 1);
+    let (plain, keyed) = get_all_serialized(&telemetry);
+    assert_eq!(plain.find("clicks").unwrap().as_i64(), Some(2));
+    assert_eq!(
+        keyed
+            .find_path(&["domains", "example.org"])
+            .unwrap()
+            .as_i64(),
+        Some(1)
+    );
+    let categories: Vec<_> = telemetry
+        .list()
+        .into_iter()
+        .map(|info| info.category)
+        .collect();
+    assert_eq!(
+        categories,
+        vec![
+            Category::Technical,
+            Category::Interaction,
+            Category::WebActivity
+        ]
+    );
+}
+
+#[test]
+fn test_consent_purges_batches() {
+    let telemetry = ServiceBuilder::new()
+        .active(true)
+        .batching(1_000, Duration::from_secs(3600))
+        .build()
+        .unwrap();
+    let clicks = plain::Count::new(
+        &telemetry,
+        Definition::new("clicks").with_category(Category::Interaction),
+    );
+    clicks.record(1);
+    telemetry.set_consent(Category::Interaction, false);
+    telemetry.set_consent(Category::Interaction, true);
+    telemetry.flush();
+    assert_eq!(clicks.snapshot(), Some(0));
+}
+
+#[test]
+fn test_sampling() {
+    let telemetry = ServiceBuilder::new().active(true).seed(42).build().unwrap();
+    let sampled = plain::Count::new(
+        &telemetry,
+        Definition::new("sampled").with_sampling(Sampling::PerRecord(0.25)),
+    );
+    let full = plain::Count::new(
+        &telemetry,
+        Definition::new("full").with_sampling(Sampling::PerRecord(1.)),
+    );
+    for _ in 0..10000 {
+        sampled.record(1);
+        full.record(1);
+    }
+    let (plain, _) = get_all_serialized(&telemetry);
+    let kept = plain.find("sampled").unwrap().as_i64().unwrap();
+    assert!(kept > 2200 && kept < 2800, "kept {} records", kept);
+    assert_eq!(plain.find("full").unwrap().as_i64(), Some(10000));
+    let rates = plain.find_path(&["__telemetry__", "sample_rates"]).unwrap();
+    assert_eq!(rates.find("sampled").unwrap().as_f64(), Some(0.25));
+    assert_eq!(rates.find("full").unwrap().as_f64(), Some(1.));
+
+    // Each payload lists the rates of the histograms it contains.
+    let keyed = keyed::KeyedCount::new(
+        &telemetry,
+        Definition::new("keyed").with_sampling(Sampling::PerRecord(0.5)),
+    );
+    keyed.record("a".to_string(), 1);
+    let (plain, keyed) = get_all_serialized(&telemetry);
+    let rates = plain.find_path(&["__telemetry__", "sample_rates"]).unwrap();
+    assert!(rates.find("keyed").is_none());
+    let rates = keyed.find_path(&["__telemetry__", "sample_rates"]).unwrap();
+    assert_eq!(rates.as_object().unwrap().len(), 1);
+    assert_eq!(rates.find("keyed").unwrap().as_f64(), Some(0.5));
+
+    let (sender, receiver) = channel();
+    telemetry.to_json(
+        Subset::Names(vec!["sampled".to_string()]),
+        SerializationFormat::SimpleJson,
+        sender,
+    );
+    let json = receiver.recv().unwrap();
+    let rates = json.find_path(&["__telemetry__", "sample_rates"]).unwrap();
+    assert_eq!(rates.as_object().unwrap().len(), 1);
+    assert_eq!(rates.find("sampled").unwrap().as_f64(), Some(0.25));
+}
+
+#[test]
+fn test_sampling_per_client() {
+    let selected = |seed| {
+        let telemetry = ServiceBuilder::new()
+            .active(true)
+            .seed(seed)
+            .build()
+            .unwrap();
+        let histograms: Vec<_> = (0..200)
+            .map(|i| {
+                plain::Count::new(
+                    &telemetry,
+                    Definition::new(format!("count{}", i)).with_sampling(Sampling::PerClient(0.5)),
+                )
+            })
+            .collect();
+        for histogram in &histograms {
+            histogram.record(3);
+        }
+        let (plain, _) = get_all_serialized(&telemetry);
+        let mut selected = Vec::new();
+        for i in 0..200 {
+            let name = format!("count{}", i);
+            // Sampling is all-or-nothing.
+            match plain.find(&name) {
+                Some(value) => {
+                    assert_eq!(value.as_i64(), Some(3));
+                    selected.push(i);
+                }
+                None => assert!(!telemetry
+                    .list()
+                    .iter()
+                    .any(|info| info.name == name && !info.sampled_out)),
+            }
+        }
+        selected
+    };
+    let first = selected(7);
+    assert!(
+        first.len() > 60 && first.len() < 140,
+        "selected {}",
+        first.len()
+    );
+    // The selection is reproducible.
+    assert_eq!(first, selected(7));
+}
+
+#[test]
+fn test_local_differential_privacy() {
+    let telemetry = ServiceBuilder::new().active(true).seed(1).build().unwrap();
+
+    // Simulate 2000 clients, 600 of which set the flag.
+    let flags: Vec<_> = (0..2000)
+        .map(|i| plain::PrivateFlag::new(&telemetry, format!("flag{}", i), 1.))
+        .collect();
+    for flag in flags.iter().take(600) {
+        flag.record(());
+    }
+
+    let choices: plain::PrivateEnum<u32> = plain::PrivateEnum::new(&telemetry, "choices", 3, 2.);
+    for i in 0..3000 {
+        choices.record(if i % 3 == 0 { 2 } else { 0 });
+    }
+
+    // Simulate 1000 clients, half of which record "a", a quarter "b".
+    let keyed: Vec<keyed::KeyedPrivateFlag<&str>> = (0..1000)
+        .map(|i| keyed::KeyedPrivateFlag::new(&telemetry, format!("keyed{}", i), 64, 2, 4.))
+        .collect();
+    for (i, flag) in keyed.iter().enumerate() {
+        if i % 2 == 0 {
+            flag.record("a", ());
+        }
+        if i % 4 == 0 {
+            flag.record("b", ());
+        }
+    }
+
+    let (plain, keyed) = get_all_serialized(&telemetry);
+    let payloads: Vec<_> = (0..2000)
+        .map(|i| plain.find(&format!("flag{}", i)).unwrap())
+        .collect();
+    // Individual payloads are noisy.
+    let reported = payloads
+        .iter()
+        .filter(|payload| payload.find("value").unwrap().as_i64() == Some(1))
+        .count();
+    assert!(reported != 600);
+    let estimate = aggregate::estimate_private_flags(payloads).unwrap();
+    assert!((estimate - 600.).abs() < 200., "estimate {}", estimate);
+
+    let estimates =
+        aggregate::estimate_private_enums(vec![plain.find("choices").unwrap()]).unwrap();
+    assert_eq!(estimates.len(), 3);
+    for (estimate, expected) in estimates.iter().zip(&[2000., 0., 1000.]) {
+        assert!(
+            (estimate - expected).abs() < 200.,
+            "estimates {:?}",
+            estimates
+        );
+    }
+
+    let payloads: Vec<_> = (0..1000)
+        .map(|i| keyed.find(&format!("keyed{}", i)).unwrap())
+        .collect();
+    let estimates = aggregate::estimate_private_keyed_flags(payloads, &["a", "b", "c"]).unwrap();
+    for (key, expected) in &[("a", 500.), ("b", 250.), ("c", 0.)] {
+        let estimate = estimates[*key];
+        assert!(
+            (estimate - expected).abs() < 100.,
+            "estimates {:?}",
+            estimates
+        );
+    }
+}
+
+#[test]
+fn test_key_policies() {
+    let telemetry = Service::new(true);
+    let allowed: keyed::KeyedCount<&str> = keyed::KeyedCount::new(
+        &telemetry,
+        Definition::new("allowed")
+            .key_policy(KeyPolicy::Allow(vec!["a".to_string(), "b".to_string()])),
+    );
+    let glob: keyed::KeyedFlag<&str> = keyed::KeyedFlag::new(
+        &telemetry,
+        Definition::new("glob")
+            .key_policy(KeyPolicy::Glob("plugin-*".to_string()))
+            .key_policy(KeyPolicy::MaxLength(10)),
+    );
+    let scrubbed: keyed::KeyedFlag<&str> = keyed::KeyedFlag::new(
+        &telemetry,
+        Definition::new("scrubbed")
+            .key_policy(KeyPolicy::ScrubUrls)
+            .key_policy(KeyPolicy::ScrubPaths)
+            .key_policy(KeyPolicy::ScrubEmails),
+    );
+    let mut salt = [0; 16];
+    for (i, byte) in salt.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let hashed: keyed::KeyedFlag<&str> = keyed::KeyedFlag::new(
+        &telemetry,
+        Definition::new("hashed").key_policy(KeyPolicy::Hash(salt)),
+    );
+    let custom: keyed::KeyedFlag<&str> = keyed::KeyedFlag::new(
+        &telemetry,
+        Definition::new("custom").key_policy(KeyPolicy::Custom(Box::new(|key: String| {
+            if key.chars().all(|c| c.is_ascii_digit()) {
+                None
+            } else {
+                Some(key.to_uppercase())
+            }
+        }))),
+    );
+
+    allowed.record("a", 1);
+    allowed.record("c", 1);
+    allowed.record("d", 2);
+    glob.record("plugin-1", ());
+    glob.record("plugin-123456", ());
+    glob.record("other", ());
+    scrubbed.record("failed to open /home/jane/secret.txt", ());
+    scrubbed.record("load https://user:pw@example.org/page?token=42#top", ());
+    scrubbed.record("mail from jane.doe@example.org.", ());
+    hashed.record("", ());
+    custom.record("1234", ());
+    custom.record("abc", ());
+
+    let (_, keyed) = get_all_serialized(&telemetry);
+    let mut expected = BTreeMap::new();
+    expected.insert("a".to_string(), Json::I64(1));
+    assert_eq!(keyed.find("allowed"), Some(&Json::Object(expected)));
+    assert_eq!(
+        keyed.find("glob"),
+        Some(&Json::Array(vec![Json::String("plugin-1".to_string())]))
+    );
+    assert_eq!(
+        keyed.find("scrubbed"),
+        Some(&Json::Array(vec![
+            Json::String("failed to open <path>".to_string()),
+            Json::String("load https://example.org/page".to_string()),
+            Json::String("mail from <email>".to_string()),
+        ]))
+    );
+    // The reference SipHash-2-4 of an empty message with key 00..0f.
+    assert_eq!(
+        keyed.find("hashed"),
+        Some(&Json::Array(vec![Json::String(
+            "726fdb47dd0e0e31".to_string()
+        )]))
+    );
+    assert_eq!(
+        keyed.find("custom"),
+        Some(&Json::Array(vec![Json::String("ABC".to_string())]))
+    );
+
+    // Rejected keys are counted.
+    let rejected = keyed
+        .find_path(&["__telemetry__", "rejected_keys"])
+        .unwrap();
+    assert_eq!(rejected.find("allowed").unwrap().as_i64(), Some(2));
+    assert_eq!(rejected.find("glob").unwrap().as_i64(), Some(2));
+    assert_eq!(rejected.find("custom").unwrap().as_i64(), Some(1));
+    assert_eq!(rejected.find("scrubbed"), None);
+
+    // The count is purged along with the data.
+    telemetry.set_consent(Category::Technical, false);
+    telemetry.set_consent(Category::Technical, true);
+    let (_, keyed) = get_all_serialized(&telemetry);
+    assert_eq!(keyed.find_path(&["__telemetry__", "rejected_keys"]), None);
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn test_regex_key_policy() {
+    let telemetry = Service::new(true);
+    let versions: keyed::KeyedCount<&str> = keyed::KeyedCount::new(
+        &telemetry,
+        Definition::new("versions")
+            .key_policy(KeyPolicy::Regex(regex::Regex::new(r"^\d+\.\d+$").unwrap())),
+    );
+    versions.record("1.2", 1);
+    versions.record("10.0", 1);
+    versions.record("1.2.3", 1);
+    versions.record("beta", 1);
+
+    let (_, keyed) = get_all_serialized(&telemetry);
+    assert_eq!(
+        keyed.find("versions").unwrap().to_string(),
+        r#"{"1.2":1,"10.0":1}"#
+    );
+    let rejected = keyed
+        .find_path(&["__telemetry__", "rejected_keys", "versions"])
+        .unwrap();
+    assert_eq!(rejected.as_i64(), Some(2));
+}
+
+#[test]
+fn test_declared_keys() {
+    let telemetry = Service::new(true);
+    let count: keyed::KeyedCount<&str> = keyed::KeyedCount::new(
+        &telemetry,
+        Definition::new("count").with_keys(&["a", "b"], UnknownKeys::Other),
+    );
+    let flag: keyed::KeyedFlag<&str> = keyed::KeyedFlag::new(
+        &telemetry,
+        Definition::new("flag").with_keys(&["a", "b"], UnknownKeys::Reject),
+    );
+    let linear: keyed::KeyedLinear<&str, u32> = keyed::KeyedLinear::new(
+        &telemetry,
+        Definition::new("linear").with_keys(&["a"], UnknownKeys::Reject),
+        0,
+        10,
+        2,
+    );
+    let enm: keyed::KeyedEnum<&str, u32> = keyed::KeyedEnum::new(
+        &telemetry,
+        Definition::new("enum").with_keys(&["a", "b"], UnknownKeys::Other),
+    );
+
+    count.record("a", 2);
+    count.record("c", 3);
+    count.record("d", 4);
+    flag.record("b", ());
+    flag.record("c", ());
+    linear.record("b", 1);
+    enm.record("z", 1);
+
+    let (_, keyed) = get_all_serialized(&telemetry);
+    let object = |fields: &[(&str, Json)]| {
+        Json::Object(
+            fields
+                .iter()
+                .map(|&(key, ref value)| (key.to_string(), value.clone()))
+                .collect(),
+        )
+    };
+    assert_eq!(
+        keyed.find("count"),
+        Some(&object(&[
+            ("a", Json::I64(2)),
+            ("b", Json::I64(0)),
+            (OTHER_KEY, Json::I64(7)),
+        ]))
+    );
+    assert_eq!(
+        keyed.find("flag"),
+        Some(&object(&[("a", Json::I64(0)), ("b", Json::I64(1))]))
+    );
+    assert_eq!(
+        keyed.find("linear"),
+        Some(&object(&[(
+            "a",
+            Json::Array(vec![Json::I64(0), Json::I64(0)])
+        )]))
+    );
+    assert_eq!(
+        keyed.find("enum"),
+        Some(&object(&[
+            ("a", Json::Array(vec![])),
+            ("b", Json::Array(vec![])),
+            (OTHER_KEY, Json::Array(vec![Json::I64(0), Json::I64(1)])),
+        ]))
+    );
+
+    // Rejected keys are counted.
+    let rejected = keyed
+        .find_path(&["__telemetry__", "rejected_keys"])
+        .unwrap();
+    assert_eq!(rejected.find("flag").unwrap().as_i64(), Some(1));
+    assert_eq!(rejected.find("linear").unwrap().as_i64(), Some(1));
+    assert_eq!(rejected.find("count"), None);
+
+    let snapshot = count.snapshot().unwrap();
+    assert_eq!(snapshot.len(), 3);
+    assert_eq!(snapshot["b"], 0);
+    assert_eq!(flag.snapshot().unwrap().len(), 1);
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Plugin {
+    Audio,
+    Video,
+}
+
+impl TelemetryKey for Plugin {
+    fn to_key_string(&self) -> String {
+        match *self {
+            Plugin::Audio => "audio".to_string(),
+            Plugin::Video => "video".to_string(),
+        }
+    }
+}
+
+#[test]
+fn test_telemetry_key() {
+    let telemetry = ServiceBuilder::new()
+        .active(true)
+        .batching(1000, Duration::from_secs(60))
+        .build()
+        .unwrap();
+    let crashes = keyed::KeyedCount::new(&telemetry, "crashes");
+    let ports: keyed::KeyedFlag<u16> = keyed::KeyedFlag::new(&telemetry, "ports");
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let crashes = crashes.clone();
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    crashes.record(Plugin::Audio, 1);
+                }
+                crashes.record(Plugin::Video, 2);
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    ports.record(8080, ());
+    ports.record(443, ());
+    ports.record(8080, ());
+    telemetry.flush();
+
+    let (_, keyed) = get_all_serialized(&telemetry);
+    let crashes = keyed.find("crashes").unwrap();
+    assert_eq!(crashes.find("audio").unwrap().as_i64(), Some(400));
+    assert_eq!(crashes.find("video").unwrap().as_i64(), Some(8));
+    assert_eq!(
+        keyed.find("ports"),
+        Some(&Json::Array(vec![
+            Json::String("443".to_string()),
+            Json::String("8080".to_string()),
+        ]))
+    );
+}
+
+#[test]
+fn test_keyed_histogram2() {
+    let telemetry = Service::new(true);
+    let errors = keyed::KeyedCount2::new(&telemetry, "errors", Some(2), Some(2));
+    let latency: keyed::KeyedLinear2<&str, &str, u32> =
+        keyed::KeyedLinear2::new(&telemetry, "latency", 0, 100, 2, None, None);
+
+    errors.record("audio", "decode", 1);
+    errors.record("audio", "decode", 2);
+    errors.record("audio", "open", 1);
+    errors.record("video", "decode", 5);
+    // Too many second keys for "audio".
+    errors.record("audio", "seek", 1);
+    // Too many first keys.
+    errors.record("network", "decode", 1);
+    latency.record("audio", "decode", 10);
+    latency.record("audio", "decode", 90);
+
+    assert_eq!(
+        errors.slice_first("audio"),
+        Some(
+            vec![("decode".to_string(), 3), ("open".to_string(), 1)]
+                .into_iter()
+                .collect()
+        )
+    );
+    assert_eq!(
+        errors.slice_second("decode"),
+        Some(
+            vec![("audio".to_string(), 3), ("video".to_string(), 5)]
+                .into_iter()
+                .collect()
+        )
+    );
+    assert_eq!(errors.slice_first("network"), Some(HashMap::new()));
+    assert_eq!(errors.snapshot().unwrap().len(), 2);
+    assert_eq!(
+        latency.slice_second("decode"),
+        Some(
+            vec![("audio".to_string(), vec![1, 1])]
+                .into_iter()
+                .collect()
+        )
+    );
+
+    let (_, keyed) = get_all_serialized(&telemetry);
+    assert_eq!(
+        keyed.find("errors").unwrap().to_string(),
+        r#"{"audio":{"decode":3,"open":1},"video":{"decode":5}}"#
+    );
+    assert_eq!(
+        keyed.find("latency").unwrap().to_string(),
+        r#"{"audio":{"decode":[1,1]}}"#
+    );
+    let rejected = keyed
+        .find_path(&["__telemetry__", "rejected_keys", "errors"])
+        .unwrap();
+    assert_eq!(rejected.as_i64(), Some(2));
+}
+
+#[test]
+fn test_labeled_count() {
+    static OUTCOMES: &[&str] = &["success", "timeout", "refused"];
+    let telemetry = Service::new(true);
+    let connections = plain::LabeledCount::new(&telemetry, "connections", OUTCOMES);
+    let batched = ServiceBuilder::new()
+        .active(true)
+        .batching(1000, Duration::from_secs(60))
+        .build()
+        .unwrap();
+    let batched_connections = plain::LabeledCount::new(&batched, "connections", OUTCOMES);
+
+    for histogram in &[&connections, &batched_connections] {
+        histogram.record(("success", 1));
+        histogram.record(("success", 2));
+        histogram.record(("timeout", 1));
+        histogram.record(("unreachable", 4));
+    }
+    batched.flush();
+
+    let expected: HashMap<&str, u32> = vec![
+        ("success", 3),
+        ("timeout", 1),
+        ("refused", 0),
+        ("__other__", 4),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(connections.snapshot(), Some(expected.clone()));
+    assert_eq!(batched_connections.snapshot(), Some(expected));
+
+    let (plain, _) = get_all_serialized(&telemetry);
+    assert_eq!(
+        plain.find("connections").unwrap().to_string(),
+        r#"{"__other__":4,"refused":0,"success":3,"timeout":1}"#
+    );
+
+    // Counts saturate rather than overflow, with or without batching.
+    for histogram in &[&connections, &batched_connections] {
+        histogram.record(("refused", u32::MAX));
+        histogram.record(("refused", u32::MAX));
+    }
+    batched.flush();
+    assert_eq!(connections.snapshot().unwrap()["refused"], u32::MAX);
+    assert_eq!(batched_connections.snapshot().unwrap()["refused"], u32::MAX);
+}
+
+#[test]
+#[should_panic]
+fn test_labeled_count_reserved_label() {
+    let telemetry = Service::new(true);
+    let _ = plain::LabeledCount::new(&telemetry, "connections", &["success", "__other__"]);
+}
+
+#[test]
+fn test_boolean() {
+    let telemetry = Service::new(true);
+    let check = plain::Boolean::new(&telemetry, "check");
+    let checks = keyed::KeyedBoolean::new(&telemetry, "checks");
+    let declared: keyed::KeyedBoolean<&str> = keyed::KeyedBoolean::new(
+        &telemetry,
+        Definition::new("declared").with_keys(&["a", "b"], UnknownKeys::Reject),
+    );
+
+    check.record(true);
+    check.record(false);
+    check.record(true);
+    checks.record("example.org", true);
+    checks.record("example.org", false);
+    checks.record("example.com", false);
+    declared.record("a", true);
+
+    assert_eq!(check.snapshot(), Some([1, 2]));
+    assert_eq!(checks.snapshot().unwrap()["example.com"], [1, 0]);
+
+    let (plain, keyed) = get_all_serialized(&telemetry);
+    assert_eq!(plain.find("check").unwrap().to_string(), "[1,2]");
+    assert_eq!(
+        keyed.find("checks").unwrap().to_string(),
+        r#"{"example.com":[1,0],"example.org":[1,1]}"#
+    );
+    assert_eq!(
+        keyed.find("declared").unwrap().to_string(),
+        r#"{"a":[0,1],"b":[0,0]}"#
+    );
+}
+
+#[test]
+fn test_stats() {
+    let telemetry = Service::new(true);
+    let sizes: plain::Stats<u32> = plain::Stats::new(&telemetry, "sizes");
+    let empty: plain::Stats<u32> = plain::Stats::new(&telemetry, "empty");
+    let keyed_sizes: keyed::KeyedStats<&str, u32> = keyed::KeyedStats::new(&telemetry, "keyed");
+    let batched = ServiceBuilder::new()
+        .active(true)
+        .batching(1000, Duration::from_secs(60))
+        .build()
+        .unwrap();
+    let batched_sizes: plain::Stats<u32> = plain::Stats::new(&batched, "sizes");
+
+    // Large values with a small variance.
+    for &value in &[1_000_000_004, 1_000_000_007, 1_000_000_013, 1_000_000_016] {
+        sizes.record(value);
+        batched_sizes.record(value);
+        batched_sizes.record(value);
+    }
+    keyed_sizes.record("a", 1);
+    keyed_sizes.record("a", 3);
+    keyed_sizes.record("b", 10);
+    batched.flush();
+
+    let summary = sizes.snapshot().unwrap();
+    assert_eq!(summary.count, 4);
+    assert_eq!(summary.sum, 4_000_000_040);
+    assert_eq!(summary.min, Some(1_000_000_004));
+    assert_eq!(summary.max, Some(1_000_000_016));
+    assert_eq!(summary.mean, 1_000_000_010.);
+    assert!((summary.variance - 22.5).abs() < 1e-6);
+    let batched_summary = batched_sizes.snapshot().unwrap();
+    assert_eq!(batched_summary.count, 8);
+    assert!((batched_summary.variance - 22.5).abs() < 1e-6);
+    let empty_summary = empty.snapshot().unwrap();
+    assert_eq!((empty_summary.count, empty_summary.min), (0, None));
+    assert_eq!(keyed_sizes.snapshot().unwrap()["a"].mean, 2.);
+
+    let (plain, keyed) = get_all_serialized(&telemetry);
+    assert_eq!(
+        plain.find("empty").unwrap().to_string(),
+        r#"{"count":0,"max":null,"mean":0.0,"min":null,"sum":0,"variance":0.0}"#
+    );
+
+    // Merge the payloads of several clients.
+    let merged = aggregate::merge_stats(vec![
+        plain.find("sizes").unwrap(),
+        plain.find("empty").unwrap(),
+        plain.find("sizes").unwrap(),
+    ])
+    .unwrap();
+    assert_eq!(merged.find("count").unwrap().as_i64(), Some(8));
+    assert_eq!(merged.find("min").unwrap().as_i64(), Some(1_000_000_004));
+    let variance = merged.find("variance").unwrap().as_f64().unwrap();
+    assert!((variance - 22.5).abs() < 1e-6);
+    assert_eq!(aggregate::merge_stats(vec![]), None);
+    assert_eq!(aggregate::merge_stats(vec![&Json::Null]), None);
+
+    let other: Json = Json::from_str(
+        r#"{"b": {"count": 1, "sum": 20, "min": 20, "max": 20, "mean": 20.0, "variance": 0.0},
+            "c": {"count": 1, "sum": 5, "min": 5, "max": 5, "mean": 5.0, "variance": 0.0}}"#,
+    )
+    .unwrap();
+    let merged = aggregate::merge_keyed_stats(vec![keyed.find("keyed").unwrap(), &other]).unwrap();
+    assert_eq!(merged.find_path(&["a", "count"]).unwrap().as_i64(), Some(2));
+    assert_eq!(
+        merged.find_path(&["b", "mean"]).unwrap().as_f64(),
+        Some(15.)
+    );
+    assert_eq!(
+        merged.find_path(&["b", "variance"]).unwrap().as_f64(),
+        Some(25.)
+    );
+    assert_eq!(merged.find_path(&["c", "max"]).unwrap().as_i64(), Some(5));
+
+    // Untrusted payloads may not overflow the merged statistics.
+    let large: Json = Json::from_str(&format!(
+        r#"{{"count": 1, "sum": {max}, "min": {max}, "max": {max}, "mean": 1.0, "variance": 0.0}}"#,
+        max = u32::MAX
+    ))
+    .unwrap();
+    let large = aggregate::merge_stats(vec![&large, &large]).unwrap();
+    assert_eq!(
+        large.find("sum").unwrap().as_u64(),
+        Some(2 * u64::from(u32::MAX))
+    );
+    let huge_count: Json = Json::from_str(&format!(
+        r#"{{"count": {}, "sum": 0, "min": 0, "max": 0, "mean": 0.0, "variance": 0.0}}"#,
+        u64::MAX
+    ))
+    .unwrap();
+    assert_eq!(aggregate::merge_stats(vec![&huge_count, &huge_count]), None);
+    let keyed_huge_count = Json::Object(
+        vec![("k".to_string(), huge_count.clone())]
+            .into_iter()
+            .collect(),
+    );
+    assert_eq!(
+        aggregate::merge_keyed_stats(vec![&keyed_huge_count, &keyed_huge_count]),
+        None
+    );
+    let huge_sum: Json = Json::from_str(&format!(
+        r#"{{"count": {}, "sum": {}, "min": {max}, "max": {max}, "mean": 1.0, "variance": 0.0}}"#,
+        u64::MAX / u64::from(u32::MAX),
+        u64::MAX / u64::from(u32::MAX) * u64::from(u32::MAX),
+        max = u32::MAX
+    ))
+    .unwrap();
+    assert!(aggregate::merge_stats(vec![&huge_sum]).is_some());
+    assert_eq!(aggregate::merge_stats(vec![&huge_sum, &huge_sum]), None);
+    // A sum that may not result from `count` values is rejected.
+    let bad_sum: Json = Json::from_str(
+        r#"{"count": 1, "sum": 18446744073709551615, "min": 1, "max": 1, "mean": 1.0, "variance": 0.0}"#,
+    )
+    .unwrap();
+    assert_eq!(aggregate::merge_stats(vec![&bad_sum]), None);
+}
+
+#[test]
+fn test_permanent_randomized_response() {
+    let telemetry = ServiceBuilder::new().active(true).seed(1).build().unwrap();
+    let flag = keyed::KeyedPrivateFlag::new(&telemetry, "flag", 64, 2, 4.);
+    flag.record("a", ());
+    let (_, keyed) = get_all_serialized(&telemetry);
+    let encoded = format!("{}", keyed.find("flag").unwrap());
+
+    // Recording the same key again draws no fresh randomness, so the
+    // true bits may not be recovered by majority vote.
+    for _ in 0..100 {
+        flag.record("a", ());
+    }
+    let (_, keyed) = get_all_serialized(&telemetry);
+    assert_eq!(format!("{}", keyed.find("flag").unwrap()), encoded);
+}
+
+/// The number of times a `Counted` key has been converted to a string.
+static KEY_CONVERSIONS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Counted(&'static str);
+
+impl TelemetryKey for Counted {
+    fn to_key_string(&self) -> String {
+        KEY_CONVERSIONS.fetch_add(1, Ordering::SeqCst);
+        self.0.to_string()
+    }
+}
+
+#[test]
+fn test_interned_keys() {
+    let telemetry = ServiceBuilder::new()
+        .active(true)
+        .max_keys(2)
+        .build()
+        .unwrap();
+
+    // At most `max_keys` keys are interned, other keys are converted
+    // with each record.
+    let count = keyed::KeyedCount::new(&telemetry, "count");
+    for _ in 0..10 {
+        count.record(Counted("a"), 1);
+        count.record(Counted("b"), 1);
+        count.record(Counted("c"), 1);
+    }
+    assert_eq!(KEY_CONVERSIONS.swap(0, Ordering::SeqCst), 12);
+
+    // Keys of private flags are never interned.
+    let flag = keyed::KeyedPrivateFlag::new(&telemetry, "flag", 64, 2, 4.);
+    for _ in 0..10 {
+        flag.record(Counted("a"), ());
+    }
+    assert_eq!(KEY_CONVERSIONS.swap(0, Ordering::SeqCst), 10);
+
+    let (_, keyed) = get_all_serialized(&telemetry);
+    assert_eq!(
+        format!("{}", keyed.find("count").unwrap()),
+        "{\"a\":10,\"b\":10}"
+    );
+}
+
+/// The number of times a `Numbered` key has been converted to a string.
+static NUMBERED_CONVERSIONS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Numbered(u32);
+
+impl TelemetryKey for Numbered {
+    fn to_key_string(&self) -> String {
+        NUMBERED_CONVERSIONS.fetch_add(1, Ordering::SeqCst);
+        self.0.to_string()
+    }
+}
+
+#[test]
+fn test_interned_keys_with_policies() {
+    let telemetry = Service::new(true);
+    let count = keyed::KeyedCount::new(
+        &telemetry,
+        Definition::new("count").key_policy(KeyPolicy::Allow(Vec::new())),
+    );
+
+    // Rejected keys are interned up to a bound, other keys are
+    // converted with each record.
+    for _ in 0..2 {
+        for i in 0..1_000 {
+            count.record(Numbered(i), 1);
+        }
+    }
+    assert_eq!(
+        NUMBERED_CONVERSIONS.load(Ordering::SeqCst),
+        256 + 2 * (1_000 - 256)
+    );
+
+    let (_, keyed) = get_all_serialized(&telemetry);
+    assert_eq!(keyed.find("count").unwrap().to_string(), "{}");
+    assert_eq!(
+        keyed
+            .find_path(&["__telemetry__", "rejected_keys", "count"])
+            .unwrap()
+            .as_i64(),
+        Some(2_000)
+    );
+}
+
+#[test]
+fn create_reserved_name() {
+    let telemetry = Service::new(true);
+    let reserved = plain::Count::new(&telemetry, "__telemetry__".to_string());
+    let count = plain::Count::new(&telemetry, "count".to_string());
+    let duplicate = keyed::KeyedCount::new(&telemetry, "count".to_string());
+    reserved.record(1);
+    count.record(2);
+    duplicate.record("key".to_string(), 3);
+
+    // The service survives, only the faulty histograms are rejected.
+    assert_eq!(count.snapshot(), Some(2));
+    assert_eq!(duplicate.snapshot(), None);
+    assert_eq!(telemetry.list().len(), 1);
+    let (plain, keyed) = get_all_serialized(&telemetry);
+    assert_eq!(plain.find("count").unwrap().as_i64(), Some(2));
+    assert_eq!(keyed, Json::Object(BTreeMap::new()));
+    assert_eq!(
+        plain.find_path(&["__telemetry__", "rejected_names"]),
+        Some(&Json::Array(vec![
+            Json::String("__telemetry__".to_string()),
+            Json::String("count".to_string()),
+        ]))
+    );
+}